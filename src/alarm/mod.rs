@@ -0,0 +1,203 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, Config};
+use crate::nextys::meters::Meters;
+
+/// Alarm states shared with every output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flags {
+    /// Input voltage at or below the `ac_down` set point
+    pub ac_down: bool,
+    /// Battery voltage at or below the `batt_low` set point
+    pub batt_low: bool,
+}
+
+impl Flags {
+    /// Bare comparison against the thresholds with no hysteresis or delay,
+    /// for a single reading that has no history to debounce against
+    pub fn new(config: &Config, input_voltage: f32, batt_voltage: f32) -> Self {
+        Flags {
+            ac_down: input_voltage <= config.ac_down_threshold,
+            batt_low: batt_voltage <= config.low_batt_threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmKind {
+    AcDown,
+    BattLow,
+}
+
+impl AlarmKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlarmKind::AcDown => "ac_down",
+            AlarmKind::BattLow => "batt_low",
+        }
+    }
+
+    /// Look up an alarm by [`AlarmKind::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [AlarmKind::AcDown, AlarmKind::BattLow]
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+/// An alarm being raised or cleared
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlarmEvent {
+    pub alarm: AlarmKind,
+    pub raised: bool,
+    pub time: DateTime<Utc>,
+    /// The reading that changed the alarm, absent when it was acknowledged
+    pub value: Option<f32>,
+}
+
+/// State machine for one alarm.
+///
+/// The condition starts at or below `set` and ends above `clear`, so a value
+/// hovering around one set point does not flap. The alarm follows the
+/// condition once it has held for the raise or clear delay, and a latched
+/// alarm then stays raised until acknowledged.
+#[derive(Debug, Clone)]
+pub struct Alarm {
+    kind: AlarmKind,
+    set: f32,
+    clear: f32,
+    raise_after: TimeDelta,
+    clear_after: TimeDelta,
+    latched: bool,
+    /// Whether the reading is past the set point, with hysteresis
+    condition: bool,
+    /// When `condition` last changed
+    since: Option<DateTime<Utc>>,
+    raised: bool,
+    acknowledged: bool,
+}
+
+impl Alarm {
+    /// An alarm configured by `options`, raised at `threshold` unless they
+    /// give a set point
+    pub fn new(kind: AlarmKind, options: &config::AlarmOptions, threshold: f32) -> Self {
+        let set = options.set.unwrap_or(threshold);
+        Alarm {
+            kind,
+            set,
+            // A clear point below the set point would leave a gap that is
+            // neither, so it can only widen the band
+            clear: options.clear.unwrap_or(set).max(set),
+            raise_after: TimeDelta::seconds(options.raise_after_secs as i64),
+            clear_after: TimeDelta::seconds(options.clear_after_secs as i64),
+            latched: options.latched,
+            condition: false,
+            since: None,
+            raised: false,
+            acknowledged: false,
+        }
+    }
+
+    pub fn kind(&self) -> AlarmKind {
+        self.kind
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.raised
+    }
+
+    /// Feed a reading taken at `time`, returning the change it caused
+    pub fn update(&mut self, value: f32, time: DateTime<Utc>) -> Option<AlarmEvent> {
+        let condition = if self.condition {
+            value <= self.clear
+        } else {
+            value <= self.set
+        };
+        if condition != self.condition || self.since.is_none() {
+            self.condition = condition;
+            self.since = Some(time);
+        }
+        self.step(time, Some(value))
+    }
+
+    /// Let a latched alarm clear, now if its condition has already ended or
+    /// otherwise as soon as it does
+    pub fn acknowledge(&mut self, time: DateTime<Utc>) -> Option<AlarmEvent> {
+        if !self.raised {
+            return None;
+        }
+        self.acknowledged = true;
+        self.step(time, None)
+    }
+
+    fn step(&mut self, time: DateTime<Utc>, value: Option<f32>) -> Option<AlarmEvent> {
+        let held = time - self.since.unwrap_or(time);
+        let raise = !self.raised && self.condition && held >= self.raise_after;
+        let clear = self.raised
+            && !self.condition
+            && held >= self.clear_after
+            && (!self.latched || self.acknowledged);
+        if !raise && !clear {
+            return None;
+        }
+        self.raised = raise;
+        self.acknowledged = false;
+        Some(AlarmEvent {
+            alarm: self.kind,
+            raised: raise,
+            time,
+            value,
+        })
+    }
+}
+
+/// Every alarm of the DCW20, fed from the meters
+#[derive(Debug, Clone)]
+pub struct AlarmEngine {
+    ac_down: Alarm,
+    batt_low: Alarm,
+}
+
+impl AlarmEngine {
+    pub fn new(config: &Config) -> Self {
+        AlarmEngine {
+            ac_down: Alarm::new(
+                AlarmKind::AcDown,
+                &config.alarms.ac_down,
+                config.ac_down_threshold,
+            ),
+            batt_low: Alarm::new(
+                AlarmKind::BattLow,
+                &config.alarms.batt_low,
+                config.low_batt_threshold,
+            ),
+        }
+    }
+
+    /// Feed a sample, returning the alarms it raised or cleared
+    pub fn update(&mut self, meters: &Meters, time: DateTime<Utc>) -> Vec<AlarmEvent> {
+        [
+            self.ac_down.update(meters.input_voltage, time),
+            self.batt_low.update(meters.batt_voltage, time),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    pub fn acknowledge(&mut self, kind: AlarmKind, time: DateTime<Utc>) -> Option<AlarmEvent> {
+        match kind {
+            AlarmKind::AcDown => self.ac_down.acknowledge(time),
+            AlarmKind::BattLow => self.batt_low.acknowledge(time),
+        }
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags {
+            ac_down: self.ac_down.is_raised(),
+            batt_low: self.batt_low.is_raised(),
+        }
+    }
+}
@@ -1,10 +1,22 @@
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
 use clap_num::maybe_hex;
 use env_logger::Env;
-use log::info;
-use rust_nextys_monitoring::{config::Config, database, nextys::Nextys};
+use log::{error, info};
+use rust_nextys_monitoring::config::{Config, Parity, Transport};
+use rust_nextys_monitoring::daemon::{self, Daemon};
+use rust_nextys_monitoring::database::{self, migrations};
+use rust_nextys_monitoring::error::Error;
+use rust_nextys_monitoring::events::{EventKind, Severity};
+use rust_nextys_monitoring::nextys::Nextys;
+use rust_nextys_monitoring::nextys::registers::{Group, RegisterMap, addresses};
+use rust_nextys_monitoring::nextys::settings::BatteryType;
+use rust_nextys_monitoring::spool::{MetricsSpool, SpooledMetrics};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const DEFAULT_EXPORT_BIND: &str = "0.0.0.0:9730";
 
 #[derive(Parser)]
 #[command(name = "Nextys Reader")]
@@ -13,6 +25,116 @@ const DEFAULT_CONFIG_PATH: &str = "config.toml";
 struct Cli {
     #[command(subcommand)]
     action: Action,
+
+    #[command(flatten)]
+    device: DeviceArgs,
+}
+
+/// Overrides for the device connection settings in the config
+#[derive(Args)]
+struct DeviceArgs {
+    /// Transport used to reach the device
+    #[arg(long, global = true)]
+    transport: Option<Transport>,
+
+    /// Serial port path ie /dev/ttyUSB0
+    #[arg(long, global = true)]
+    port: Option<String>,
+
+    /// Baud rate
+    #[arg(long, global = true)]
+    baud_rate: Option<u32>,
+
+    /// Data bits (5-8)
+    #[arg(long, global = true)]
+    data_bits: Option<u8>,
+
+    /// Parity
+    #[arg(long, global = true)]
+    parity: Option<Parity>,
+
+    /// Stop bits (1 or 2)
+    #[arg(long, global = true)]
+    stop_bits: Option<u8>,
+
+    /// Modbus slave id in decimal or hexadecimal
+    #[arg(long, global = true, value_parser=maybe_hex::<u8>)]
+    slave_id: Option<u8>,
+
+    /// Read timeout in milliseconds
+    #[arg(long, global = true)]
+    timeout_ms: Option<u64>,
+
+    /// Modbus gateway host for the tcp transports
+    #[arg(long, global = true)]
+    host: Option<String>,
+
+    /// Modbus gateway port for the tcp transports
+    #[arg(long, global = true)]
+    tcp_port: Option<u16>,
+
+    /// Modbus unit id behind the gateway in decimal or hexadecimal
+    #[arg(long, global = true, value_parser=maybe_hex::<u8>)]
+    unit_id: Option<u8>,
+}
+
+impl DeviceArgs {
+    /// Load the config at `path` with the command line overrides applied
+    fn load_config(&self, path: &str) -> Config {
+        let mut config = Config::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e.report());
+            std::process::exit(1);
+        });
+        if let Some(transport) = self.transport {
+            config.transport = transport;
+        }
+        let serial = &mut config.serial;
+        if let Some(port) = &self.port {
+            serial.port = port.clone();
+        }
+        if let Some(baud_rate) = self.baud_rate {
+            serial.baud_rate = baud_rate;
+        }
+        if let Some(data_bits) = self.data_bits {
+            serial.data_bits = data_bits;
+        }
+        if let Some(parity) = self.parity {
+            serial.parity = parity;
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            serial.stop_bits = stop_bits;
+        }
+        if let Some(slave_id) = self.slave_id {
+            serial.slave_id = slave_id;
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            serial.timeout_ms = timeout_ms;
+            config.tcp.timeout_ms = timeout_ms;
+        }
+        let tcp = &mut config.tcp;
+        if let Some(host) = &self.host {
+            tcp.host = host.clone();
+        }
+        if let Some(port) = self.tcp_port {
+            tcp.port = port;
+        }
+        if let Some(unit_id) = self.unit_id {
+            tcp.unit_id = unit_id;
+        }
+        config
+    }
+}
+
+/// Print the problems found in `config`, exiting when any is an error
+fn check_config(config: &Config) {
+    let report = config.validate();
+    if report.is_empty() {
+        return;
+    }
+    eprintln!("{report}");
+    if report.has_errors() {
+        std::process::exit(1);
+    }
 }
 
 #[derive(Subcommand)]
@@ -30,21 +152,127 @@ enum Action {
         /// How many registers to aggregate together
         #[arg(short, long, default_value = "1")]
         count: u16,
+
+        /// Config path
+        #[arg(long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
     },
     /// Read all meters
     ReadMeters {
         /// Loop reading meters
         #[arg(short, long)]
         to_loop: bool,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// Read registers by name from the register map
+    ReadRegisters {
+        /// Register name, all registers are read if omitted
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Only read registers in this group
+        #[arg(short, long)]
+        group: Option<Group>,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// Print the register map in use as TOML
+    ShowRegisterMap {
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// Compare block reads with one request per register
+    BenchmarkReads {
+        /// How many times to read each block
+        #[arg(short, long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
     },
     /// Read Settings
-    ReadSettings {},
+    ReadSettings {
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// Write settings to the device, only the given fields are changed
+    WriteSettings {
+        /// Battery type: 1 lead, 2 nickel, 3 lithium, 4 supercapacitor
+        #[arg(long)]
+        batt_type: Option<i16>,
+        /// Battery charge voltage in V
+        #[arg(long)]
+        charge_voltage: Option<f32>,
+        /// Battery charge current in A
+        #[arg(long)]
+        charge_current: Option<f32>,
+        /// Battery float voltage in V
+        #[arg(long)]
+        float_voltage: Option<f32>,
+        /// Battery low voltage cutoff in V
+        #[arg(long)]
+        low_voltage: Option<f32>,
+        /// Battery deep discharge voltage in V
+        #[arg(long)]
+        deep_discharge_voltage: Option<f32>,
+        /// Battery maximum discharge current in A
+        #[arg(long)]
+        max_discharge_current: Option<f32>,
+        /// Battery capacity in Ah
+        #[arg(long)]
+        capacity: Option<f32>,
+        /// Nominal output voltage in V
+        #[arg(long)]
+        nominal_output_voltage: Option<f32>,
+        /// Maximum input current in A
+        #[arg(long)]
+        max_input_current: Option<f32>,
+        /// Maximum output current in A
+        #[arg(long)]
+        max_output_current: Option<f32>,
+
+        /// Only print the changes that would be written
+        #[arg(short, long)]
+        dry_run: bool,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
     /// Show Config
     ShowConfig {
         /// Config path
         #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
         config_path: String,
     },
+    /// Check the config for mistakes, including against the device settings
+    CheckConfig {
+        /// Skip the checks that read the device
+        #[arg(long)]
+        offline: bool,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// Bring the database schema up to date
+    Migrate {
+        /// Only list the migrations that would be applied
+        #[arg(short, long)]
+        dry_run: bool,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
     /// Initialize Database and device
     InitializeDevice {
         #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
@@ -62,86 +290,391 @@ enum Action {
         #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
         config_path: String,
     },
+    /// Sample and upload meters until stopped, reconnecting as needed
+    Run {
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// List recorded alarms, communication losses and other events
+    Events {
+        /// Only events of this kind, may be repeated
+        #[arg(short, long)]
+        kind: Vec<EventKind>,
+
+        /// Only events at least this severe
+        #[arg(short, long)]
+        severity: Option<Severity>,
+
+        /// Only events at or after this time, ie 2025-01-01T00:00:00Z
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only events before this time
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+
+        /// Most events to list
+        #[arg(short, long, default_value = "50")]
+        limit: i64,
+
+        /// Include every device rather than the configured one
+        #[arg(short, long)]
+        all_devices: bool,
+
+        /// Only print how many events match
+        #[arg(long)]
+        count: bool,
+
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+    },
+    /// Serve Prometheus metrics without uploading to the database
+    Export {
+        /// Config path
+        #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+        config_path: String,
+
+        /// Address to listen on, defaults to [http] bind in the config
+        #[arg(short, long)]
+        bind: Option<SocketAddr>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("error")).init();
     let cli = Cli::parse();
-    let mut nextys = Nextys::new();
     match cli.action {
         Action::ReadAddress {
             address,
             to_loop,
             count,
+            config_path,
         } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
             if to_loop {
                 loop {
-                    let reading = nextys.get_address(address, count).await;
-                    println!("{:?}", reading);
+                    match nextys.get_address(address, count).await {
+                        Ok(reading) => println!("{:?}", reading),
+                        Err(e) => error!("{e}"),
+                    }
                     std::thread::sleep(std::time::Duration::from_millis(1000));
                 }
             } else {
-                let reading = nextys.get_address(address, count).await;
+                let reading = nextys.get_address(address, count).await?;
                 println!("{:?}", reading);
             }
         }
-        Action::ReadMeters { to_loop } => {
+        Action::ReadMeters {
+            to_loop,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
             if to_loop {
                 loop {
-                    let meters = nextys.get_meters().await;
-                    println!("{:#?}", meters);
+                    match nextys.get_meters().await {
+                        Ok(meters) => println!("{:#?}", meters),
+                        Err(e) => error!("{e}"),
+                    }
                     std::thread::sleep(std::time::Duration::from_millis(1000));
                 }
             } else {
-                let meters = nextys.get_avg_meters().await;
+                let meters = nextys.get_avg_meters().await?;
                 println!("{:#?}", meters);
             }
         }
-        Action::ReadSettings {} => {
-            let settings = nextys.get_settings().await;
+        Action::ReadRegisters {
+            name,
+            group,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
+            let registers: Vec<_> = nextys
+                .register_map()
+                .registers
+                .iter()
+                .filter(|r| name.as_ref().is_none_or(|name| &r.name == name))
+                .filter(|r| group.is_none_or(|group| r.group == group))
+                .cloned()
+                .collect();
+            if registers.is_empty() {
+                return Err("no matching registers in the register map".into());
+            }
+            for register in registers {
+                let value = nextys.read_register(&register.name).await?;
+                println!("{} = {} {}", register.name, value, register.unit);
+            }
+        }
+        Action::ShowRegisterMap { config_path } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let map = match &config.register_map {
+                Some(path) => RegisterMap::load(path)?,
+                None => RegisterMap::dcw20(),
+            };
+            println!("{}", toml::to_string_pretty(&map)?);
+        }
+        Action::BenchmarkReads {
+            iterations,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
+            let map = nextys.register_map();
+            let mut blocks = map.blocks(Group::Meters);
+            blocks.extend(map.blocks(Group::Settings));
+            for (start, count) in blocks {
+                let mut block_time = Duration::ZERO;
+                let mut single_time = Duration::ZERO;
+                for _ in 0..iterations {
+                    let started = Instant::now();
+                    nextys.get_address(start, count).await?;
+                    block_time += started.elapsed();
+
+                    let started = Instant::now();
+                    for address in addresses(start, count) {
+                        nextys.get_address(address, 1).await?;
+                    }
+                    single_time += started.elapsed();
+                }
+                println!(
+                    "{:#06x}-{:#06x}: block {:?}, per register {:?}, saved {:?} per read",
+                    start,
+                    start as u32 + count as u32 - 1,
+                    block_time / iterations,
+                    single_time / iterations,
+                    single_time.saturating_sub(block_time) / iterations
+                );
+            }
+        }
+        Action::ReadSettings { config_path } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
+            let settings = nextys.get_settings().await?;
             println!("{:#?}", settings);
         }
+        Action::WriteSettings {
+            batt_type,
+            charge_voltage,
+            charge_current,
+            float_voltage,
+            low_voltage,
+            deep_discharge_voltage,
+            max_discharge_current,
+            capacity,
+            nominal_output_voltage,
+            max_input_current,
+            max_output_current,
+            dry_run,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
+            let current = nextys.get_settings().await?;
+            let mut desired = current.clone();
+            if let Some(batt_type) = batt_type {
+                desired.batt_type_int = batt_type;
+                desired.batt_type = BatteryType::from(batt_type);
+            }
+            desired.batt_charge_voltage = charge_voltage.unwrap_or(desired.batt_charge_voltage);
+            desired.batt_charge_current = charge_current.unwrap_or(desired.batt_charge_current);
+            desired.batt_float_voltage = float_voltage.unwrap_or(desired.batt_float_voltage);
+            desired.batt_low_voltage = low_voltage.unwrap_or(desired.batt_low_voltage);
+            desired.batt_deep_discharge_voltage =
+                deep_discharge_voltage.unwrap_or(desired.batt_deep_discharge_voltage);
+            desired.batt_max_discharge_current =
+                max_discharge_current.unwrap_or(desired.batt_max_discharge_current);
+            desired.batt_capacity = capacity.unwrap_or(desired.batt_capacity);
+            desired.nominal_output_voltage =
+                nominal_output_voltage.unwrap_or(desired.nominal_output_voltage);
+            desired.max_input_current = max_input_current.unwrap_or(desired.max_input_current);
+            desired.max_output_current = max_output_current.unwrap_or(desired.max_output_current);
+
+            let changes = current.diff(&desired);
+            if changes.is_empty() {
+                println!("Settings already match, nothing to write");
+            } else if dry_run {
+                let map = nextys.register_map();
+                for change in &changes {
+                    let check = map
+                        .get(change.name)
+                        .ok_or(Error::UnknownRegister(change.name.to_string()))
+                        .and_then(|register| register.encode(change.new as f64));
+                    match check {
+                        Ok(_) => println!("{change}"),
+                        Err(e) => println!("{change} (rejected: {e})"),
+                    }
+                }
+            } else {
+                for change in nextys.apply_settings(&desired).await? {
+                    println!("Wrote {change}");
+                }
+            }
+        }
         Action::ShowConfig { config_path } => {
-            let mut config = Config::load(config_path.as_str()).unwrap();
-            println!("{:#?}", config);
-            config.device_id = Some(20);
-            config.save(config_path.as_str()).unwrap();
+            let config = cli.device.load_config(config_path.as_str());
+            println!("{:#?}", config.redacted());
+        }
+        Action::CheckConfig {
+            offline,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let mut report = config.validate();
+            if !offline {
+                let settings = match Nextys::from_config(&config).await {
+                    Ok(mut nextys) => nextys.get_settings().await,
+                    Err(e) => Err(e),
+                };
+                match settings {
+                    Ok(settings) => report.extend(config.validate_settings(&settings)),
+                    Err(e) => {
+                        report
+                            .warning(
+                                "transport",
+                                format!("could not read the device settings: {e}"),
+                            )
+                            .hint("check the [serial] or [tcp] section, or pass --offline");
+                    }
+                }
+            }
+            println!("{report}");
+            if report.has_errors() {
+                std::process::exit(1);
+            }
+        }
+        Action::Migrate {
+            dry_run,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let pool = database::initialize_connection(config.clone()).await?;
+            if dry_run {
+                let pending = migrations::pending(&pool).await?;
+                if pending.is_empty() {
+                    println!("Schema is up to date");
+                }
+                for migration in pending {
+                    println!("Would apply {:04} {}", migration.version, migration.name);
+                }
+            } else {
+                let applied = migrations::migrate(&pool).await?;
+                if applied.is_empty() {
+                    println!("Schema is up to date");
+                }
+                for migration in applied {
+                    println!("Applied {:04} {}", migration.version, migration.name);
+                }
+            }
         }
         Action::InitializeDevice { config_path } => {
-            let mut config = Config::load(config_path.as_str()).unwrap();
+            let mut config = cli.device.load_config(config_path.as_str());
             let pool = database::initialize_connection(config.clone()).await?;
-            let _ = database::get_id(pool, &mut config).await?;
+            migrations::migrate(&pool).await?;
+            let id = database::get_id(pool, &mut config).await?;
+            // Reloaded so the device overrides given on the command line
+            // are not written to the file along with the id
+            let mut config = Config::load(config_path.as_str())?;
+            config.device_id = Some(id);
             match config.save(config_path.as_str()) {
-                Ok(_) => println!("Succesfully wrote {:#?} to {}", config, config_path),
+                Ok(_) => println!(
+                    "Succesfully wrote {:#?} to {}",
+                    config.redacted(),
+                    config_path
+                ),
                 Err(e) => panic!("Error 1: {e}"),
             };
-            println!("{:#?}", config);
         }
         Action::UploadSettings { config_path } => {
-            let config = Config::load(config_path.as_str()).unwrap();
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
             let pool = database::initialize_connection(config.clone()).await?;
-            let settings = nextys.get_settings().await;
+            let settings = nextys.get_settings().await?;
+            match database::record_settings(&pool, &config, Utc::now(), &settings).await? {
+                Some(changes) => {
+                    for change in changes {
+                        println!("{change}");
+                    }
+                }
+                None => println!("Settings unchanged since the last upload"),
+            }
             match database::upload_settings(pool, &config, &settings).await {
                 Ok(_) => info!("Uploaded settings"),
                 Err(e) => panic!("Error 2:{e}"),
             };
         }
         Action::UploadMeters { config_path } => {
-            let config = Config::load(config_path.as_str()).unwrap();
+            let config = cli.device.load_config(config_path.as_str());
+            let mut nextys = Nextys::from_config(&config).await?;
             let pool = database::initialize_connection(config.clone()).await?;
+            let spool = MetricsSpool::new(&config.spool);
             loop {
-                let meters = nextys.get_avg_meters().await;
-                let result = database::upload_metrics(&pool, &config, &meters).await;
-                match result {
-                    Ok(_) => info!("Uploaded Metrics"),
+                // A failed read is skipped rather than uploaded as zeros
+                let meters = match nextys.get_avg_meters().await {
+                    Ok(meters) => meters,
                     Err(e) => {
-                        pool.close().await;
-                        panic!("Error 3:{e}")
+                        error!("Failed to read meters: {e}");
+                        std::thread::sleep(std::time::Duration::from_millis(1000));
+                        continue;
+                    }
+                };
+                let record = SpooledMetrics {
+                    time: Utc::now(),
+                    meters,
+                    alarms: None,
+                };
+                spool.upload(&pool, &config, record).await?;
+            }
+        }
+        Action::Run { config_path } => {
+            let config = cli.device.load_config(config_path.as_str());
+            check_config(&config);
+            Daemon::new(config).run().await?;
+        }
+        Action::Events {
+            kind,
+            severity,
+            since,
+            until,
+            limit,
+            all_devices,
+            count,
+            config_path,
+        } => {
+            let config = cli.device.load_config(config_path.as_str());
+            let pool = database::initialize_connection(config.clone()).await?;
+            let filter = database::EventFilter {
+                sensor_id: if all_devices { None } else { config.device_id },
+                kinds: kind,
+                severity,
+                since,
+                until,
+                limit: Some(limit),
+            };
+            if count {
+                println!("{}", database::count_events(&pool, &filter).await?);
+            } else {
+                let events = database::query_events(&pool, &filter).await?;
+                for (sensor_id, event) in events.iter().rev() {
+                    match (all_devices, sensor_id) {
+                        (true, Some(id)) => println!("[{id}] {event}"),
+                        _ => println!("{event}"),
                     }
                 }
             }
         }
+        Action::Export { config_path, bind } => {
+            let config = cli.device.load_config(config_path.as_str());
+            check_config(&config);
+            let bind = bind
+                .or(config.http.bind)
+                .unwrap_or_else(|| DEFAULT_EXPORT_BIND.parse().unwrap());
+            daemon::export(config, bind).await?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use clap_num::maybe_hex;
+use env_logger::Env;
+use log::{error, info};
+use rust_nextys_monitoring::nextys::registers::RegisterMap;
+use rust_nextys_monitoring::sim::{Scenario, Simulator};
+use tokio::net::TcpListener;
+use tokio_modbus::server::{rtu, rtu_over_tcp, tcp};
+use tokio_serial::{SerialPort, SerialStream};
+
+#[derive(Parser)]
+#[command(name = "Nextys Simulator")]
+#[command(version = "0.1")]
+#[command(about = "Simulate a Nextys DCW20 for testing without hardware")]
+struct Cli {
+    #[command(subcommand)]
+    listen: Listen,
+
+    /// Scenario to play
+    #[arg(short, long, default_value = "normal")]
+    scenario: Scenario,
+
+    /// Simulated seconds per real second
+    #[arg(long, default_value = "1.0")]
+    speed: f64,
+
+    /// Modbus slave id in decimal or hexadecimal
+    #[arg(long, default_value = "1", value_parser=maybe_hex::<u8>)]
+    slave_id: u8,
+
+    /// Register map extending the built in DCW20 map
+    #[arg(long)]
+    register_map: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Listen {
+    /// Serve Modbus RTU on a pseudo terminal
+    Pty {
+        /// Symlink to create pointing at the pseudo terminal
+        #[arg(short, long)]
+        link: Option<String>,
+    },
+    /// Serve Modbus TCP
+    Tcp {
+        #[arg(short, long, default_value = "127.0.0.1:5020")]
+        bind: SocketAddr,
+    },
+    /// Serve raw RTU frames over TCP
+    RtuOverTcp {
+        #[arg(short, long, default_value = "127.0.0.1:5021")]
+        bind: SocketAddr,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+    let map = match &cli.register_map {
+        Some(path) => RegisterMap::load(path)?,
+        None => RegisterMap::dcw20(),
+    };
+    let simulator = Simulator::new(map, cli.scenario, cli.slave_id);
+    let ticker = simulator.clone();
+    tokio::spawn(async move { ticker.run(cli.speed).await });
+    let on_process_error = |e| error!("{e}");
+
+    match cli.listen {
+        Listen::Pty { link } => {
+            let (master, slave) = SerialStream::pair()?;
+            let path = slave.name().ok_or("pseudo terminal has no name")?;
+            if let Some(link) = &link {
+                let _ = std::fs::remove_file(link);
+                std::os::unix::fs::symlink(&path, link)?;
+                info!("Serving {:?} on {} ({})", cli.scenario, link, path);
+            } else {
+                info!("Serving {:?} on {}", cli.scenario, path);
+            }
+            // The slave end has to stay open for the master to keep working
+            let _slave = slave;
+            rtu::Server::new(master).serve_forever(simulator).await?;
+        }
+        Listen::Tcp { bind } => {
+            let server = tcp::Server::new(TcpListener::bind(bind).await?);
+            info!("Serving {:?} on tcp://{}", cli.scenario, bind);
+            let simulator = Arc::new(simulator);
+            let on_connected = |stream, _| {
+                let simulator = simulator.clone();
+                async move { Ok(Some((simulator, stream))) }
+            };
+            server.serve(&on_connected, on_process_error).await?;
+        }
+        Listen::RtuOverTcp { bind } => {
+            let server = rtu_over_tcp::Server::new(TcpListener::bind(bind).await?);
+            info!("Serving {:?} on rtu-over-tcp://{}", cli.scenario, bind);
+            let simulator = Arc::new(simulator);
+            let on_connected = |stream, _| {
+                let simulator = simulator.clone();
+                async move { Ok(Some((simulator, stream))) }
+            };
+            server.serve(&on_connected, on_process_error).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,426 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::alarm::{AlarmEngine, AlarmEvent, AlarmKind, Flags};
+use crate::config::{self, Config};
+use crate::daemon::Backoff;
+use crate::error::{Error, Result};
+use crate::nextys::Nextys;
+use crate::nextys::meters::{Meters, MetersAggregate};
+use crate::nextys::settings::{SettingChange, Settings};
+use crate::nextys::transport::{ModbusTransport, RegisterTransport};
+use crate::spool::SpooledMetrics;
+
+/// Queued commands before senders wait
+const COMMAND_CAPACITY: usize = 32;
+/// Events a slow subscriber may fall behind by before it misses some
+const EVENT_CAPACITY: usize = 256;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Connect<T> = Box<dyn FnMut() -> BoxFuture<Result<Nextys<T>>> + Send>;
+
+/// A single meter reading and when it was taken
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub time: DateTime<Utc>,
+    pub meters: Meters,
+}
+
+/// Latest state seen on the bus, readable without queueing a command
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Snapshot {
+    pub sample: Option<Sample>,
+    /// The most recently completed window
+    pub window: Option<SpooledMetrics>,
+    pub settings: Option<Settings>,
+    /// Alarms raised by the alarm engine
+    pub alarms: Flags,
+    pub device_connected: bool,
+    pub database_connected: bool,
+}
+
+/// Broadcast to every subscriber of the bus
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The meters were read
+    Sample(Sample),
+    /// A window of samples was aggregated
+    Window(SpooledMetrics),
+    /// Settings were written through the bus
+    SettingsChanged(Vec<SettingChange>),
+    /// An alarm was raised or cleared
+    Alarm(AlarmEvent),
+    /// A request to the device finished, with the kind of error if it failed
+    Request {
+        duration: Duration,
+        error: Option<&'static str>,
+    },
+    Connected,
+    /// The link to the device was lost, with the error that showed it
+    Disconnected(String),
+}
+
+/// Requests handled by the task owning the device
+#[derive(Debug)]
+pub enum Command {
+    ReadMeters(oneshot::Sender<Result<Meters>>),
+    ReadSettings(oneshot::Sender<Result<Settings>>),
+    ReadRegister {
+        address: u16,
+        count: u16,
+        reply: oneshot::Sender<Result<Vec<u16>>>,
+    },
+    WriteSettings {
+        settings: Box<Settings>,
+        reply: oneshot::Sender<Result<Vec<SettingChange>>>,
+    },
+    /// Acknowledge a latched alarm, replying with the clear it caused
+    AcknowledgeAlarm {
+        alarm: AlarmKind,
+        reply: oneshot::Sender<Result<Option<AlarmEvent>>>,
+    },
+    /// Stop the bus, replying with the window in progress
+    Shutdown(oneshot::Sender<Option<SpooledMetrics>>),
+}
+
+/// Cloneable client side of the bus
+#[derive(Clone)]
+pub struct BusHandle {
+    commands: mpsc::Sender<Command>,
+    /// Weak so subscribers see the channel close once the bus stops
+    events: broadcast::WeakSender<Event>,
+    snapshot: Arc<RwLock<Snapshot>>,
+}
+
+impl BusHandle {
+    pub async fn read_meters(&self) -> Result<Meters> {
+        self.request(Command::ReadMeters).await
+    }
+
+    pub async fn read_settings(&self) -> Result<Settings> {
+        self.request(Command::ReadSettings).await
+    }
+
+    pub async fn read_register(&self, address: u16, count: u16) -> Result<Vec<u16>> {
+        self.request(|reply| Command::ReadRegister {
+            address,
+            count,
+            reply,
+        })
+        .await
+    }
+
+    /// Apply settings to the device, see [`Nextys::apply_settings`]
+    pub async fn write_settings(&self, settings: Settings) -> Result<Vec<SettingChange>> {
+        self.request(|reply| Command::WriteSettings {
+            settings: Box::new(settings),
+            reply,
+        })
+        .await
+    }
+
+    /// Acknowledge `alarm`, see [`crate::alarm::Alarm::acknowledge`]
+    pub async fn acknowledge_alarm(&self, alarm: AlarmKind) -> Result<Option<AlarmEvent>> {
+        self.request(|reply| Command::AcknowledgeAlarm { alarm, reply })
+            .await
+    }
+
+    /// Receive events from now on, the receiver closes when the bus stops
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        match self.events.upgrade() {
+            Some(events) => events.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<RwLock<Snapshot>> {
+        self.snapshot.clone()
+    }
+
+    /// Stop the bus and return the samples of the unfinished window
+    pub async fn shutdown(&self) -> Option<SpooledMetrics> {
+        let (reply, response) = oneshot::channel();
+        self.commands.send(Command::Shutdown(reply)).await.ok()?;
+        response.await.ok().flatten()
+    }
+
+    async fn request<R>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<R>>) -> Command,
+    ) -> Result<R> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| Error::BusClosed)?;
+        response.await.map_err(|_| Error::BusClosed)?
+    }
+}
+
+/// Task that owns the device, samples it and serves commands one at a time
+pub struct Bus<T = ModbusTransport> {
+    connect: Connect<T>,
+    nextys: Option<Nextys<T>>,
+    backoff: Backoff,
+    sample_interval: Duration,
+    window: Duration,
+    window_start: Instant,
+    samples: Vec<Meters>,
+    alarms: AlarmEngine,
+    events: broadcast::Sender<Event>,
+    snapshot: Arc<RwLock<Snapshot>>,
+}
+
+impl Bus {
+    /// Start a bus connecting to the device described by `config`
+    pub fn spawn(config: &Config) -> BusHandle {
+        let shared = Arc::new(config.clone());
+        Bus::spawn_with(&config.daemon, AlarmEngine::new(config), move || {
+            let config = shared.clone();
+            async move { Nextys::from_config(&config).await }
+        })
+    }
+}
+
+impl<T: RegisterTransport + Send + 'static> Bus<T> {
+    /// Start a bus that opens the device with `connect`, again after every
+    /// lost connection
+    pub fn spawn_with<F, Fut>(
+        options: &config::Daemon,
+        alarms: AlarmEngine,
+        mut connect: F,
+    ) -> BusHandle
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Nextys<T>>> + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::channel(COMMAND_CAPACITY);
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let snapshot = Arc::new(RwLock::new(Snapshot::default()));
+        let bus = Bus {
+            connect: Box::new(move || Box::pin(connect())),
+            nextys: None,
+            backoff: Backoff::new(
+                Duration::from_millis(options.backoff_initial_ms),
+                Duration::from_secs(options.backoff_max_secs),
+            ),
+            sample_interval: Duration::from_millis(options.sample_interval_ms.max(1)),
+            window: Duration::from_secs(options.window_secs.max(1)),
+            window_start: Instant::now(),
+            samples: Vec::new(),
+            alarms,
+            events,
+            snapshot: snapshot.clone(),
+        };
+        let events = bus.events.downgrade();
+        tokio::spawn(bus.run(receiver));
+        BusHandle {
+            commands,
+            events,
+            snapshot,
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        let mut sample = time::interval(self.sample_interval);
+        sample.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.window_start = Instant::now();
+        loop {
+            tokio::select! {
+                _ = sample.tick() => {
+                    self.sample().await;
+                    if self.window_start.elapsed() >= self.window {
+                        if let Some(record) = self.close_window() {
+                            self.send(Event::Window(record));
+                        }
+                        if let Ok(settings) = self.read_settings().await {
+                            self.snapshot.write().unwrap().settings = Some(settings);
+                        }
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Shutdown(reply)) => {
+                        let _ = reply.send(self.close_window());
+                        break;
+                    }
+                    Some(command) => self.handle(command).await,
+                    // Every handle was dropped
+                    None => break,
+                },
+            }
+        }
+    }
+
+    async fn handle(&mut self, command: Command) {
+        // A dropped reply only means the caller stopped waiting
+        match command {
+            Command::ReadMeters(reply) => {
+                let _ = reply.send(self.read_meters().await);
+            }
+            Command::ReadSettings(reply) => {
+                let settings = self.read_settings().await;
+                if let Ok(settings) = &settings {
+                    self.snapshot.write().unwrap().settings = Some(settings.clone());
+                }
+                let _ = reply.send(settings);
+            }
+            Command::ReadRegister {
+                address,
+                count,
+                reply,
+            } => {
+                let started = Instant::now();
+                let result = match self.device().await {
+                    Ok(nextys) => nextys.get_address(address, count).await,
+                    Err(e) => Err(e),
+                };
+                let _ = reply.send(self.checked(started, result));
+            }
+            Command::WriteSettings { settings, reply } => {
+                let started = Instant::now();
+                let result = match self.device().await {
+                    Ok(nextys) => nextys.apply_settings(&settings).await,
+                    Err(e) => Err(e),
+                };
+                let result = self.checked(started, result);
+                if let Ok(changes) = &result
+                    && !changes.is_empty()
+                {
+                    self.snapshot.write().unwrap().settings = Some(*settings);
+                    self.send(Event::SettingsChanged(changes.clone()));
+                }
+                let _ = reply.send(result);
+            }
+            Command::AcknowledgeAlarm { alarm, reply } => {
+                let event = self.alarms.acknowledge(alarm, Utc::now());
+                if let Some(event) = &event {
+                    self.snapshot.write().unwrap().alarms = self.alarms.flags();
+                    self.send(Event::Alarm(event.clone()));
+                }
+                let _ = reply.send(Ok(event));
+            }
+            Command::Shutdown(_) => unreachable!("shutdown is handled by the run loop"),
+        }
+    }
+
+    /// Take one sample for the current window
+    async fn sample(&mut self) {
+        let Ok(meters) = self.read_meters().await else {
+            return;
+        };
+        let sample = Sample {
+            time: Utc::now(),
+            meters: meters.clone(),
+        };
+        let alarms = self.alarms.update(&meters, sample.time);
+        {
+            let mut snapshot = self.snapshot.write().unwrap();
+            snapshot.sample = Some(sample.clone());
+            snapshot.alarms = self.alarms.flags();
+        }
+        for event in alarms {
+            info!(
+                "Alarm {} {}",
+                event.alarm.name(),
+                if event.raised { "raised" } else { "cleared" }
+            );
+            self.send(Event::Alarm(event));
+        }
+        self.send(Event::Sample(sample));
+        self.samples.push(meters);
+    }
+
+    /// Aggregate the samples taken since the last window and start a new one
+    fn close_window(&mut self) -> Option<SpooledMetrics> {
+        self.window_start = Instant::now();
+        let meters = MetersAggregate::from_samples(&self.samples)?;
+        self.samples.clear();
+        let record = SpooledMetrics {
+            time: Utc::now(),
+            meters,
+            alarms: Some(self.alarms.flags()),
+        };
+        self.snapshot.write().unwrap().window = Some(record.clone());
+        Some(record)
+    }
+
+    async fn read_meters(&mut self) -> Result<Meters> {
+        let started = Instant::now();
+        let result = match self.device().await {
+            Ok(nextys) => nextys.get_meters().await,
+            Err(e) => Err(e),
+        };
+        self.checked(started, result)
+    }
+
+    async fn read_settings(&mut self) -> Result<Settings> {
+        let started = Instant::now();
+        let result = match self.device().await {
+            Ok(nextys) => nextys.get_settings().await,
+            Err(e) => Err(e),
+        };
+        self.checked(started, result)
+    }
+
+    /// The open device, reconnecting if the backoff allows it
+    async fn device(&mut self) -> Result<&mut Nextys<T>> {
+        if self.nextys.is_none() {
+            if !self.backoff.ready() {
+                return Err(Error::NotConnected);
+            }
+            match (self.connect)().await {
+                Ok(nextys) => {
+                    info!("Connected to device");
+                    self.backoff.reset();
+                    self.nextys = Some(nextys);
+                    self.snapshot.write().unwrap().device_connected = true;
+                    self.send(Event::Connected);
+                }
+                Err(e) => {
+                    let delay = self.backoff.failed();
+                    error!("Failed to connect to device, retrying in {delay:?}: {e}");
+                    return Err(e);
+                }
+            }
+        }
+        Ok(self.nextys.as_mut().expect("device was connected above"))
+    }
+
+    /// Report a request started at `started` and drop the device when
+    /// `result` shows the link is gone
+    fn checked<R>(&mut self, started: Instant, result: Result<R>) -> Result<R> {
+        // Failures to connect were already logged when reconnecting
+        if self.nextys.is_none() {
+            return result;
+        }
+        self.send(Event::Request {
+            duration: started.elapsed(),
+            error: result.as_ref().err().map(Error::kind),
+        });
+        if let Err(e) = &result {
+            if e.is_connection_lost() {
+                error!("Lost connection to device: {e}");
+                self.nextys = None;
+                self.snapshot.write().unwrap().device_connected = false;
+                self.send(Event::Disconnected(e.to_string()));
+            } else {
+                // The device answered so the link is fine
+                warn!("Failed to read device: {e}");
+            }
+        }
+        result
+    }
+
+    fn send(&self, event: Event) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
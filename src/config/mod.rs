@@ -1,9 +1,16 @@
 use anyhow::{Context, Result};
 use serde::Serialize;
 use serde_derive::Deserialize;
-use std::{fs, net::IpAddr};
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+};
 use toml;
 
+mod validate;
+
+pub use validate::{Level, Problem, Report};
+
 #[derive(Deserialize, Clone, Debug, Serialize)]
 pub struct Config {
     pub timescaledb: TimescaleDB,
@@ -13,38 +20,655 @@ pub struct Config {
     pub location: String,
     pub low_batt_threshold: f32,
     pub ac_down_threshold: f32,
+    /// Register map overriding or extending the built in DCW20 map
+    pub register_map: Option<String>,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub serial: Serial,
+    #[serde(default)]
+    pub tcp: Tcp,
+    #[serde(default)]
+    pub spool: Spool,
+    #[serde(default)]
+    pub daemon: Daemon,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub mqtt: Mqtt,
+    #[serde(default)]
+    pub nut: Nut,
+    #[serde(default)]
+    pub snmp: Snmp,
+    #[serde(default)]
+    pub alarms: Alarms,
+    /// Fields set from the environment or a file, left out of [`Config::save`]
+    #[serde(skip)]
+    overridden: Vec<Override>,
+    /// `NEXTYS_` variables that match no field and were ignored, reported by
+    /// [`Config::validate`]
+    #[serde(skip)]
+    unknown_overrides: Vec<String>,
+}
+
+/// A field replaced after reading config.toml, with the value it had there
+#[derive(Clone, Debug)]
+struct Override {
+    path: Vec<String>,
+    previous: Option<toml::Value>,
+}
+
+/// Why a config could not be loaded
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// The file is missing or unreadable
+    #[error("failed to read config {path}: {source}")]
+    Read { path: String, source: io::Error },
+    /// The file is not valid TOML
+    #[error("invalid config at line {line}, column {column}: {}", source.message())]
+    Syntax {
+        line: usize,
+        column: usize,
+        source: toml::de::Error,
+    },
+    /// Fields are missing or of the wrong type
+    #[error("invalid config\n{0}")]
+    Invalid(Report),
+    /// A `NEXTYS_` variable names a key inside a field that is not a table
+    #[error("{0} is not a config field, use __ between a section and its key")]
+    Override(String),
+    #[error("failed to read timescaledb.timescaledb_pass_file {path}: {source}")]
+    PasswordFile { path: String, source: io::Error },
+}
+
+impl ConfigError {
+    /// The error in the format of [`Config::validate`]
+    pub fn report(&self) -> Report {
+        let mut report = Report::default();
+        match self {
+            ConfigError::Read { path, source } => {
+                report
+                    .error(path, source.to_string())
+                    .hint("pass the path of config.toml with --config-path");
+            }
+            ConfigError::Syntax {
+                line,
+                column,
+                source,
+            } => {
+                report.error(&format!("line {line}, column {column}"), source.message());
+            }
+            ConfigError::Invalid(problems) => report.extend(problems.clone()),
+            ConfigError::Override(name) => {
+                report
+                    .error(name, "names a key inside a field that is not a section")
+                    .hint("use __ between a section and its key, ie NEXTYS_MQTT__HOST");
+            }
+            ConfigError::PasswordFile { path, source } => {
+                report.error(
+                    "timescaledb.timescaledb_pass_file",
+                    format!("{path}: {source}"),
+                );
+            }
+        }
+        report
+    }
+}
+
+/// Environment variables starting with this set config fields, with `__`
+/// between a section and its key, ie `NEXTYS_MQTT__PASSWORD`
+pub const ENV_PREFIX: &str = "NEXTYS_";
+
+/// How the DCW20 is reached
+#[derive(Deserialize, Clone, Copy, Debug, Default, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    /// Modbus RTU on the `[serial]` port
+    #[default]
+    Rtu,
+    /// Modbus TCP to the `[tcp]` gateway
+    Tcp,
+    /// Raw RTU frames tunnelled through the `[tcp]` gateway
+    RtuOverTcp,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize)]
 pub struct TimescaleDB {
-    pub timescaledb_host: IpAddr,
-    pub timescaledb_port: i64,
+    /// Host name or IP address
+    pub timescaledb_host: String,
+    #[serde(default = "default_timescaledb_port")]
+    pub timescaledb_port: u16,
     pub timescaledb_user: String,
+    /// Not needed with `timescaledb_pass_file`
+    #[serde(default)]
     pub timescaledb_pass: String,
+    /// File holding the password, ie a systemd credential, used instead of
+    /// `timescaledb_pass`
+    pub timescaledb_pass_file: Option<String>,
     pub timescaledb_db: String,
+    #[serde(default)]
+    pub timescaledb_sslmode: SslMode,
+    /// CA certificate the server is verified against, PEM
+    pub timescaledb_ssl_root_cert: Option<String>,
+    /// Client certificate and key for certificate authentication, PEM
+    pub timescaledb_ssl_client_cert: Option<String>,
+    pub timescaledb_ssl_client_key: Option<String>,
+    /// Shown in `pg_stat_activity`
+    #[serde(default = "default_application_name")]
+    pub timescaledb_application_name: String,
+    /// How long to wait for a connection
+    #[serde(default = "default_connect_timeout_secs")]
+    pub timescaledb_connect_timeout_secs: u64,
+    /// Statements running longer are cancelled by the server, no limit when
+    /// unset
+    pub timescaledb_statement_timeout_ms: Option<u64>,
+    #[serde(default = "default_max_connections")]
+    pub timescaledb_max_connections: u32,
+}
+
+fn default_timescaledb_port() -> u16 {
+    5432
+}
+
+fn default_application_name() -> String {
+    "nextys_reader".to_string()
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_max_connections() -> u32 {
+    5
+}
+
+/// Whether the database connection is encrypted and how the server is
+/// checked, as `sslmode` in libpq
+#[derive(Deserialize, Clone, Copy, Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Allow,
+    /// Encrypt when the server supports it
+    #[default]
+    Prefer,
+    Require,
+    /// Require a server certificate signed by the root certificate
+    VerifyCa,
+    /// As `verify-ca` and the certificate must name the host
+    VerifyFull,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+#[serde(default)]
+pub struct Serial {
+    pub port: String,
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub slave_id: u8,
+    pub timeout_ms: u64,
+}
+
+impl Default for Serial {
+    /// Factory settings of the DCW20 on its USB port
+    fn default() -> Self {
+        Serial {
+            port: "/dev/ttyACM0".to_string(),
+            baud_rate: 19_200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            slave_id: 0x01,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+#[serde(default)]
+pub struct Tcp {
+    pub host: String,
+    pub port: u16,
+    pub unit_id: u8,
+    pub timeout_ms: u64,
+}
+
+impl Default for Tcp {
+    fn default() -> Self {
+        Tcp {
+            host: "127.0.0.1".to_string(),
+            port: 502,
+            unit_id: 0x01,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// Where metrics are kept while TimescaleDB is unreachable
+#[derive(Deserialize, Clone, Debug, Serialize)]
+#[serde(default)]
+pub struct Spool {
+    pub path: String,
+    /// Oldest samples are dropped once the spool grows past this size
+    pub max_bytes: u64,
+    /// Samples older than this are dropped instead of uploaded
+    pub max_age_hours: u64,
+}
+
+impl Default for Spool {
+    fn default() -> Self {
+        Spool {
+            path: "metrics_spool.jsonl".to_string(),
+            max_bytes: 50 * 1024 * 1024,
+            max_age_hours: 7 * 24,
+        }
+    }
+}
+
+/// Sampling and recovery behaviour of `nextys_reader run`
+#[derive(Deserialize, Clone, Debug, Serialize)]
+#[serde(default)]
+pub struct Daemon {
+    /// Length of each aggregated window uploaded to the database
+    pub window_secs: u64,
+    /// Time between meter reads inside a window
+    pub sample_interval_ms: u64,
+    /// First reconnect delay, doubled after every failed attempt
+    pub backoff_initial_ms: u64,
+    /// Upper bound on the reconnect delay
+    pub backoff_max_secs: u64,
+    /// Send READY and WATCHDOG notifications to systemd
+    pub systemd_notify: bool,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Daemon {
+            window_secs: 10,
+            sample_interval_ms: 1000,
+            backoff_initial_ms: 1000,
+            backoff_max_secs: 60,
+            systemd_notify: false,
+        }
+    }
+}
+
+/// JSON API served by the daemon
+#[derive(Deserialize, Clone, Debug, Default, Serialize)]
+#[serde(default)]
+pub struct Http {
+    /// Address to listen on, the API is off when unset
+    pub bind: Option<SocketAddr>,
+    /// Bearer token required by the POST endpoints, which are off when unset
+    pub control_token: Option<String>,
+}
+
+/// MQTT broker the daemon publishes readings to
+#[derive(Deserialize, Clone, Debug, Serialize)]
+#[serde(default)]
+pub struct Mqtt {
+    /// Broker host, publishing is off when unset
+    pub host: Option<String>,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topics are published under `<topic_prefix>/<sys_name>/`
+    pub topic_prefix: String,
+    /// Publish Home Assistant discovery payloads
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Mqtt {
+            host: None,
+            port: 1883,
+            username: None,
+            password: None,
+            topic_prefix: "nextys".to_string(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+/// Network UPS Tools server for `upsmon` and `upsc` clients
+#[derive(Deserialize, Clone, Debug, Serialize)]
+#[serde(default)]
+pub struct Nut {
+    /// Address to listen on, usually port 3493, the server is off when unset
+    pub bind: Option<SocketAddr>,
+    /// Name clients use for the unit, as in `nextys@host`
+    pub ups_name: String,
+    /// Credentials required to log in and claim primary, anyone may when unset
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for Nut {
+    fn default() -> Self {
+        Nut {
+            bind: None,
+            ups_name: "nextys".to_string(),
+            username: None,
+            password: None,
+        }
+    }
+}
+
+/// SNMP agent serving the system group and the readings
+#[derive(Deserialize, Clone, Debug, Default, Serialize)]
+#[serde(default)]
+pub struct Snmp {
+    /// Address to listen on, usually port 161, the agent is off when unset
+    pub bind: Option<SocketAddr>,
+    /// Read only SNMPv2c community, v2c is refused when unset
+    pub community: Option<String>,
+    /// sysContact
+    pub contact: String,
+    /// Engine id as hex, derived from `ip_address` when unset
+    pub engine_id: Option<String>,
+    /// SNMPv3 users, authenticated with SHA and encrypted with AES-128
+    pub users: Vec<SnmpUser>,
+    /// Managers sent a notification when `ac_down` or `batt_low` change
+    pub traps: Vec<TrapTarget>,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+pub struct SnmpUser {
+    pub name: String,
+    pub auth_password: String,
+    /// Requests must also be encrypted when set
+    pub priv_password: Option<String>,
+}
+
+/// Where to send notifications, as SNMPv3 when `user` names one of the
+/// users and as SNMPv2c with `community` otherwise
+#[derive(Deserialize, Clone, Debug, Serialize)]
+pub struct TrapTarget {
+    pub target: SocketAddr,
+    pub community: Option<String>,
+    pub user: Option<String>,
+}
+
+/// Tuning of the alarms raised from the meters
+#[derive(Deserialize, Clone, Debug, Default, Serialize)]
+#[serde(default)]
+pub struct Alarms {
+    pub ac_down: AlarmOptions,
+    pub batt_low: AlarmOptions,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, Serialize)]
+#[serde(default)]
+pub struct AlarmOptions {
+    /// Reading at or below which the alarm starts, `ac_down_threshold` or
+    /// `low_batt_threshold` when unset
+    pub set: Option<f32>,
+    /// Reading above which the alarm ends, the set point when unset
+    pub clear: Option<f32>,
+    /// How long the reading must stay past the set point before raising
+    pub raise_after_secs: u64,
+    /// How long the reading must stay past the clear point before clearing
+    pub clear_after_secs: u64,
+    /// Keep the alarm raised until it is acknowledged
+    pub latched: bool,
 }
 
 impl Config {
-    pub fn load(path: &str) -> Result<Self, toml::de::Error> {
-        let content = fs::read_to_string(path).expect(
-            format!(
-                "should have been able to find file, make sure the provided path: '{}'  exists",
-                path
-            )
-            .as_str(),
-        );
-        let config_res: Result<Config, toml::de::Error> = toml::from_str(&content);
-        let config = match config_res {
-            Ok(config) => Ok(config),
-            Err(error) => Err(error),
-        };
-        config
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+        Config::parse(&content, std::env::vars())
+    }
+
+    /// Parse config.toml `content`, then apply the `NEXTYS_` variables in
+    /// `env` and read `timescaledb_pass_file`.
+    ///
+    /// Variables hold TOML values. They replace strings as they are, so only
+    /// a value for a field missing from the file needs quoting when it could
+    /// be read as a number or boolean.
+    pub fn parse(
+        content: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut table: toml::Table = toml::from_str(content).map_err(|source| {
+            let start = source
+                .span()
+                .map_or(0, |span| span.start)
+                .min(content.len());
+            let before = &content[..start];
+            ConfigError::Syntax {
+                line: before.matches('\n').count() + 1,
+                column: before.chars().rev().take_while(|&c| c != '\n').count() + 1,
+                source,
+            }
+        })?;
+        let mut overridden = Vec::new();
+        let mut from_env = Vec::new();
+        let mut as_strings = Vec::new();
+        for (name, raw) in env {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let path: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+            let parent =
+                section(&mut table, &path).ok_or_else(|| ConfigError::Override(name.clone()))?;
+            let key = path.last().expect("split returns at least one part");
+            let value = match parent.get(key) {
+                Some(toml::Value::String(_)) => toml::Value::String(raw),
+                _ => match toml::from_str::<toml::Table>(&format!("value = {raw}"))
+                    .ok()
+                    .and_then(|mut value| value.remove("value"))
+                {
+                    Some(value) => {
+                        as_strings.push((path.join("."), toml::Value::String(raw)));
+                        value
+                    }
+                    None => toml::Value::String(raw),
+                },
+            };
+            let previous = parent.insert(key.clone(), value);
+            from_env.push((name, path.clone()));
+            overridden.push(Override { path, previous });
+        }
+
+        let path = ["timescaledb", "timescaledb_pass"]
+            .map(String::from)
+            .to_vec();
+        let set_by_env = overridden.iter().any(|o| o.path == path);
+        if let Some(database) = table.get_mut("timescaledb").and_then(|v| v.as_table_mut())
+            && let Some(file) = database
+                .get("timescaledb_pass_file")
+                .and_then(|v| v.as_str())
+            && !set_by_env
+        {
+            let password =
+                fs::read_to_string(file).map_err(|source| ConfigError::PasswordFile {
+                    path: file.to_string(),
+                    source,
+                })?;
+            // Files written by editors or echo end with a newline
+            let password = password.trim_end_matches(['\r', '\n']).to_string();
+            let previous = database.insert(path[1].clone(), toml::Value::String(password));
+            overridden.push(Override { path, previous });
+        }
+
+        let mut config = deserialize(toml::Value::Table(table), as_strings)?;
+        config.overridden = overridden;
+        // Unknown keys are dropped when deserializing, so a variable naming
+        // one is missing from the config written back out
+        let known = toml::Table::try_from(&config).expect("config should serialize to TOML");
+        config.unknown_overrides = from_env
+            .into_iter()
+            .filter(|(_, path)| lookup(&known, path).is_none())
+            .map(|(name, _)| name)
+            .collect();
+        Ok(config)
     }
 
+    /// Write the config to `path`. Fields set from the environment or
+    /// `timescaledb_pass_file` keep the value they had in the file, if any,
+    /// so secrets never end up in config.toml.
     pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut table =
+            toml::Table::try_from(self).context("Failed to serialize config to TOML")?;
+        for field in &self.overridden {
+            let Some(parent) = section(&mut table, &field.path) else {
+                continue;
+            };
+            let key = field.path.last().expect("paths are never empty");
+            match &field.previous {
+                Some(value) => parent.insert(key.clone(), value.clone()),
+                None => parent.remove(key),
+            };
+        }
         let toml_str =
-            toml::to_string_pretty(self).context("Failed to serialize config to TOML")?;
+            toml::to_string_pretty(&table).context("Failed to serialize config to TOML")?;
         fs::write(path, toml_str).context("Failed to write to save file")?;
         Ok(())
     }
+
+    /// A copy safe to show to clients, with passwords masked
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.overridden.clear();
+        config.timescaledb.timescaledb_pass = "********".to_string();
+        if config.http.control_token.is_some() {
+            config.http.control_token = Some("********".to_string());
+        }
+        if config.mqtt.password.is_some() {
+            config.mqtt.password = Some("********".to_string());
+        }
+        if config.nut.password.is_some() {
+            config.nut.password = Some("********".to_string());
+        }
+        if config.snmp.community.is_some() {
+            config.snmp.community = Some("********".to_string());
+        }
+        for user in &mut config.snmp.users {
+            user.auth_password = "********".to_string();
+            if user.priv_password.is_some() {
+                user.priv_password = Some("********".to_string());
+            }
+        }
+        for trap in &mut config.snmp.traps {
+            if trap.community.is_some() {
+                trap.community = Some("********".to_string());
+            }
+        }
+        config
+    }
+}
+
+/// The config held in `value`, or every field of the wrong type plus the
+/// first missing one. A field of the wrong type is dropped and the rest tried
+/// again, so one mistake does not hide the next.
+///
+/// `as_strings` holds the environment values read as TOML with the raw text
+/// they came from. One whose field wants a string is retried as that text,
+/// so a password such as `123456` or `true` still loads.
+fn deserialize(
+    mut value: toml::Value,
+    mut as_strings: Vec<(String, toml::Value)>,
+) -> Result<Config, ConfigError> {
+    let mut problems = Report::default();
+    loop {
+        let error = match serde_path_to_error::deserialize::<_, Config>(value.clone()) {
+            Ok(config) if problems.is_empty() => return Ok(config),
+            Ok(_) => return Err(ConfigError::Invalid(problems)),
+            Err(error) => error,
+        };
+        let path = error.path();
+        let segments: Vec<_> = path.iter().collect();
+        if let Some(index) = as_strings.iter().position(|(p, _)| *p == path.to_string()) {
+            let (_, text) = as_strings.swap_remove(index);
+            if replace(&mut value, &segments, Some(text)) {
+                continue;
+            }
+        }
+        let message = error.inner().message();
+        if let Some(key) = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.strip_suffix('`'))
+        {
+            let (field, hint) = match path.iter().next() {
+                None => (key.to_string(), format!("add {key} to config.toml")),
+                Some(_) => (
+                    format!("{path}.{key}"),
+                    format!("add {key} to the [{path}] section"),
+                ),
+            };
+            // Unless it is the field of the wrong type just dropped
+            if problems.get(&field).is_none() {
+                problems.error(&field, "is required").hint(hint);
+            }
+            return Err(ConfigError::Invalid(problems));
+        }
+        problems.error(&path.to_string(), message);
+        if !replace(&mut value, &segments, None) {
+            return Err(ConfigError::Invalid(problems));
+        }
+    }
+}
+
+/// Replace the table key at `path` with `with`, or remove it when `None`.
+/// False if there is no such key. Array items are kept so the indexes of
+/// later problems stay right.
+fn replace(
+    value: &mut toml::Value,
+    path: &[&serde_path_to_error::Segment],
+    with: Option<toml::Value>,
+) -> bool {
+    use serde_path_to_error::Segment;
+    let Some((last, parents)) = path.split_last() else {
+        return false;
+    };
+    let parent = parents
+        .iter()
+        .try_fold(value, |value, segment| match segment {
+            Segment::Map { key } => value.get_mut(key.as_str()),
+            Segment::Seq { index } => value.get_mut(*index),
+            _ => None,
+        });
+    match (parent, last) {
+        (Some(toml::Value::Table(table)), Segment::Map { key }) => match with {
+            Some(with) => table.insert(key.clone(), with).is_some(),
+            None => table.remove(key).is_some(),
+        },
+        _ => false,
+    }
+}
+
+/// The value at `path`, if any
+fn lookup<'a>(table: &'a toml::Table, path: &[String]) -> Option<&'a toml::Value> {
+    let (key, sections) = path.split_last()?;
+    sections
+        .iter()
+        .try_fold(table, |table, name| table.get(name)?.as_table())?
+        .get(key)
+}
+
+/// The table holding the last key of `path`, created as needed
+fn section<'a>(table: &'a mut toml::Table, path: &[String]) -> Option<&'a mut toml::Table> {
+    let (_, sections) = path.split_last()?;
+    sections.iter().try_fold(table, |table, name| {
+        table
+            .entry(name.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+    })
 }
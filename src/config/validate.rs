@@ -0,0 +1,563 @@
+use std::fmt;
+use std::path::Path;
+
+use super::{AlarmOptions, Config, ENV_PREFIX, SslMode, Transport};
+use crate::error::Error;
+use crate::nextys::registers::RegisterMap;
+use crate::nextys::settings::Settings;
+
+/// How bad a problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Likely a mistake, the config still works
+    Warning,
+    /// The daemon would misbehave or fail, it refuses to start
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Level::Warning => "warning",
+            Level::Error => "error",
+        })
+    }
+}
+
+/// One thing wrong with a config
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub level: Level,
+    /// Path of the field as written in config.toml, ie `tcp.port`
+    pub field: String,
+    pub message: String,
+    /// How to fix it
+    pub hint: Option<String>,
+}
+
+impl Problem {
+    pub fn hint(&mut self, hint: impl Into<String>) -> &mut Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.level, self.field, self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n  hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Every problem found in a config, errors first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn error(&mut self, field: &str, message: impl Into<String>) -> &mut Problem {
+        self.push(Level::Error, field, message.into())
+    }
+
+    pub fn warning(&mut self, field: &str, message: impl Into<String>) -> &mut Problem {
+        self.push(Level::Warning, field, message.into())
+    }
+
+    fn push(&mut self, level: Level, field: &str, message: String) -> &mut Problem {
+        self.problems.push(Problem {
+            level,
+            field: field.to_string(),
+            message,
+            hint: None,
+        });
+        self.problems.last_mut().expect("just pushed")
+    }
+
+    pub fn extend(&mut self, other: Report) {
+        self.problems.extend(other.problems);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().filter(|p| p.level == Level::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().filter(|p| p.level == Level::Warning)
+    }
+
+    /// Looked up by field path, mostly for tests
+    pub fn get(&self, field: &str) -> Option<&Problem> {
+        self.problems.iter().find(|p| p.field == field)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut problems: Vec<&Problem> = self.problems.iter().collect();
+        problems.sort_by_key(|p| std::cmp::Reverse(p.level));
+        for problem in problems {
+            writeln!(f, "{problem}")?;
+        }
+        let errors = self.errors().count();
+        let warnings = self.warnings().count();
+        write!(f, "{errors} error(s), {warnings} warning(s)")
+    }
+}
+
+/// The variable name of every field in `table`
+fn env_names(table: &toml::Table, prefix: &str, names: &mut Vec<String>) {
+    for (key, value) in table {
+        let name = format!("{prefix}{}", key.to_uppercase());
+        match value {
+            toml::Value::Table(table) => env_names(table, &format!("{name}__"), names),
+            _ => names.push(name),
+        }
+    }
+}
+
+/// Levenshtein distance, to suggest the field a misspelled variable meant
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &b) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(a != b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The set point an alarm is raised at and the field it comes from
+fn set_point(section: &str, options: &AlarmOptions, threshold: (&str, f32)) -> (String, f32) {
+    match options.set {
+        Some(set) => (format!("alarms.{section}.set"), set),
+        None => (threshold.0.to_string(), threshold.1),
+    }
+}
+
+impl Config {
+    /// Check the config for values that parse but cannot work, without
+    /// touching the device or the database
+    pub fn validate(&self) -> Report {
+        let mut report = Report::default();
+        self.validate_alarms(&mut report);
+        self.validate_database(&mut report);
+        self.validate_device(&mut report);
+        self.validate_daemon(&mut report);
+        self.validate_outputs(&mut report);
+        self.validate_overrides(&mut report);
+        report
+    }
+
+    /// Check the alarm set points against the battery settings read from the
+    /// device
+    pub fn validate_settings(&self, settings: &Settings) -> Report {
+        let mut report = Report::default();
+        let (field, set) = set_point(
+            "batt_low",
+            &self.alarms.batt_low,
+            ("low_batt_threshold", self.low_batt_threshold),
+        );
+        let float = settings.batt_float_voltage;
+        let cutoff = settings.batt_low_voltage;
+        if set >= float {
+            report
+                .error(
+                    &field,
+                    format!(
+                        "{set} V is not below the battery float voltage of {float} V, \
+                         batt_low would be raised with a full battery"
+                    ),
+                )
+                .hint(format!(
+                    "use a value between the low voltage cutoff of {cutoff} V and {float} V"
+                ));
+        } else if set <= cutoff {
+            report
+                .warning(
+                    &field,
+                    format!(
+                        "{set} V is at or below the low voltage cutoff of {cutoff} V, \
+                         the load is dropped before batt_low is raised"
+                    ),
+                )
+                .hint(format!("raise it above {cutoff} V to get a warning first"));
+        }
+        if let Some(clear) = self.alarms.batt_low.clear
+            && clear >= float
+        {
+            report
+                .warning(
+                    "alarms.batt_low.clear",
+                    format!(
+                        "{clear} V is not below the battery float voltage of {float} V, \
+                         batt_low may never clear while the battery floats"
+                    ),
+                )
+                .hint(format!("use a value below {float} V"));
+        }
+        report
+    }
+
+    fn validate_overrides(&self, report: &mut Report) {
+        if self.unknown_overrides.is_empty() {
+            return;
+        }
+        let table = toml::Table::try_from(self).expect("config should serialize to TOML");
+        let mut known = Vec::new();
+        env_names(&table, ENV_PREFIX, &mut known);
+        for name in &self.unknown_overrides {
+            let problem = report.warning(name, "matches no config field and is ignored");
+            let closest = known
+                .iter()
+                .map(|known| (edit_distance(name, known), known))
+                .min();
+            // Close enough to be a typo, about one edit in eight characters
+            match closest {
+                Some((distance, known)) if distance <= known.len() / 8 => {
+                    problem.hint(format!("did you mean {known}?"))
+                }
+                _ => problem.hint("use __ between a section and its key, ie NEXTYS_MQTT__HOST"),
+            };
+        }
+    }
+
+    fn validate_alarms(&self, report: &mut Report) {
+        for (section, options, threshold) in [
+            (
+                "ac_down",
+                &self.alarms.ac_down,
+                ("ac_down_threshold", self.ac_down_threshold),
+            ),
+            (
+                "batt_low",
+                &self.alarms.batt_low,
+                ("low_batt_threshold", self.low_batt_threshold),
+            ),
+        ] {
+            let (field, set) = set_point(section, options, threshold);
+            if set.is_nan() || set <= 0.0 {
+                report
+                    .error(
+                        &field,
+                        format!("{set} V must be above 0 V for {section} to be raised"),
+                    )
+                    .hint("readings are never negative, use the voltage the alarm should start at");
+            }
+            if let Some(clear) = options.clear
+                && clear < set
+            {
+                report
+                    .warning(
+                        &format!("alarms.{section}.clear"),
+                        format!("{clear} V is below the set point of {set} V and is ignored"),
+                    )
+                    .hint(format!("use a value above {set} V or remove it"));
+            }
+        }
+    }
+
+    fn validate_database(&self, report: &mut Report) {
+        let database = &self.timescaledb;
+        if database.timescaledb_host.is_empty() {
+            report.error("timescaledb.timescaledb_host", "is empty");
+        }
+        if database.timescaledb_port == 0 {
+            report
+                .error("timescaledb.timescaledb_port", "0 is not a valid port")
+                .hint("PostgreSQL listens on 5432 unless configured otherwise");
+        }
+        if database.timescaledb_pass.is_empty() {
+            report
+                .warning("timescaledb.timescaledb_pass", "is empty")
+                .hint(
+                    "set timescaledb_pass_file or NEXTYS_TIMESCALEDB__TIMESCALEDB_PASS \
+                     unless the server trusts this host",
+                );
+        }
+        match (
+            &database.timescaledb_ssl_client_cert,
+            &database.timescaledb_ssl_client_key,
+        ) {
+            (Some(_), None) => {
+                report
+                    .error(
+                        "timescaledb.timescaledb_ssl_client_key",
+                        "is required with timescaledb_ssl_client_cert",
+                    )
+                    .hint("set it to the PEM file holding the private key of the certificate");
+            }
+            (None, Some(_)) => {
+                report
+                    .error(
+                        "timescaledb.timescaledb_ssl_client_cert",
+                        "is required with timescaledb_ssl_client_key",
+                    )
+                    .hint("set it to the PEM file holding the client certificate");
+            }
+            _ => {}
+        }
+        for (field, path) in [
+            (
+                "timescaledb.timescaledb_ssl_root_cert",
+                &database.timescaledb_ssl_root_cert,
+            ),
+            (
+                "timescaledb.timescaledb_ssl_client_cert",
+                &database.timescaledb_ssl_client_cert,
+            ),
+            (
+                "timescaledb.timescaledb_ssl_client_key",
+                &database.timescaledb_ssl_client_key,
+            ),
+        ] {
+            if let Some(path) = path
+                && !Path::new(path).is_file()
+            {
+                report.error(field, format!("{path} does not exist"));
+            }
+        }
+        if database.timescaledb_ssl_root_cert.is_some()
+            && !matches!(
+                database.timescaledb_sslmode,
+                SslMode::VerifyCa | SslMode::VerifyFull
+            )
+        {
+            report
+                .warning(
+                    "timescaledb.timescaledb_ssl_root_cert",
+                    "is only used to verify the server with verify-ca or verify-full",
+                )
+                .hint(r#"set timescaledb_sslmode = "verify-full""#);
+        }
+        if database.timescaledb_connect_timeout_secs == 0 {
+            report
+                .error(
+                    "timescaledb.timescaledb_connect_timeout_secs",
+                    "connecting would always time out",
+                )
+                .hint("remove it to wait the default 10 seconds");
+        }
+        if database.timescaledb_max_connections == 0 {
+            report
+                .error(
+                    "timescaledb.timescaledb_max_connections",
+                    "no connection could ever be opened",
+                )
+                .hint("remove it to allow the default 5");
+        }
+        if self.device_id.is_none() {
+            report
+                .warning(
+                    "device_id",
+                    "is not set, readings are uploaded without a sensor id",
+                )
+                .hint("run `nextys_reader initialize-device` to register the device");
+        }
+    }
+
+    fn validate_device(&self, report: &mut Report) {
+        if let Some(path) = &self.register_map
+            && let Err(e) = RegisterMap::load(path)
+        {
+            let reason = match e {
+                Error::RegisterMap { reason, .. } => reason,
+                e => e.to_string(),
+            };
+            report.error("register_map", format!("{path}: {reason}"));
+        }
+        match self.transport {
+            Transport::Rtu => {
+                let serial = &self.serial;
+                if serial.port.is_empty() {
+                    report
+                        .error("serial.port", "is empty")
+                        .hint("the DCW20 usually shows up as /dev/ttyACM0");
+                }
+                if serial.baud_rate == 0 {
+                    report
+                        .error("serial.baud_rate", "must be above 0")
+                        .hint("the DCW20 ships at 19200");
+                }
+                if !(5..=8).contains(&serial.data_bits) {
+                    report
+                        .error(
+                            "serial.data_bits",
+                            format!("{} is not 5, 6, 7 or 8", serial.data_bits),
+                        )
+                        .hint("the DCW20 ships with 8");
+                }
+                if !matches!(serial.stop_bits, 1 | 2) {
+                    report
+                        .error(
+                            "serial.stop_bits",
+                            format!("{} is not 1 or 2", serial.stop_bits),
+                        )
+                        .hint("the DCW20 ships with 1");
+                }
+                if serial.timeout_ms == 0 {
+                    report.error("serial.timeout_ms", "every read would time out");
+                }
+            }
+            Transport::Tcp | Transport::RtuOverTcp => {
+                let tcp = &self.tcp;
+                if tcp.host.is_empty() {
+                    report
+                        .error("tcp.host", "is empty")
+                        .hint("set it to the address of the Modbus gateway");
+                }
+                if tcp.port == 0 {
+                    report
+                        .error("tcp.port", "0 is not a valid port")
+                        .hint("Modbus gateways usually listen on 502");
+                }
+                if tcp.timeout_ms == 0 {
+                    report.error("tcp.timeout_ms", "every read would time out");
+                }
+            }
+        }
+    }
+
+    fn validate_daemon(&self, report: &mut Report) {
+        let daemon = &self.daemon;
+        if daemon.window_secs == 0 {
+            report
+                .error("daemon.window_secs", "must be at least 1")
+                .hint("remove it to upload every 10 seconds");
+        }
+        if daemon.sample_interval_ms == 0 {
+            report
+                .error("daemon.sample_interval_ms", "must be above 0")
+                .hint("remove it to sample every second");
+        } else if daemon.sample_interval_ms > daemon.window_secs * 1000 {
+            report
+                .warning(
+                    "daemon.sample_interval_ms",
+                    format!(
+                        "is longer than daemon.window_secs, windows hold a single sample \
+                         taken every {} ms",
+                        daemon.sample_interval_ms
+                    ),
+                )
+                .hint("sample at least once per window");
+        }
+        if daemon.backoff_initial_ms > daemon.backoff_max_secs * 1000 {
+            report.warning(
+                "daemon.backoff_initial_ms",
+                "is longer than daemon.backoff_max_secs, which caps every delay",
+            );
+        }
+    }
+
+    fn validate_outputs(&self, report: &mut Report) {
+        if let Some(token) = &self.http.control_token
+            && token.len() < 16
+        {
+            report
+                .warning(
+                    "http.control_token",
+                    "is shorter than 16 characters and may be guessed",
+                )
+                .hint("generate one with `openssl rand -hex 16`");
+        }
+
+        let mqtt = &self.mqtt;
+        if mqtt.host.is_some() && mqtt.port == 0 {
+            report
+                .error("mqtt.port", "0 is not a valid port")
+                .hint("brokers usually listen on 1883");
+        }
+        if mqtt.password.is_some() && mqtt.username.is_none() {
+            report
+                .warning("mqtt.password", "is ignored without mqtt.username")
+                .hint("set mqtt.username too");
+        }
+
+        let nut = &self.nut;
+        if nut.username.is_some() && nut.password.is_none() {
+            report
+                .warning(
+                    "nut.password",
+                    "is not set, clients may log in with any password",
+                )
+                .hint("set it to the password upsmon is configured with");
+        }
+        if nut.ups_name.is_empty() {
+            report
+                .error("nut.ups_name", "is empty")
+                .hint("clients name the unit as <ups_name>@host, ie nextys");
+        }
+
+        let snmp = &self.snmp;
+        if let Some(engine_id) = &snmp.engine_id
+            && crate::snmp::decode_hex(engine_id).is_none()
+        {
+            report
+                .error("snmp.engine_id", "should be 5 to 32 bytes of hex")
+                .hint("remove it to derive one from ip_address");
+        }
+        if snmp.bind.is_some() && snmp.community.is_none() && snmp.users.is_empty() {
+            report
+                .warning("snmp.bind", "every request is refused")
+                .hint("set snmp.community for SNMPv2c or add snmp.users for SNMPv3");
+        }
+        for (i, user) in snmp.users.iter().enumerate() {
+            if user.auth_password.len() < 8 {
+                report
+                    .warning(
+                        &format!("snmp.users[{i}].auth_password"),
+                        "is shorter than 8 characters, managers refuse to derive a key from it",
+                    )
+                    .hint("use a longer password");
+            }
+            if let Some(password) = &user.priv_password
+                && password.len() < 8
+            {
+                report
+                    .warning(
+                        &format!("snmp.users[{i}].priv_password"),
+                        "is shorter than 8 characters, managers refuse to derive a key from it",
+                    )
+                    .hint("use a longer password");
+            }
+        }
+        for (i, trap) in snmp.traps.iter().enumerate() {
+            match &trap.user {
+                Some(name) if !snmp.users.iter().any(|user| &user.name == name) => {
+                    report
+                        .error(
+                            &format!("snmp.traps[{i}].user"),
+                            format!("{name} is not one of snmp.users"),
+                        )
+                        .hint("add the user to snmp.users or send SNMPv2c with community");
+                }
+                Some(_) => {}
+                None if trap.community.is_none() => {
+                    report
+                        .error(
+                            &format!("snmp.traps[{i}]"),
+                            format!("{} has neither a user nor a community", trap.target),
+                        )
+                        .hint("set user for SNMPv3 or community for SNMPv2c");
+                }
+                None => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use chrono::Utc;
+use log::{error, info, warn};
+use sd_notify::NotifyState;
+use sqlx::{Pool, Postgres};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Instant};
+
+use crate::bus::{Bus, BusHandle, Event};
+use crate::config::Config;
+use crate::database;
+use crate::events::SensorEvent;
+use crate::http;
+use crate::metrics::Metrics;
+use crate::mqtt;
+use crate::nextys::settings::Settings;
+use crate::nut;
+use crate::snmp;
+use crate::spool::{MetricsSpool, SpooledMetrics};
+
+/// Exponential reconnect delay that doubles on each failure up to a cap
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            delay: initial,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Whether the delay since the last failure has passed
+    pub fn ready(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Record a failed attempt, returning how long until the next one
+    pub fn failed(&mut self) -> Duration {
+        let delay = self.delay;
+        self.next_attempt = Instant::now() + delay;
+        self.delay = (self.delay * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = self.initial;
+        self.next_attempt = Instant::now();
+    }
+}
+
+/// Events held while the database is unreachable before the oldest are dropped
+const MAX_PENDING_EVENTS: usize = 1000;
+
+/// Long running uploader that keeps the device and database connected
+pub struct Daemon {
+    config: Config,
+    bus: BusHandle,
+    pool: Option<Pool<Postgres>>,
+    spool: MetricsSpool,
+    database_backoff: Backoff,
+    /// Events not yet in the database, oldest first
+    pending_events: VecDeque<SensorEvent>,
+    /// Whether the device was lost, so the next connect is a restore
+    device_lost: bool,
+    /// Settings last written to the history
+    recorded_settings: Option<Settings>,
+}
+
+impl Daemon {
+    /// Start sampling the device described by `config`
+    pub fn new(config: Config) -> Self {
+        Daemon {
+            bus: Bus::spawn(&config),
+            spool: MetricsSpool::new(&config.spool),
+            database_backoff: Backoff::new(
+                Duration::from_millis(config.daemon.backoff_initial_ms),
+                Duration::from_secs(config.daemon.backoff_max_secs),
+            ),
+            config,
+            pool: None,
+            pending_events: VecDeque::new(),
+            device_lost: false,
+            recorded_settings: None,
+        }
+    }
+
+    /// Handle to the device for other clients in the process
+    pub fn bus(&self) -> &BusHandle {
+        &self.bus
+    }
+
+    /// Upload windows until SIGTERM or SIGINT, then flush the open window
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut events = self.bus.subscribe();
+        let mut watchdog = self.watchdog_interval();
+        let api = match self.config.http.bind {
+            Some(bind) => {
+                let listener = TcpListener::bind(bind).await?;
+                info!("Serving HTTP API on {bind}");
+                let metrics = Arc::new(Metrics::new(&self.config));
+                tokio::spawn(metrics.clone().watch(self.bus.subscribe()));
+                let router = with_control(
+                    http::router(self.bus.snapshot(), metrics, &self.config),
+                    &self.bus,
+                    &self.config,
+                );
+                Some(tokio::spawn(http::serve(listener, router)))
+            }
+            None => None,
+        };
+
+        let nut = match self.config.nut.bind {
+            Some(bind) => {
+                let listener = TcpListener::bind(bind).await?;
+                info!("Serving NUT on {bind}");
+                let snapshot = self.bus.snapshot();
+                let config = self.config.clone();
+                Some(tokio::spawn(async move {
+                    nut::serve(listener, snapshot, &config).await
+                }))
+            }
+            None => None,
+        };
+        let snmp = match self.config.snmp.bind {
+            Some(bind) => {
+                let socket = UdpSocket::bind(bind).await?;
+                info!("Serving SNMP on {bind}");
+                let agent = snmp::Agent::new(&self.config, self.bus.snapshot())?;
+                Some(tokio::spawn(snmp::serve(
+                    socket,
+                    agent,
+                    self.bus.subscribe(),
+                )))
+            }
+            None => None,
+        };
+        let mqtt = tokio::spawn(mqtt::run(self.config.clone(), self.bus.clone()));
+
+        self.notify(&[NotifyState::Ready]);
+        info!("Daemon started");
+        self.record(SensorEvent::daemon_started(Utc::now())).await;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(Event::Window(record)) => self.upload(record).await,
+                    Ok(event) => self.record_bus_event(&event).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Uploader fell behind, {missed} events missed")
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick(&mut watchdog) => self.notify(&[NotifyState::Watchdog]),
+                _ = sigterm.recv() => break,
+                _ = sigint.recv() => break,
+            }
+        }
+
+        info!("Shutting down");
+        self.notify(&[NotifyState::Stopping]);
+        // Windows completed while shutting down are still queued
+        while let Ok(event) = events.try_recv() {
+            match event {
+                Event::Window(record) => self.upload(record).await,
+                event => self.record_bus_event(&event).await,
+            }
+        }
+        if let Some(record) = self.bus.shutdown().await {
+            self.upload(record).await;
+        }
+        for server in [api, nut, snmp].into_iter().flatten() {
+            server.abort();
+        }
+        // Publishes the offline status once the bus has closed
+        let _ = mqtt.await;
+        if let Some(pool) = self.pool.take() {
+            pool.close().await;
+        }
+        Ok(())
+    }
+
+    /// Upload a window, spooling it while the database is unreachable
+    async fn upload(&mut self, record: SpooledMetrics) {
+        if !self.database_backoff.ready() {
+            self.set_database_connected(false);
+            return self.push(&record);
+        }
+        if self.pool.is_none() {
+            match database::initialize_connection(self.config.clone()).await {
+                Ok(pool) => {
+                    info!("Connected to database");
+                    // Uploads may still work against an older schema
+                    if let Err(e) = database::migrations::migrate(&pool).await {
+                        error!("Failed to migrate database: {e}");
+                    }
+                    self.pool = Some(pool);
+                }
+                Err(e) => {
+                    let delay = self.database_backoff.failed();
+                    error!("Failed to connect to database, retrying in {delay:?}: {e}");
+                    self.set_database_connected(false);
+                    return self.push(&record);
+                }
+            }
+        }
+        let pool = self.pool.as_ref().expect("pool was connected above");
+        let time = record.time;
+        let uploaded = match self.spool.upload(pool, &self.config, record).await {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                error!("Failed to spool metrics, dropping the window from {time}: {e}");
+                false
+            }
+        };
+        self.set_database_connected(uploaded);
+        if uploaded {
+            self.database_backoff.reset();
+            self.flush_events().await;
+            self.record_settings().await;
+        } else {
+            let delay = self.database_backoff.failed();
+            warn!("Spooling metrics for at least {delay:?}");
+        }
+    }
+
+    /// Spool a window, dropping it when the spool cannot be written so
+    /// sampling carries on
+    fn push(&self, record: &SpooledMetrics) {
+        if let Err(e) = self.spool.push(record) {
+            error!(
+                "Failed to spool metrics, dropping the window from {}: {e}",
+                record.time
+            );
+        }
+    }
+
+    /// Store the settings last read by the bus when they changed, which the
+    /// history checks against the database again
+    async fn record_settings(&mut self) {
+        let Some(settings) = self.bus.snapshot().read().unwrap().settings.clone() else {
+            return;
+        };
+        if let Some(recorded) = &self.recorded_settings
+            && recorded.diff(&settings).is_empty()
+        {
+            return;
+        }
+        let pool = self
+            .pool
+            .as_ref()
+            .expect("pool is connected after an upload");
+        let result = match database::record_settings(pool, &self.config, Utc::now(), &settings)
+            .await
+        {
+            Ok(Some(_)) => database::upload_settings(pool.clone(), &self.config, &settings).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => self.recorded_settings = Some(settings),
+            Err(e) => warn!("Failed to record settings, retrying next window: {e}"),
+        }
+    }
+
+    /// Record the incidents among the bus events
+    async fn record_bus_event(&mut self, event: &Event) {
+        let now = Utc::now();
+        let event = match event {
+            Event::Alarm(alarm) => SensorEvent::alarm(alarm),
+            Event::SettingsChanged(changes) => SensorEvent::settings_changed(now, changes),
+            Event::Disconnected(error) => {
+                self.device_lost = true;
+                SensorEvent::comm_lost(now, error)
+            }
+            Event::Connected if self.device_lost => {
+                self.device_lost = false;
+                SensorEvent::comm_restored(now)
+            }
+            _ => return,
+        };
+        self.record(event).await;
+    }
+
+    /// Write an event to the database, holding it until the next successful
+    /// upload if the database is not connected
+    async fn record(&mut self, event: SensorEvent) {
+        if self.pending_events.len() >= MAX_PENDING_EVENTS {
+            warn!("Too many events waiting for the database, dropping the oldest");
+            self.pending_events.pop_front();
+        }
+        self.pending_events.push_back(event);
+        if self.database_backoff.ready() {
+            self.flush_events().await;
+        }
+    }
+
+    async fn flush_events(&mut self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        while let Some(event) = self.pending_events.front() {
+            if let Err(e) = database::insert_event(pool, &self.config, event).await {
+                warn!("Failed to record event, keeping it for later: {e}");
+                return;
+            }
+            self.pending_events.pop_front();
+        }
+    }
+
+    fn set_database_connected(&self, connected: bool) {
+        self.bus.snapshot().write().unwrap().database_connected = connected;
+    }
+
+    /// Half the systemd watchdog timeout when notifications are enabled
+    fn watchdog_interval(&self) -> Option<time::Interval> {
+        let mut usec = 0;
+        if !self.config.daemon.systemd_notify || !sd_notify::watchdog_enabled(false, &mut usec) {
+            return None;
+        }
+        Some(time::interval(Duration::from_micros(usec / 2)))
+    }
+
+    fn notify(&self, state: &[NotifyState]) {
+        if !self.config.daemon.systemd_notify {
+            return;
+        }
+        if let Err(e) = sd_notify::notify(false, state) {
+            warn!("Failed to notify systemd: {e}");
+        }
+    }
+}
+
+/// `router` plus the control endpoints when `http.control_token` enables them
+fn with_control(router: Router, bus: &BusHandle, config: &Config) -> Router {
+    match &config.http.control_token {
+        Some(token) => router.merge(http::control(bus.clone(), token)),
+        None => router,
+    }
+}
+
+/// Serve Prometheus metrics and the JSON API on `bind` without a database,
+/// until SIGTERM or SIGINT
+pub async fn export(config: Config, bind: SocketAddr) -> io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let bus = Bus::spawn(&config);
+    let metrics = Arc::new(Metrics::new(&config));
+    tokio::spawn(metrics.clone().watch(bus.subscribe()));
+    let listener = TcpListener::bind(bind).await?;
+    info!("Serving metrics on http://{bind}/metrics");
+    let router = with_control(
+        http::router(bus.snapshot(), metrics, &config),
+        &bus,
+        &config,
+    );
+    tokio::select! {
+        result = http::serve(listener, router) => result?,
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+    bus.shutdown().await;
+    Ok(())
+}
+
+/// Wait for the next tick, or forever when there is no interval
+async fn tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,69 @@
+-- Schema created by initialize_tables before migrations existed, so every
+-- statement leaves an existing deployment as it is
+CREATE TABLE IF NOT EXISTS sensor_metadata (
+    id SERIAL PRIMARY KEY,
+    ip_address INET,
+    sysName VARCHAR(50),
+    location VARCHAR(50),
+    batt_low INT,
+    ac_down INT,
+    batt_type INTEGER,
+    charge_voltage REAL,
+    charge_current REAL,
+    float_voltage REAL,
+    low_voltage REAL,
+    deep_discharge_voltage REAL,
+    max_discharge_current REAL,
+    batt_capacity REAL,
+    DCDC_OUTPUT_MODE INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS sensor_data (
+    time TIMESTAMPTZ NOT NULL,
+    sensor_id INTEGER,
+    input_voltage_min REAL,
+    input_voltage_avg REAL,
+    input_voltage_max REAL,
+    input_current_min REAL,
+    input_current_avg REAL,
+    input_current_max REAL,
+    output_voltage_min REAL,
+    output_voltage_avg REAL,
+    output_voltage_max REAL,
+    output_current_min REAL,
+    output_current_avg REAL,
+    output_current_max REAL,
+    batt_voltage_min REAL,
+    batt_voltage_avg REAL,
+    batt_voltage_max REAL,
+    batt_current_min REAL,
+    batt_current_avg REAL,
+    batt_current_max REAL,
+    batt_soc REAL,
+    batt_int_resistance REAL,
+    batt_charge_capacity REAL,
+    operating_time INTEGER,
+    batt_operating_time INTEGER
+);
+
+-- The name Postgres gave the unnamed index initialize_tables created
+CREATE INDEX IF NOT EXISTS sensor_data_sensor_id_time_idx ON sensor_data (sensor_id, time DESC);
+
+SELECT create_hypertable('sensor_data', 'time', if_not_exists => TRUE);
+
+-- Compression settings cannot be changed once chunks are compressed
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM timescaledb_information.hypertables
+        WHERE hypertable_name = 'sensor_data' AND compression_enabled
+    ) THEN
+        ALTER TABLE sensor_data SET (
+            timescaledb.compress,
+            timescaledb.compress_segmentby = 'sensor_id'
+        );
+    END IF;
+END
+$$;
+
+SELECT add_compression_policy('sensor_data', INTERVAL '2 days', if_not_exists => TRUE);
@@ -0,0 +1,13 @@
+-- Also created by initialize_tables in the release that introduced it
+CREATE TABLE IF NOT EXISTS sensor_events (
+    time TIMESTAMPTZ NOT NULL,
+    sensor_id INTEGER,
+    kind VARCHAR(32) NOT NULL,
+    severity VARCHAR(16) NOT NULL,
+    payload JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX IF NOT EXISTS sensor_events_sensor_id_kind_time_idx
+    ON sensor_events (sensor_id, kind, time DESC);
+
+SELECT create_hypertable('sensor_events', 'time', if_not_exists => TRUE);
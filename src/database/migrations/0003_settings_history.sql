@@ -0,0 +1,19 @@
+-- Also created by initialize_tables in the release that introduced it
+CREATE TABLE IF NOT EXISTS sensor_settings_history (
+    time TIMESTAMPTZ NOT NULL,
+    sensor_id INTEGER,
+    batt_type SMALLINT,
+    batt_charge_voltage REAL,
+    batt_charge_current REAL,
+    batt_float_voltage REAL,
+    batt_low_voltage REAL,
+    batt_deep_discharge_voltage REAL,
+    batt_max_discharge_current REAL,
+    batt_capacity REAL,
+    nominal_output_voltage REAL,
+    max_input_current REAL,
+    max_output_current REAL
+);
+
+CREATE INDEX IF NOT EXISTS sensor_settings_history_sensor_id_time_idx
+    ON sensor_settings_history (sensor_id, time DESC);
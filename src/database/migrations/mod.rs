@@ -0,0 +1,97 @@
+use log::info;
+use sqlx::{Pool, Postgres};
+
+/// A schema change, applied once per database in version order
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, oldest first. Applied migrations must never be edited,
+/// add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        sql: include_str!("0001_baseline.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "sensor_events",
+        sql: include_str!("0002_sensor_events.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "settings_history",
+        sql: include_str!("0003_settings_history.sql"),
+    },
+];
+
+/// Held while migrating so two processes starting together take turns
+const LOCK_KEY: i64 = 0x6e65_7874_7973;
+
+/// Versions already applied, none if migrations never ran
+pub async fn applied(pool: &Pool<Postgres>) -> Result<Vec<i64>, sqlx::Error> {
+    let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass('schema_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+    let versions: Vec<(i64,)> = sqlx::query_as("SELECT version FROM schema_migrations")
+        .fetch_all(pool)
+        .await?;
+    Ok(versions.into_iter().map(|(version,)| version).collect())
+}
+
+/// Migrations not yet applied, in the order they would run
+pub async fn pending(pool: &Pool<Postgres>) -> Result<Vec<&'static Migration>, sqlx::Error> {
+    let applied = applied(pool).await?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+/// Apply every pending migration, each in its own transaction, returning
+/// the ones applied
+pub async fn migrate(pool: &Pool<Postgres>) -> Result<Vec<&'static Migration>, sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );",
+    )
+    .execute(pool)
+    .await?;
+    let mut ran = Vec::new();
+    for migration in MIGRATIONS {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(LOCK_KEY)
+            .execute(&mut *tx)
+            .await?;
+        // Checked under the lock, another process may have just applied it
+        let (done,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM schema_migrations WHERE version = $1)")
+                .bind(migration.version)
+                .fetch_one(&mut *tx)
+                .await?;
+        if done {
+            continue;
+        }
+        sqlx::raw_sql(migration.sql).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!("Applied migration {} {}", migration.version, migration.name);
+        ran.push(migration);
+    }
+    Ok(ran)
+}
@@ -1,144 +1,64 @@
-use sqlx::{Postgres, Transaction, postgres::PgPoolOptions};
+pub mod migrations;
 
-use crate::config::Config;
-use crate::nextys::meters::Meters;
-use crate::nextys::settings::Settings;
-use chrono::Utc;
+use std::time::Duration;
+
+use log::info;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+use sqlx::{Postgres, Row};
+
+use crate::alarm::Flags;
+use crate::config::{Config, SslMode, TimescaleDB};
+use crate::events::{EventKind, SensorEvent, Severity};
+use crate::nextys::meters::MetersAggregate;
+use crate::nextys::settings::{BatteryType, SettingChange, Settings};
+use chrono::{DateTime, Utc};
+
+/// Connect options for the `[timescaledb]` section. Fields are passed as
+/// they are rather than through a URL, so passwords need no escaping.
+pub fn connect_options(config: &TimescaleDB) -> PgConnectOptions {
+    let ssl_mode = match config.timescaledb_sslmode {
+        SslMode::Disable => PgSslMode::Disable,
+        SslMode::Allow => PgSslMode::Allow,
+        SslMode::Prefer => PgSslMode::Prefer,
+        SslMode::Require => PgSslMode::Require,
+        SslMode::VerifyCa => PgSslMode::VerifyCa,
+        SslMode::VerifyFull => PgSslMode::VerifyFull,
+    };
+    let mut options = PgConnectOptions::new()
+        .host(&config.timescaledb_host)
+        .port(config.timescaledb_port)
+        .username(&config.timescaledb_user)
+        .password(&config.timescaledb_pass)
+        .database(&config.timescaledb_db)
+        .application_name(&config.timescaledb_application_name)
+        .ssl_mode(ssl_mode);
+    if let Some(path) = &config.timescaledb_ssl_root_cert {
+        options = options.ssl_root_cert(path);
+    }
+    if let Some(path) = &config.timescaledb_ssl_client_cert {
+        options = options.ssl_client_cert(path);
+    }
+    if let Some(path) = &config.timescaledb_ssl_client_key {
+        options = options.ssl_client_key(path);
+    }
+    if let Some(timeout) = config.timescaledb_statement_timeout_ms {
+        options = options.options([("statement_timeout", timeout.to_string())]);
+    }
+    options
+}
 
 /// Initialize database connection
 pub async fn initialize_connection(config: Config) -> Result<sqlx::Pool<Postgres>, sqlx::Error> {
+    let database = &config.timescaledb;
     let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(
-            format!(
-                "postgres://{}:{}@{}/{}",
-                config.timescaledb.timescaledb_user,
-                config.timescaledb.timescaledb_pass,
-                config.timescaledb.timescaledb_host,
-                config.timescaledb.timescaledb_db
-            )
-            .as_str(),
-        )
+        .max_connections(database.timescaledb_max_connections.max(1))
+        .acquire_timeout(Duration::from_secs(
+            database.timescaledb_connect_timeout_secs,
+        ))
+        .connect_with(connect_options(database))
         .await?;
     Ok(pool)
 }
-/// Initialize tables, this should only need to be called once at database creation.
-pub async fn initialize_tables(pool: sqlx::Pool<Postgres>) -> Result<(), sqlx::Error> {
-    let meta_exists: (bool,) = sqlx::query_as(
-        "SELECT EXISTS (
-                SELECT 1 FROM information_schema.tables 
-                WHERE table_name = 'sensor_metadata')",
-    )
-    .fetch_one(&pool)
-    .await?;
-
-    let hypertable_exists: (bool,) = sqlx::query_as(
-        "SELECT EXISTS (
-                SELECT 1 FROM _timescaledb_catalog.hypertable
-                WHERE table_name = 'sensor_data')",
-    )
-    .fetch_one(&pool)
-    .await?;
-
-    match meta_exists.0 {
-        true => {}
-        false => {
-            let mut tx: Transaction<Postgres> = pool.begin().await?;
-            sqlx::query(
-                "CREATE TABLE sensor_metadata (
-                        id SERIAL PRIMARY KEY,
-                        ip_address INET,
-                        sysName VARCHAR(50),
-                        location VARCHAR(50),
-                        batt_low INT,
-                        ac_down INT,
-                        batt_type INTEGER,
-                        charge_voltage REAL,
-                        charge_current REAL,
-                        float_voltage REAL,
-                        low_voltage REAL,
-                        deep_discharge_voltage REAL,
-                        max_discharge_current REAL,
-                        batt_capacity REAL,
-                        DCDC_OUTPUT_MODE INTEGER
-                        );",
-            )
-            .execute(&mut *tx)
-            .await?;
-            tx.commit().await?;
-            println!("Created metadata table");
-        }
-    }
-    // check for hypertable
-    match hypertable_exists.0 {
-        true => {}
-        false => {
-            let mut tx: Transaction<Postgres> = pool.begin().await?;
-            // Create data table
-            sqlx::query(
-                "CREATE TABLE sensor_data (
-                        time TIMESTAMPTZ NOT NULL,
-                        sensor_id INTEGER,
-                        input_voltage_min REAL,
-                        input_voltage_avg REAL,
-                        input_voltage_max REAL,
-                        input_current_min REAL,
-                        input_current_avg REAL,
-                        input_current_max REAL,
-                        output_voltage_min REAL,
-                        output_voltage_avg REAL,
-                        output_voltage_max REAL,
-                        output_current_min REAL,
-                        output_current_avg REAL,
-                        output_current_max REAL,
-                        batt_voltage_min REAL,
-                        batt_voltage_avg REAL,
-                        batt_voltage_max REAL,
-                        batt_current_min REAL,
-                        batt_current_avg REAL,
-                        batt_current_max REAL,
-                        batt_soc REAL,
-                        batt_int_resistance REAL,
-                        batt_charge_capacity REAL,
-                        operating_time INTEGER,
-                        batt_operating_time INTEGER
-                    );",
-            )
-            .execute(&mut *tx)
-            .await?;
-            println!("Created data table");
-            // create index
-            sqlx::query("CREATE INDEX ON sensor_data (sensor_id, time DESC);")
-                .execute(&mut *tx)
-                .await?;
-            // Convert to hypertable
-            sqlx::query(
-                "SELECT create_hypertable('sensor_data', 'time');
-            ",
-            )
-            .execute(&mut *tx)
-            .await?;
-            println!("Converted to hypertable");
-            // set compression
-            sqlx::query(
-                "
-                ALTER TABLE sensor_data SET (timescaledb.compress,
-                    timescaledb.compress_segmentby = 'sensor_id');",
-            )
-            .execute(&mut *tx)
-            .await?;
-            // set compression policy
-            sqlx::query("SELECT add_compression_policy('sensor_data', INTERVAL '2 days');")
-                .execute(&mut *tx)
-                .await?;
-
-            tx.commit().await?;
-        }
-    }
-    println!("Meta existence: {:#?}", meta_exists.0);
-    println!("Hypertable existence: {:#?}", hypertable_exists.0);
-    Ok(())
-}
 //get/set id
 pub async fn get_id(
     pool: sqlx::Pool<sqlx::Postgres>,
@@ -156,7 +76,7 @@ pub async fn get_id(
                 VALUES ($1, $2, $3)
                 RETURNING id;",
             )
-            .bind(&config.ip_address)
+            .bind(config.ip_address)
             .bind(&config.sys_name)
             .bind(&config.location)
             .fetch_one(&pool)
@@ -220,64 +140,304 @@ VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
     .await?;
     Ok(())
 }
-/// upload metrics
+/// the settings last stored in the history for this device
+pub async fn latest_settings(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+) -> Result<Option<Settings>, sqlx::Error> {
+    let row = sqlx::query(
+        "
+SELECT * FROM sensor_settings_history
+WHERE sensor_id IS NOT DISTINCT FROM $1
+ORDER BY time DESC
+LIMIT 1",
+    )
+    .bind(config.device_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let batt_type: i16 = row.try_get("batt_type")?;
+    Ok(Some(Settings {
+        batt_type: BatteryType::from(batt_type),
+        batt_type_int: batt_type,
+        batt_charge_voltage: row.try_get("batt_charge_voltage")?,
+        batt_charge_current: row.try_get("batt_charge_current")?,
+        batt_float_voltage: row.try_get("batt_float_voltage")?,
+        batt_low_voltage: row.try_get("batt_low_voltage")?,
+        batt_deep_discharge_voltage: row.try_get("batt_deep_discharge_voltage")?,
+        batt_max_discharge_current: row.try_get("batt_max_discharge_current")?,
+        batt_capacity: row.try_get("batt_capacity")?,
+        nominal_output_voltage: row.try_get("nominal_output_voltage")?,
+        max_input_current: row.try_get("max_input_current")?,
+        max_output_current: row.try_get("max_output_current")?,
+    }))
+}
+
+/// add `settings` to the history if they differ from the last stored
+/// snapshot, logging each change. Returns the changes, empty for the first
+/// snapshot, or `None` when nothing changed and nothing was stored.
+pub async fn record_settings(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    time: DateTime<Utc>,
+    settings: &Settings,
+) -> Result<Option<Vec<SettingChange>>, sqlx::Error> {
+    let changes = match latest_settings(pool, config).await? {
+        Some(previous) => {
+            let changes = previous.diff(settings);
+            if changes.is_empty() {
+                return Ok(None);
+            }
+            changes
+        }
+        None => Vec::new(),
+    };
+    sqlx::query(
+        "
+INSERT INTO sensor_settings_history (
+    time,
+    sensor_id,
+    batt_type,
+    batt_charge_voltage,
+    batt_charge_current,
+    batt_float_voltage,
+    batt_low_voltage,
+    batt_deep_discharge_voltage,
+    batt_max_discharge_current,
+    batt_capacity,
+    nominal_output_voltage,
+    max_input_current,
+    max_output_current
+    )
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+    )
+    .bind(time)
+    .bind(config.device_id)
+    .bind(settings.batt_type_int)
+    .bind(settings.batt_charge_voltage)
+    .bind(settings.batt_charge_current)
+    .bind(settings.batt_float_voltage)
+    .bind(settings.batt_low_voltage)
+    .bind(settings.batt_deep_discharge_voltage)
+    .bind(settings.batt_max_discharge_current)
+    .bind(settings.batt_capacity)
+    .bind(settings.nominal_output_voltage)
+    .bind(settings.max_input_current)
+    .bind(settings.max_output_current)
+    .execute(pool)
+    .await?;
+    if changes.is_empty() {
+        info!("Stored first settings snapshot");
+    }
+    for change in &changes {
+        info!("Setting changed, {change}");
+    }
+    Ok(Some(changes))
+}
+/// upload metrics for the window ending at `time`, with the alarm engine's
+/// `alarms` or else the window averages against the thresholds. The row and
+/// the alarm flags commit together, so a failed upload can be replayed
+/// without storing the window twice.
 pub async fn upload_metrics(
     pool: &sqlx::Pool<sqlx::Postgres>,
     config: &Config,
-    meters: &Meters,
+    time: DateTime<Utc>,
+    meters: &MetersAggregate,
+    alarms: Option<Flags>,
 ) -> Result<(), sqlx::Error> {
-    let now = Utc::now().timestamp_millis();
+    let mut tx = pool.begin().await?;
     sqlx::query(
         "
 INSERT INTO sensor_data (
     time,
     sensor_id,
+    input_voltage_min,
     input_voltage_avg,
+    input_voltage_max,
+    input_current_min,
     input_current_avg,
+    input_current_max,
+    output_voltage_min,
     output_voltage_avg,
+    output_voltage_max,
+    output_current_min,
     output_current_avg,
+    output_current_max,
+    batt_voltage_min,
     batt_voltage_avg,
+    batt_voltage_max,
+    batt_current_min,
     batt_current_avg,
+    batt_current_max,
     batt_soc,
-    batt_int_resistance
+    batt_int_resistance,
+    batt_charge_capacity,
+    operating_time,
+    batt_operating_time
     )
-VALUES (to_timestamp($1), $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14,
+    $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25)",
     )
-    .bind(now)
+    .bind(time)
     .bind(config.device_id)
-    .bind(meters.input_voltage)
-    .bind(meters.input_current)
-    .bind(meters.output_voltage)
-    .bind(meters.output_current)
-    .bind(meters.batt_voltage)
-    .bind(meters.batt_current)
-    .bind(meters.batt_soc)
-    .bind(meters.batt_int_resistance)
-    .execute(pool)
+    .bind(meters.input_voltage.min)
+    .bind(meters.input_voltage.avg)
+    .bind(meters.input_voltage.max)
+    .bind(meters.input_current.min)
+    .bind(meters.input_current.avg)
+    .bind(meters.input_current.max)
+    .bind(meters.output_voltage.min)
+    .bind(meters.output_voltage.avg)
+    .bind(meters.output_voltage.max)
+    .bind(meters.output_current.min)
+    .bind(meters.output_current.avg)
+    .bind(meters.output_current.max)
+    .bind(meters.batt_voltage.min)
+    .bind(meters.batt_voltage.avg)
+    .bind(meters.batt_voltage.max)
+    .bind(meters.batt_current.min)
+    .bind(meters.batt_current.avg)
+    .bind(meters.batt_current.max)
+    .bind(meters.batt_soc.avg)
+    .bind(meters.batt_int_resistance.avg)
+    .bind(meters.batt_charge_capacity.map(|stat| stat.avg))
+    .bind(meters.operating_time)
+    .bind(meters.batt_operating_time)
+    .execute(&mut *tx)
     .await?;
-    // Check for ac_down/batt_low
-    let ac_down: i32;
-    let batt_low: i32;
-    if meters.input_voltage <= config.ac_down_threshold {
-        ac_down = 1;
-    } else {
-        ac_down = 0;
-    }
-    if meters.batt_voltage <= config.low_batt_threshold {
-        batt_low = 1;
-    } else {
-        batt_low = 0;
-    }
+    let flags = alarms
+        .unwrap_or_else(|| Flags::new(config, meters.input_voltage.avg, meters.batt_voltage.avg));
     sqlx::query(
         "
     UPDATE sensor_metadata
         SET batt_low = $1, ac_down = $2
     WHERE id = $3",
     )
-    .bind(batt_low)
-    .bind(ac_down)
+    .bind(flags.batt_low as i32)
+    .bind(flags.ac_down as i32)
     .bind(config.device_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// record an event for this device
+pub async fn insert_event(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    event: &SensorEvent,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "
+INSERT INTO sensor_events (time, sensor_id, kind, severity, payload)
+VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(event.time)
+    .bind(config.device_id)
+    .bind(event.kind.name())
+    .bind(event.severity.name())
+    .bind(&event.payload)
     .execute(pool)
     .await?;
     Ok(())
 }
+
+/// Which events [`query_events`] returns, every field narrows the result
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub sensor_id: Option<i32>,
+    pub kinds: Vec<EventKind>,
+    /// Least severity to include
+    pub severity: Option<Severity>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+/// time, sensor_id, kind, severity and payload of a `sensor_events` row
+type EventRow = (
+    DateTime<Utc>,
+    Option<i32>,
+    String,
+    String,
+    serde_json::Value,
+);
+
+/// Conditions shared by [`query_events`] and [`count_events`], bound by
+/// [`EventFilter::bind`]
+const EVENT_FILTER: &str = "
+WHERE ($1::INTEGER IS NULL OR sensor_id = $1)
+    AND kind = ANY($2)
+    AND severity = ANY($3)
+    AND ($4::TIMESTAMPTZ IS NULL OR time >= $4)
+    AND ($5::TIMESTAMPTZ IS NULL OR time < $5)";
+
+impl EventFilter {
+    /// Bind the parameters of [`EVENT_FILTER`]. Kinds and severities are
+    /// always listed so rows written by a newer version with ones this
+    /// version does not know are neither listed nor counted.
+    fn bind<'q, O>(
+        &self,
+        query: sqlx::query::QueryAs<'q, sqlx::Postgres, O, sqlx::postgres::PgArguments>,
+    ) -> sqlx::query::QueryAs<'q, sqlx::Postgres, O, sqlx::postgres::PgArguments> {
+        let kinds: Vec<&str> = if self.kinds.is_empty() {
+            EventKind::ALL.iter().map(EventKind::name).collect()
+        } else {
+            self.kinds.iter().map(EventKind::name).collect()
+        };
+        let severities: Vec<&str> = self
+            .severity
+            .unwrap_or(Severity::Info)
+            .and_above()
+            .iter()
+            .map(Severity::name)
+            .collect();
+        query
+            .bind(self.sensor_id)
+            .bind(kinds)
+            .bind(severities)
+            .bind(self.since)
+            .bind(self.until)
+    }
+}
+
+/// events matching `filter`, newest first, with the sensor they came from
+pub async fn query_events(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    filter: &EventFilter,
+) -> Result<Vec<(Option<i32>, SensorEvent)>, sqlx::Error> {
+    let sql = format!(
+        "SELECT time, sensor_id, kind, severity, payload FROM sensor_events{EVENT_FILTER}
+ORDER BY time DESC
+LIMIT $6"
+    );
+    let rows: Vec<EventRow> = filter
+        .bind(sqlx::query_as(&sql))
+        .bind(filter.limit)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(time, sensor_id, kind, severity, payload)| {
+            let event = SensorEvent {
+                time,
+                kind: EventKind::from_name(&kind)?,
+                severity: Severity::from_name(&severity)?,
+                payload,
+            };
+            Some((sensor_id, event))
+        })
+        .collect())
+}
+
+/// how many events match `filter`, ignoring its limit
+pub async fn count_events(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    filter: &EventFilter,
+) -> Result<i64, sqlx::Error> {
+    let sql = format!("SELECT COUNT(*) FROM sensor_events{EVENT_FILTER}");
+    let (count,): (i64,) = filter.bind(sqlx::query_as(&sql)).fetch_one(pool).await?;
+    Ok(count)
+}
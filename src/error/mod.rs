@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use tokio_modbus::ExceptionCode;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors talking to a DCW20
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The serial port or gateway could not be opened
+    #[error("failed to connect to device: {0}")]
+    Connect(#[from] std::io::Error),
+    /// The transport failed mid request, ie the cable was pulled
+    #[error("transport error at address {address:#06x}: {source}")]
+    Transport {
+        address: u16,
+        source: tokio_modbus::Error,
+    },
+    /// The device answered with a Modbus exception
+    #[error("device returned exception for address {address:#06x}: {code}")]
+    Exception { address: u16, code: ExceptionCode },
+    /// The device did not answer in time
+    #[error("timed out after {timeout:?} at address {address:#06x}")]
+    Timeout { address: u16, timeout: Duration },
+    /// The device answered with fewer registers than requested
+    #[error("short read at address {address:#06x}: expected {expected} registers, got {received}")]
+    ShortRead {
+        address: u16,
+        expected: u16,
+        received: usize,
+    },
+    /// A register map file could not be loaded
+    #[error("invalid register map {path}: {reason}")]
+    RegisterMap { path: String, reason: String },
+    /// A register was requested by a name the register map does not have
+    #[error("no register named {0} in the register map")]
+    UnknownRegister(String),
+    /// A write was attempted on a register not marked `rw`
+    #[error("register {0} is read only")]
+    ReadOnly(String),
+    /// A write was attempted with a value outside the register limits
+    #[error("{value} is out of range for {name} (min {min:?}, max {max:?})")]
+    OutOfRange {
+        name: String,
+        value: f64,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    /// The value read back after a write differs from the one written
+    #[error("wrote {expected} to {name} but read back {actual}")]
+    VerifyFailed {
+        name: String,
+        expected: f64,
+        actual: f64,
+    },
+    /// The device is down and waiting out its reconnect backoff
+    #[error("device is not connected")]
+    NotConnected,
+    /// The task owning the device has stopped
+    #[error("device bus has shut down")]
+    BusClosed,
+}
+
+impl Error {
+    /// Whether the link to the device is gone and has to be reopened
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            Error::Connect(_) | Error::Transport { .. } | Error::Timeout { .. }
+        )
+    }
+
+    /// Short stable name of the error, used as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Connect(_) => "connect",
+            Error::Transport { .. } => "transport",
+            Error::Exception { .. } => "exception",
+            Error::Timeout { .. } => "timeout",
+            Error::ShortRead { .. } => "short_read",
+            Error::RegisterMap { .. } => "register_map",
+            Error::UnknownRegister(_) => "unknown_register",
+            Error::ReadOnly(_) => "read_only",
+            Error::OutOfRange { .. } => "out_of_range",
+            Error::VerifyFailed { .. } => "verify_failed",
+            Error::NotConnected => "not_connected",
+            Error::BusClosed => "bus_closed",
+        }
+    }
+}
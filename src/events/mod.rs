@@ -0,0 +1,175 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::alarm::{AlarmEvent, AlarmKind};
+use crate::nextys::settings::SettingChange;
+
+/// What happened, stored in the `kind` column of `sensor_events`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum EventKind {
+    AlarmRaised,
+    AlarmCleared,
+    CommLost,
+    CommRestored,
+    SettingsChanged,
+    DaemonStarted,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        EventKind::AlarmRaised,
+        EventKind::AlarmCleared,
+        EventKind::CommLost,
+        EventKind::CommRestored,
+        EventKind::SettingsChanged,
+        EventKind::DaemonStarted,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::AlarmRaised => "alarm_raised",
+            EventKind::AlarmCleared => "alarm_cleared",
+            EventKind::CommLost => "comm_lost",
+            EventKind::CommRestored => "comm_restored",
+            EventKind::SettingsChanged => "settings_changed",
+            EventKind::DaemonStarted => "daemon_started",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        EventKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// How urgent an event is, ordered from least to most
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub const ALL: [Severity; 3] = [Severity::Info, Severity::Warning, Severity::Critical];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Severity::ALL
+            .into_iter()
+            .find(|severity| severity.name() == name)
+    }
+
+    /// This severity and every one above it
+    pub fn and_above(&self) -> Vec<Severity> {
+        Severity::ALL
+            .into_iter()
+            .filter(|severity| severity >= self)
+            .collect()
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// A row of `sensor_events`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SensorEvent {
+    pub time: DateTime<Utc>,
+    pub kind: EventKind,
+    pub severity: Severity,
+    /// Details that depend on the kind
+    pub payload: Value,
+}
+
+impl SensorEvent {
+    /// An alarm raised or cleared. Losing mains is a warning while the
+    /// battery holds, a low battery means the load is about to drop.
+    pub fn alarm(event: &AlarmEvent) -> Self {
+        let (kind, severity) = match (event.raised, event.alarm) {
+            (true, AlarmKind::AcDown) => (EventKind::AlarmRaised, Severity::Warning),
+            (true, AlarmKind::BattLow) => (EventKind::AlarmRaised, Severity::Critical),
+            (false, _) => (EventKind::AlarmCleared, Severity::Info),
+        };
+        SensorEvent {
+            time: event.time,
+            kind,
+            severity,
+            payload: json!({ "alarm": event.alarm.name(), "value": event.value }),
+        }
+    }
+
+    /// The device stopped answering
+    pub fn comm_lost(time: DateTime<Utc>, error: &str) -> Self {
+        SensorEvent {
+            time,
+            kind: EventKind::CommLost,
+            severity: Severity::Warning,
+            payload: json!({ "error": error }),
+        }
+    }
+
+    /// The device answers again after [`SensorEvent::comm_lost`]
+    pub fn comm_restored(time: DateTime<Utc>) -> Self {
+        SensorEvent {
+            time,
+            kind: EventKind::CommRestored,
+            severity: Severity::Info,
+            payload: json!({}),
+        }
+    }
+
+    pub fn settings_changed(time: DateTime<Utc>, changes: &[SettingChange]) -> Self {
+        SensorEvent {
+            time,
+            kind: EventKind::SettingsChanged,
+            severity: Severity::Info,
+            payload: json!({ "changes": changes }),
+        }
+    }
+
+    pub fn daemon_started(time: DateTime<Utc>) -> Self {
+        SensorEvent {
+            time,
+            kind: EventKind::DaemonStarted,
+            severity: Severity::Info,
+            payload: json!({ "version": env!("CARGO_PKG_VERSION") }),
+        }
+    }
+}
+
+impl fmt::Display for SensorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<8} {:<16} {}",
+            self.time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            self.severity,
+            self.kind,
+            self.payload
+        )
+    }
+}
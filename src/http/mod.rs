@@ -0,0 +1,156 @@
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use axum::extract::{Path, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+
+use crate::alarm::AlarmKind;
+use crate::bus::{BusHandle, Snapshot};
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+#[derive(Clone)]
+struct ApiState {
+    snapshot: Arc<RwLock<Snapshot>>,
+    metrics: Arc<Metrics>,
+    config: Arc<Config>,
+}
+
+/// Read only JSON API over the daemon snapshot, plus Prometheus `/metrics`
+pub fn router(snapshot: Arc<RwLock<Snapshot>>, metrics: Arc<Metrics>, config: &Config) -> Router {
+    let state = ApiState {
+        snapshot,
+        metrics,
+        config: Arc::new(config.redacted()),
+    };
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/meters", get(meters))
+        .route("/meters/avg", get(meters_avg))
+        .route("/settings", get(settings))
+        .route("/alarms", get(alarms))
+        .route("/config", get(config_handler))
+        .route("/health", get(health))
+        .with_state(state)
+}
+
+/// Requests that act on the device through the bus, each of which must
+/// carry `Authorization: Bearer <token>`
+pub fn control(bus: BusHandle, token: &str) -> Router {
+    let token: Arc<str> = Arc::from(token);
+    Router::new()
+        .route("/alarms/{alarm}/acknowledge", post(acknowledge))
+        .route_layer(middleware::from_fn_with_state(token, authorize))
+        .with_state(bus)
+}
+
+async fn authorize(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if same(given.as_bytes(), token.as_bytes()) => next.run(request).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(json!({ "error": "missing or wrong control token" })),
+        )
+            .into_response(),
+    }
+}
+
+/// Compare in time independent of where the inputs first differ
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub async fn serve(listener: TcpListener, router: Router) -> io::Result<()> {
+    axum::serve(listener, router).await
+}
+
+/// 200 with the value, or 503 when the daemon has not produced it yet
+fn respond<T: Serialize>(value: Option<T>, missing: &str) -> Response {
+    match value {
+        Some(value) => Json(value).into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": missing })),
+        )
+            .into_response(),
+    }
+}
+
+async fn metrics_handler(State(state): State<ApiState>) -> impl IntoResponse {
+    let snapshot = state.snapshot.read().unwrap().clone();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&snapshot),
+    )
+}
+
+async fn meters(State(state): State<ApiState>) -> Response {
+    let sample = state.snapshot.read().unwrap().sample.clone();
+    respond(sample, "no meters read yet")
+}
+
+async fn meters_avg(State(state): State<ApiState>) -> Response {
+    let window = state.snapshot.read().unwrap().window.clone();
+    respond(window, "no window completed yet")
+}
+
+async fn settings(State(state): State<ApiState>) -> Response {
+    let settings = state.snapshot.read().unwrap().settings.clone();
+    respond(settings, "no settings read yet")
+}
+
+async fn alarms(State(state): State<ApiState>) -> Response {
+    Json(state.snapshot.read().unwrap().alarms).into_response()
+}
+
+/// Acknowledge a latched alarm, answering with the clear it caused if any
+async fn acknowledge(State(bus): State<BusHandle>, Path(alarm): Path<String>) -> Response {
+    let Some(alarm) = AlarmKind::from_name(&alarm) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("no alarm named {alarm}") })),
+        )
+            .into_response();
+    };
+    match bus.acknowledge_alarm(alarm).await {
+        Ok(event) => Json(json!({ "cleared": event })).into_response(),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+async fn config_handler(State(state): State<ApiState>) -> Json<Config> {
+    Json(state.config.as_ref().clone())
+}
+
+/// 200 while the device is reachable, 503 otherwise
+async fn health(State(state): State<ApiState>) -> Response {
+    let snapshot = state.snapshot.read().unwrap();
+    let status = if snapshot.device_connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "device_connected": snapshot.device_connected,
+        "database_connected": snapshot.database_connected,
+        "last_sample": snapshot.sample.as_ref().map(|sample| sample.time),
+    });
+    (status, Json(body)).into_response()
+}
@@ -1,6 +1,18 @@
+pub mod alarm;
+pub mod bus;
 pub mod config;
+pub mod daemon;
 pub mod database;
+pub mod error;
+pub mod events;
+pub mod http;
+pub mod metrics;
+pub mod mqtt;
 pub mod nextys;
+pub mod nut;
+pub mod sim;
+pub mod snmp;
+pub mod spool;
 
 pub fn convert_to_signed(input: Vec<u16>) -> i16 {
     i16::from_be_bytes(input[0].to_be_bytes())
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::warn;
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::bus::{Event, Snapshot};
+use crate::config::Config;
+use crate::nextys::meters::Meters;
+use crate::nextys::settings::Settings;
+
+const NAMESPACE: &str = "nextys";
+
+/// Modbus round trips are tens of milliseconds on RTU, up to the timeout
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Gauge name, help text and how to read its value
+type Reading<T, V = f64> = (&'static str, &'static str, fn(&T) -> V);
+
+const METERS: [Reading<Meters>; 8] = [
+    ("input_voltage_volts", "Input voltage", |m| {
+        m.input_voltage as f64
+    }),
+    ("input_current_amperes", "Input current", |m| {
+        m.input_current as f64
+    }),
+    ("output_voltage_volts", "Output voltage", |m| {
+        m.output_voltage as f64
+    }),
+    ("output_current_amperes", "Output current", |m| {
+        m.output_current as f64
+    }),
+    ("battery_voltage_volts", "Battery voltage", |m| {
+        m.batt_voltage as f64
+    }),
+    (
+        "battery_current_amperes",
+        "Battery current, negative while discharging",
+        |m| m.batt_current as f64,
+    ),
+    ("battery_soc_percent", "Battery state of charge", |m| {
+        m.batt_soc as f64
+    }),
+    (
+        "battery_internal_resistance_milliohms",
+        "Battery internal resistance",
+        |m| m.batt_int_resistance as f64,
+    ),
+];
+
+/// Meters the device may not have, left out when absent
+const COUNTERS: [Reading<Meters, Option<f64>>; 3] = [
+    (
+        "battery_charge_capacity_amp_hours",
+        "Charge currently held by the battery",
+        |m| m.batt_charge_capacity.map(f64::from),
+    ),
+    (
+        "operating_time_hours",
+        "Hours the unit has been running",
+        |m| m.operating_time.map(f64::from),
+    ),
+    (
+        "battery_operating_time_hours",
+        "Hours the unit has run from the battery",
+        |m| m.batt_operating_time.map(f64::from),
+    ),
+];
+
+const SETTINGS: [Reading<Settings>; 7] = [
+    (
+        "setting_battery_type",
+        "Configured battery chemistry",
+        |s| s.batt_type_int as f64,
+    ),
+    (
+        "setting_charge_voltage_volts",
+        "Battery charge voltage",
+        |s| s.batt_charge_voltage as f64,
+    ),
+    (
+        "setting_float_voltage_volts",
+        "Battery float voltage",
+        |s| s.batt_float_voltage as f64,
+    ),
+    (
+        "setting_low_voltage_volts",
+        "Battery low voltage warning",
+        |s| s.batt_low_voltage as f64,
+    ),
+    (
+        "setting_deep_discharge_voltage_volts",
+        "Battery deep discharge cutoff",
+        |s| s.batt_deep_discharge_voltage as f64,
+    ),
+    (
+        "setting_battery_capacity_amp_hours",
+        "Battery capacity",
+        |s| s.batt_capacity as f64,
+    ),
+    (
+        "setting_nominal_output_voltage_volts",
+        "Nominal output voltage",
+        |s| s.nominal_output_voltage as f64,
+    ),
+];
+
+/// Prometheus metrics for one DCW20.
+///
+/// Counters accumulate from bus events, gauges are built from the snapshot
+/// on every scrape so values the daemon has not read yet are left out
+/// rather than reported as zero.
+pub struct Metrics {
+    labels: HashMap<String, String>,
+    registry: Registry,
+    errors: IntCounterVec,
+    latency: Histogram,
+}
+
+impl Metrics {
+    /// Metrics labelled with the system name, location and device id
+    pub fn new(config: &Config) -> Self {
+        let mut labels = HashMap::from([
+            ("sys_name".to_string(), config.sys_name.clone()),
+            ("location".to_string(), config.location.clone()),
+        ]);
+        if let Some(device_id) = config.device_id {
+            labels.insert("device_id".to_string(), device_id.to_string());
+        }
+        let registry = registry(&labels);
+        let errors = IntCounterVec::new(
+            Opts::new("read_errors_total", "Failed device requests by kind"),
+            &["kind"],
+        )
+        .expect("metric name should be valid");
+        registry
+            .register(Box::new(errors.clone()))
+            .expect("metric should only be registered once");
+        let latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "modbus_request_duration_seconds",
+                "Time taken by device requests",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )
+        .expect("metric name should be valid");
+        registry
+            .register(Box::new(latency.clone()))
+            .expect("metric should only be registered once");
+
+        Metrics {
+            labels,
+            registry,
+            errors,
+            latency,
+        }
+    }
+
+    /// Count device requests as they finish
+    pub fn observe(&self, event: &Event) {
+        if let Event::Request { duration, error } = event {
+            self.latency.observe(duration.as_secs_f64());
+            if let Some(kind) = error {
+                self.errors.with_label_values(&[*kind]).inc();
+            }
+        }
+    }
+
+    /// Observe bus events until the bus shuts down
+    pub async fn watch(self: Arc<Self>, mut events: broadcast::Receiver<Event>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => self.observe(&event),
+                Err(RecvError::Lagged(missed)) => warn!("Metrics missed {missed} events"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Text exposition of the counters and of the gauges in `snapshot`
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let gauges = registry(&self.labels);
+        gauge(
+            &gauges,
+            "up",
+            "Whether the device is answering",
+            snapshot.device_connected as u8 as f64,
+        );
+        if let Some(sample) = &snapshot.sample {
+            for (name, help, value) in METERS {
+                gauge(&gauges, name, help, value(&sample.meters));
+            }
+            for (name, help, value) in COUNTERS {
+                if let Some(value) = value(&sample.meters) {
+                    gauge(&gauges, name, help, value);
+                }
+            }
+            gauge(
+                &gauges,
+                "ac_down",
+                "Whether the ac_down alarm is raised",
+                snapshot.alarms.ac_down as u8 as f64,
+            );
+            gauge(
+                &gauges,
+                "batt_low",
+                "Whether the batt_low alarm is raised",
+                snapshot.alarms.batt_low as u8 as f64,
+            );
+        }
+        if let Some(settings) = &snapshot.settings {
+            for (name, help, value) in SETTINGS {
+                gauge(&gauges, name, help, value(settings));
+            }
+        }
+
+        let mut families = self.registry.gather();
+        families.extend(gauges.gather());
+        families.sort_by(|a, b| a.name().cmp(b.name()));
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&families, &mut buffer)
+            .expect("text encoding should not fail");
+        String::from_utf8(buffer).expect("metrics should be utf-8")
+    }
+}
+
+fn registry(labels: &HashMap<String, String>) -> Registry {
+    Registry::new_custom(Some(NAMESPACE.to_string()), Some(labels.clone()))
+        .expect("metric labels should be valid")
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str, value: f64) {
+    let gauge = Gauge::new(name, help).expect("metric name should be valid");
+    gauge.set(value);
+    registry
+        .register(Box::new(gauge))
+        .expect("metric should only be registered once");
+}
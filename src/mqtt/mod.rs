@@ -0,0 +1,363 @@
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{Value, json};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::alarm::Flags;
+use crate::bus::{BusHandle, Event};
+use crate::config::Config;
+use crate::nextys::meters::Meters;
+
+/// Requests queued for the broker before new messages are dropped
+const QUEUE_CAPACITY: usize = 64;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long shutdown waits for the offline message to be sent
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How Home Assistant should present a meter
+struct Sensor {
+    field: &'static str,
+    name: &'static str,
+    unit: &'static str,
+    device_class: Option<&'static str>,
+    state_class: &'static str,
+}
+
+const SENSORS: [Sensor; 11] = [
+    Sensor {
+        field: "input_voltage",
+        name: "Input voltage",
+        unit: "V",
+        device_class: Some("voltage"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "input_current",
+        name: "Input current",
+        unit: "A",
+        device_class: Some("current"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "output_voltage",
+        name: "Output voltage",
+        unit: "V",
+        device_class: Some("voltage"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "output_current",
+        name: "Output current",
+        unit: "A",
+        device_class: Some("current"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "batt_voltage",
+        name: "Battery voltage",
+        unit: "V",
+        device_class: Some("voltage"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "batt_current",
+        name: "Battery current",
+        unit: "A",
+        device_class: Some("current"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "batt_soc",
+        name: "Battery",
+        unit: "%",
+        device_class: Some("battery"),
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "batt_int_resistance",
+        name: "Battery internal resistance",
+        unit: "mΩ",
+        device_class: None,
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "batt_charge_capacity",
+        name: "Battery charge",
+        unit: "Ah",
+        device_class: None,
+        state_class: "measurement",
+    },
+    Sensor {
+        field: "operating_time",
+        name: "Operating time",
+        unit: "h",
+        device_class: Some("duration"),
+        state_class: "total_increasing",
+    },
+    Sensor {
+        field: "batt_operating_time",
+        name: "Battery operating time",
+        unit: "h",
+        device_class: Some("duration"),
+        state_class: "total_increasing",
+    },
+];
+
+/// Alarm flag, Home Assistant device class and display name
+const ALARMS: [(&str, &str, &str); 2] = [
+    ("ac_down", "problem", "Mains failure"),
+    ("batt_low", "battery", "Battery low"),
+];
+
+/// A message ready to publish
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl Message {
+    fn new(topic: String, payload: impl Into<String>, retain: bool) -> Self {
+        Message {
+            topic,
+            payload: payload.into(),
+            retain,
+        }
+    }
+}
+
+/// Topic layout for one DCW20
+pub struct Topics {
+    config: Config,
+    node_id: String,
+}
+
+impl Topics {
+    pub fn new(config: &Config) -> Self {
+        // Discovery ids and topic levels only allow a limited character set
+        let node_id = config
+            .sys_name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Topics {
+            config: config.clone(),
+            node_id,
+        }
+    }
+
+    /// Topic under this unit's base, ie `nextys/ups_1/meters`
+    pub fn topic(&self, path: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.config.mqtt.topic_prefix, self.node_id, path
+        )
+    }
+
+    pub fn availability(&self) -> String {
+        self.topic("availability")
+    }
+
+    /// Each meter on its own topic plus all of them as one JSON object.
+    /// Counters the device does not have are not published on their own.
+    pub fn meters(&self, meters: &Meters) -> Vec<Message> {
+        let json = serde_json::to_value(meters).expect("meters should serialize");
+        let mut messages: Vec<Message> = SENSORS
+            .iter()
+            .filter(|sensor| !json[sensor.field].is_null())
+            .map(|sensor| {
+                Message::new(
+                    self.topic(&format!("meters/{}", sensor.field)),
+                    json[sensor.field].to_string(),
+                    false,
+                )
+            })
+            .collect();
+        messages.push(Message::new(self.topic("meters"), json.to_string(), false));
+        messages
+    }
+
+    /// Alarm states, retained so new subscribers see the current state
+    pub fn alarms(&self, flags: Flags) -> Vec<Message> {
+        let json = serde_json::to_value(flags).expect("flags should serialize");
+        let mut messages: Vec<Message> = ALARMS
+            .iter()
+            .map(|(field, _, _)| {
+                let state = if json[field] == true { "ON" } else { "OFF" };
+                Message::new(self.topic(&format!("alarms/{field}")), state, true)
+            })
+            .collect();
+        messages.push(Message::new(self.topic("alarms"), json.to_string(), true));
+        messages
+    }
+
+    /// Home Assistant discovery config for every sensor and alarm
+    pub fn discovery(&self) -> Vec<Message> {
+        let prefix = &self.config.mqtt.discovery_prefix;
+        let device = json!({
+            "identifiers": [format!("nextys_{}", self.node_id)],
+            "name": self.config.sys_name,
+            "manufacturer": "Nextys",
+            "model": "DCW20",
+            "suggested_area": self.config.location,
+        });
+        let mut messages = Vec::new();
+        for sensor in &SENSORS {
+            let mut payload = json!({
+                "name": sensor.name,
+                "unique_id": format!("nextys_{}_{}", self.node_id, sensor.field),
+                "state_topic": self.topic(&format!("meters/{}", sensor.field)),
+                "availability_topic": self.availability(),
+                "unit_of_measurement": sensor.unit,
+                "state_class": sensor.state_class,
+                "device": device,
+            });
+            if let Some(device_class) = sensor.device_class {
+                payload["device_class"] = Value::from(device_class);
+            }
+            messages.push(Message::new(
+                format!("{prefix}/sensor/{}/{}/config", self.node_id, sensor.field),
+                payload.to_string(),
+                true,
+            ));
+        }
+        for (field, device_class, name) in ALARMS {
+            let payload = json!({
+                "name": name,
+                "unique_id": format!("nextys_{}_{field}", self.node_id),
+                "state_topic": self.topic(&format!("alarms/{field}")),
+                "availability_topic": self.availability(),
+                "device_class": device_class,
+                "device": device,
+            });
+            messages.push(Message::new(
+                format!("{prefix}/binary_sensor/{}/{field}/config", self.node_id),
+                payload.to_string(),
+                true,
+            ));
+        }
+        messages
+    }
+}
+
+/// Publish readings from the bus to the configured broker until the bus
+/// shuts down, then mark the unit offline
+pub async fn run(config: Config, bus: BusHandle) {
+    let Some(host) = &config.mqtt.host else {
+        return;
+    };
+    let topics = Topics::new(&config);
+    let mut options =
+        MqttOptions::new(format!("nextys-{}", topics.node_id), host, config.mqtt.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        topics.availability(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &config.mqtt.username {
+        let password = config.mqtt.password.clone().unwrap_or_default();
+        options.set_credentials(username, password);
+    }
+    let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+
+    // Discovery and availability are sent again on every reconnect
+    let mut birth = vec![Message::new(topics.availability(), "online", true)];
+    if config.mqtt.discovery {
+        birth.extend(topics.discovery());
+    }
+    let mut connection = tokio::spawn(poll(eventloop, client.clone(), birth));
+
+    let mut events = bus.subscribe();
+    let mut settings = None;
+    loop {
+        match events.recv().await {
+            Ok(Event::Window(record)) => {
+                let meters = record.meters.average();
+                let flags = record.alarms.unwrap_or_else(|| {
+                    Flags::new(&config, meters.input_voltage, meters.batt_voltage)
+                });
+                let mut messages = topics.meters(&meters);
+                messages.extend(topics.alarms(flags));
+                // Settings only change occasionally, republish when they do
+                let snapshot = bus.snapshot().read().unwrap().settings.clone();
+                if let Some(current) = snapshot.map(|s| json!(s).to_string())
+                    && settings.as_ref() != Some(&current)
+                {
+                    messages.push(Message::new(topics.topic("settings"), &current, true));
+                    settings = Some(current);
+                }
+                publish(&client, messages);
+            }
+            // Alarms go out as they change rather than with the next window
+            Ok(Event::Alarm(_)) => {
+                let flags = bus.snapshot().read().unwrap().alarms;
+                publish(&client, topics.alarms(flags));
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => warn!("MQTT publisher missed {missed} events"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+    publish(
+        &client,
+        vec![Message::new(topics.availability(), "offline", true)],
+    );
+    // The offline message only goes out if the broker is reachable
+    let _ = client.try_disconnect();
+    if time::timeout(DISCONNECT_TIMEOUT, &mut connection)
+        .await
+        .is_err()
+    {
+        debug!("MQTT broker unreachable at shutdown");
+        connection.abort();
+    }
+}
+
+fn publish(client: &AsyncClient, messages: Vec<Message>) {
+    for message in messages {
+        // A full queue means the broker is unreachable, drop rather than stall
+        if let Err(e) = client.try_publish(
+            message.topic,
+            QoS::AtLeastOnce,
+            message.retain,
+            message.payload,
+        ) {
+            debug!("Dropped MQTT message: {e}");
+        }
+    }
+}
+
+/// Drive the connection, which reconnects on the next poll after an error
+async fn poll(mut eventloop: EventLoop, client: AsyncClient, birth: Vec<Message>) {
+    let mut reported = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                reported = false;
+                publish(&client, birth.clone());
+            }
+            Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                if !reported {
+                    warn!("MQTT broker unavailable, retrying: {e}");
+                    reported = true;
+                }
+                time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
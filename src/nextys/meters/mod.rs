@@ -1,4 +1,7 @@
-#[derive(Debug, Clone)]
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Meters {
     pub input_voltage: f32,
     pub input_current: f32,
@@ -8,43 +11,101 @@ pub struct Meters {
     pub batt_current: f32,
     pub batt_soc: f32,
     pub batt_int_resistance: f32,
+    /// Absent when the device does not answer for the counter registers
+    pub batt_charge_capacity: Option<f32>,
+    pub operating_time: Option<i32>,
+    pub batt_operating_time: Option<i32>,
 }
 
-impl Meters {
-    pub fn average(values: Vec<Meters>) -> Self {
-        let len = values.len() as f32;
-
-        let mut sum = Meters {
-            input_voltage: 0.0,
-            input_current: 0.0,
-            output_voltage: 0.0,
-            output_current: 0.0,
-            batt_voltage: 0.0,
-            batt_current: 0.0,
-            batt_soc: 0.0,
-            batt_int_resistance: 0.0,
-        };
+/// Minimum, mean and maximum of one channel over a window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stat {
+    pub min: f32,
+    pub avg: f32,
+    pub max: f32,
+}
 
-        for v in values {
-            sum.input_voltage += v.input_voltage;
-            sum.input_current += v.input_current;
-            sum.output_voltage += v.output_voltage;
-            sum.output_current += v.output_current;
-            sum.batt_voltage += v.batt_voltage;
-            sum.batt_current += v.batt_current;
-            sum.batt_soc += v.batt_soc;
-            sum.batt_int_resistance += v.batt_int_resistance;
+impl Stat {
+    fn from_values(values: impl Iterator<Item = f32>) -> Self {
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        let mut sum = 0.0;
+        let mut len = 0.0;
+        for value in values {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+            len += 1.0;
         }
+        Stat {
+            min,
+            avg: sum / len,
+            max,
+        }
+    }
+}
+
+/// Every channel of [`Meters`] aggregated over a window of samples
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetersAggregate {
+    pub input_voltage: Stat,
+    pub input_current: Stat,
+    pub output_voltage: Stat,
+    pub output_current: Stat,
+    pub batt_voltage: Stat,
+    pub batt_current: Stat,
+    pub batt_soc: Stat,
+    pub batt_int_resistance: Stat,
+    /// Over the samples that had it, absent when none did
+    pub batt_charge_capacity: Option<Stat>,
+    /// Last counter value read in the window
+    pub operating_time: Option<i32>,
+    /// Last counter value read in the window
+    pub batt_operating_time: Option<i32>,
+    pub samples: usize,
+}
+
+impl MetersAggregate {
+    /// Aggregate a window of samples, `None` if the window is empty
+    pub fn from_samples(samples: &[Meters]) -> Option<Self> {
+        samples.last()?;
+        let stat = |field: fn(&Meters) -> f32| Stat::from_values(samples.iter().map(field));
+        let last = |field: fn(&Meters) -> Option<i32>| samples.iter().rev().find_map(field);
+        let capacity: Vec<f32> = samples
+            .iter()
+            .filter_map(|m| m.batt_charge_capacity)
+            .collect();
+        Some(MetersAggregate {
+            input_voltage: stat(|m| m.input_voltage),
+            input_current: stat(|m| m.input_current),
+            output_voltage: stat(|m| m.output_voltage),
+            output_current: stat(|m| m.output_current),
+            batt_voltage: stat(|m| m.batt_voltage),
+            batt_current: stat(|m| m.batt_current),
+            batt_soc: stat(|m| m.batt_soc),
+            batt_int_resistance: stat(|m| m.batt_int_resistance),
+            batt_charge_capacity: (!capacity.is_empty())
+                .then(|| Stat::from_values(capacity.into_iter())),
+            operating_time: last(|m| m.operating_time),
+            batt_operating_time: last(|m| m.batt_operating_time),
+            samples: samples.len(),
+        })
+    }
 
+    /// Mean of every channel
+    pub fn average(&self) -> Meters {
         Meters {
-            input_voltage: sum.input_voltage / len,
-            input_current: sum.input_current / len,
-            output_voltage: sum.output_voltage / len,
-            output_current: sum.output_current / len,
-            batt_voltage: sum.batt_voltage / len,
-            batt_current: sum.batt_current / len,
-            batt_soc: sum.batt_soc / len,
-            batt_int_resistance: sum.batt_int_resistance / len,
+            input_voltage: self.input_voltage.avg,
+            input_current: self.input_current.avg,
+            output_voltage: self.output_voltage.avg,
+            output_current: self.output_current.avg,
+            batt_voltage: self.batt_voltage.avg,
+            batt_current: self.batt_current.avg,
+            batt_soc: self.batt_soc.avg,
+            batt_int_resistance: self.batt_int_resistance.avg,
+            batt_charge_capacity: self.batt_charge_capacity.map(|stat| stat.avg),
+            operating_time: self.operating_time,
+            batt_operating_time: self.batt_operating_time,
         }
     }
 }
@@ -1,173 +1,306 @@
 use std::time::Duration;
 
 use chrono::Utc;
-use log::error;
+use log::warn;
 use tokio::time;
-use tokio_modbus::client::{Context, rtu};
-use tokio_modbus::prelude::Reader;
-use tokio_modbus::slave::Slave;
-use tokio_serial::SerialStream;
+use tokio_modbus::ExceptionCode;
 pub mod meters;
+pub mod registers;
 pub mod settings;
-use crate::convert_to_signed;
-use crate::nextys::meters::Meters;
-use crate::nextys::settings::{BatteryType, Settings};
+pub mod transport;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::nextys::meters::{Meters, MetersAggregate};
+use crate::nextys::registers::{Group, Register, RegisterMap, Values};
+use crate::nextys::settings::{BatteryType, SettingChange, Settings};
+use crate::nextys::transport::{ModbusTransport, RegisterTransport};
 
-pub struct Nextys {
-    ctx: Context,
+pub struct Nextys<T = ModbusTransport> {
+    transport: T,
+    map: RegisterMap,
+    /// Set once the device refused the counters, so they are not asked for
+    /// on every sample
+    counters_refused: bool,
 }
+
 impl Nextys {
-    pub fn new() -> Self {
-        let builder = tokio_serial::new("/dev/ttyACM0", 19_200);
-        let port = SerialStream::open(&builder).expect("Error opening the serial port");
-        let slave = Slave(0x01);
-        let ctx = rtu::attach_slave(port, slave);
-        Nextys { ctx }
-    }
-
-    pub async fn get_address(&mut self, address: u16, count: u16) -> Vec<u16> {
-        match self.ctx.read_holding_registers(address, count).await {
-            Ok(data) => match data {
-                Ok(data) => data,
-                Err(e) => {
-                    error!("failed to read address {address}: {:?}", e);
-                    vec![0]
-                }
-            },
-            Err(e) => {
-                error!("Failed to read address {address}: {:?}", e);
-                vec![0]
-            }
+    /// Connect to the device using the transport selected in the config
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let map = match &config.register_map {
+            Some(path) => RegisterMap::load(path)?,
+            None => RegisterMap::dcw20(),
+        };
+        let transport = ModbusTransport::connect(config).await?;
+        Ok(Nextys::new(transport, map))
+    }
+}
+
+impl<T: RegisterTransport> Nextys<T> {
+    pub fn new(transport: T, map: RegisterMap) -> Self {
+        Nextys {
+            transport,
+            map,
+            counters_refused: false,
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub async fn get_address(&mut self, address: u16, count: u16) -> Result<Vec<u16>> {
+        let data = self
+            .transport
+            .read_holding_registers(address, count)
+            .await?;
+        if data.len() < count as usize {
+            return Err(Error::ShortRead {
+                address,
+                expected: count,
+                received: data.len(),
+            });
         }
+        Ok(data)
+    }
+
+    /// Write raw words starting at `address`
+    pub async fn set_address(&mut self, address: u16, words: &[u16]) -> Result<()> {
+        self.transport.write_registers(address, words).await
     }
 
-    pub async fn get_avg_meters(&mut self) -> Meters {
+    /// Sample the meters once a second for ten seconds
+    pub async fn get_avg_meters(&mut self) -> Result<MetersAggregate> {
         let mut meters: Vec<Meters> = Vec::new();
         let now = Utc::now().timestamp();
 
         while Utc::now().timestamp() < now + 10 {
-            meters.push(self.get_meters().await);
+            meters.push(self.get_meters().await?);
             time::sleep(Duration::from_secs(1)).await;
         }
-        Meters::average(meters)
-    }
-
-    pub async fn get_meters(&mut self) -> Meters {
-        let input_voltage = self.get_input_voltage().await;
-        let input_current = self.get_input_current().await;
-        let output_voltage = self.get_output_voltage().await;
-        let output_current = self.get_output_current().await;
-        let batt_voltage = self.get_batt_voltage().await;
-        let batt_current = self.get_batt_current().await;
-        let batt_soc = self.get_batt_soc().await;
-        let batt_int_resistance = self.get_batt_int_resistance().await;
-        Meters {
-            input_voltage,
-            input_current,
-            output_voltage,
-            output_current,
-            batt_voltage,
-            batt_current,
-            batt_soc,
-            batt_int_resistance,
-        }
+        Ok(MetersAggregate::from_samples(&meters).expect("window should hold at least one sample"))
     }
 
-    pub async fn get_settings(&mut self) -> Settings {
-        let batt_type = self.get_batt_type().await;
-        let batt_type_int = self.get_batt_type_int().await;
-        let batt_charge_voltage = self.get_batt_charge_voltage().await;
-        let batt_charge_current = self.get_batt_charge_current().await;
-        let batt_float_voltage = self.get_batt_float_voltage().await;
-        let batt_low_voltage = self.get_batt_low_voltage().await;
-        let batt_deep_discharge_voltage = self.get_batt_deep_discharge_voltage().await;
-        let batt_max_discharge_current = self.get_batt_max_discharge_current().await;
-        let batt_capacity = self.get_batt_capacity().await;
-        let nominal_output_voltage = self.get_nominal_output_voltage().await;
-        let max_input_current = self.get_max_input_current().await;
-        let max_output_current = self.get_max_output_current().await;
-        Settings {
-            batt_type,
-            batt_type_int,
-            batt_charge_voltage,
-            batt_charge_current,
-            batt_float_voltage,
-            batt_low_voltage,
-            batt_deep_discharge_voltage,
-            batt_max_discharge_current,
-            batt_capacity,
-            nominal_output_voltage,
-            max_input_current,
-            max_output_current,
-        }
+    /// Read a contiguous register block in one request
+    pub async fn get_block(&mut self, (start, count): (u16, u16)) -> Result<Block> {
+        let data = self.get_address(start, count).await?;
+        Ok(Block { start, data })
     }
 
-    // Settings
-    async fn get_batt_type(&mut self) -> BatteryType {
-        match self.get_address(0x1010, 1).await[0] {
-            1 => BatteryType::Lead,
-            2 => BatteryType::Nickel,
-            3 => BatteryType::Lithium,
-            4 => BatteryType::Supercapacitor,
-            _ => BatteryType::Unknown,
+    pub fn register_map(&self) -> &RegisterMap {
+        &self.map
+    }
+
+    /// Read and decode every register in `group` from the register map
+    pub async fn read_group(&mut self, group: Group) -> Result<Values> {
+        let mut blocks = Vec::new();
+        for span in self.map.blocks(group) {
+            blocks.push(self.get_block(span).await?);
+        }
+        let mut values = Values::default();
+        for register in self.map.group(group) {
+            let block = blocks
+                .iter()
+                .find(|b| b.contains(register))
+                .expect("register map blocks should cover every register");
+            values.0.insert(
+                register.name.clone(),
+                register.decode(block.words(register.address, register.count())),
+            );
         }
+        Ok(values)
+    }
+
+    /// Read and decode a single register by name
+    pub async fn read_register(&mut self, name: &str) -> Result<f64> {
+        let register = self.register(name)?;
+        let data = self.get_address(register.address, register.count()).await?;
+        Ok(register.decode(&data))
+    }
+
+    /// Write a single register by name and confirm it by reading it back
+    pub async fn write_register(&mut self, name: &str, value: f64) -> Result<()> {
+        let register = self.register(name)?;
+        let words = register.encode(value)?;
+        self.set_address(register.address, &words).await?;
+        self.verify(&register, &words).await
     }
-    async fn get_batt_type_int(&mut self) -> i16 {
-        self.get_address(0x1010, 1).await[0] as i16
+
+    fn register(&self, name: &str) -> Result<Register> {
+        self.map
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownRegister(name.to_string()))
     }
-    async fn get_batt_charge_voltage(&mut self) -> f32 {
-        self.get_address(0x1011, 1).await[0] as f32 / 10.0
+
+    async fn verify(&mut self, register: &Register, words: &[u16]) -> Result<()> {
+        let actual = self.get_address(register.address, register.count()).await?;
+        if actual[..words.len()] != *words {
+            return Err(Error::VerifyFailed {
+                name: register.name.clone(),
+                expected: register.decode(words),
+                actual: register.decode(&actual),
+            });
+        }
+        Ok(())
     }
-    async fn get_batt_charge_current(&mut self) -> f32 {
-        self.get_address(0x1012, 1).await[0] as f32 / 10.0
+
+    pub async fn get_meters(&mut self) -> Result<Meters> {
+        let values = self.read_group(Group::Meters).await?;
+        let counters = self.read_counters().await;
+        Ok(Meters {
+            input_voltage: values.get("input_voltage")? as f32,
+            input_current: values.get("input_current")? as f32,
+            output_voltage: values.get("output_voltage")? as f32,
+            output_current: values.get("output_current")? as f32,
+            batt_voltage: values.get("batt_voltage")? as f32,
+            batt_current: values.get("batt_current")? as f32,
+            batt_soc: values.get("batt_soc")? as f32,
+            batt_int_resistance: values.get("batt_int_resistance")? as f32,
+            batt_charge_capacity: counters.get("batt_charge_capacity").ok().map(|v| v as f32),
+            operating_time: counters.get("operating_time").ok().map(|v| v as i32),
+            batt_operating_time: counters.get("batt_operating_time").ok().map(|v| v as i32),
+        })
     }
-    async fn get_batt_float_voltage(&mut self) -> f32 {
-        self.get_address(0x1013, 1).await[0] as f32 / 10.0
+
+    /// The counters group, empty when the device does not answer for it so
+    /// a unit without them still reports its meters
+    async fn read_counters(&mut self) -> Values {
+        if self.counters_refused {
+            return Values::default();
+        }
+        match self.read_group(Group::Counters).await {
+            Ok(values) => values,
+            Err(
+                e @ Error::Exception {
+                    code: ExceptionCode::IllegalDataAddress | ExceptionCode::IllegalFunction,
+                    ..
+                },
+            ) => {
+                warn!("Device does not have the counter registers, no longer reading them: {e}");
+                self.counters_refused = true;
+                Values::default()
+            }
+            Err(e) => {
+                warn!("Failed to read the counter registers: {e}");
+                Values::default()
+            }
+        }
     }
-    async fn get_batt_low_voltage(&mut self) -> f32 {
-        self.get_address(0x1014, 1).await[0] as f32 / 10.0
+
+    pub async fn get_settings(&mut self) -> Result<Settings> {
+        let values = self.read_group(Group::Settings).await?;
+        let batt_type_int = values.get("batt_type")? as i16;
+        Ok(Settings {
+            batt_type: BatteryType::from(batt_type_int),
+            batt_type_int,
+            batt_charge_voltage: values.get("batt_charge_voltage")? as f32,
+            batt_charge_current: values.get("batt_charge_current")? as f32,
+            batt_float_voltage: values.get("batt_float_voltage")? as f32,
+            batt_low_voltage: values.get("batt_low_voltage")? as f32,
+            batt_deep_discharge_voltage: values.get("batt_deep_discharge_voltage")? as f32,
+            batt_max_discharge_current: values.get("batt_max_discharge_current")? as f32,
+            batt_capacity: values.get("batt_capacity")? as f32,
+            nominal_output_voltage: values.get("nominal_output_voltage")? as f32,
+            max_input_current: values.get("max_input_current")? as f32,
+            max_output_current: values.get("max_output_current")? as f32,
+        })
     }
-    async fn get_batt_deep_discharge_voltage(&mut self) -> f32 {
-        self.get_address(0x1015, 1).await[0] as f32 / 10.0
+
+    /// Write every setting that differs from the device, returning what changed.
+    ///
+    /// All values are checked against the register map before anything is
+    /// written, then contiguous registers are written together and each one
+    /// is read back to confirm it.
+    pub async fn apply_settings(&mut self, settings: &Settings) -> Result<Vec<SettingChange>> {
+        let current = self.get_settings().await?;
+        let changes = current.diff(settings);
+        let mut writes = Vec::new();
+        for change in &changes {
+            let register = self.register(change.name)?;
+            let words = register.encode(change.new as f64)?;
+            writes.push((register, words));
+        }
+        writes.sort_by_key(|(register, _)| register.address);
+
+        let mut runs: Vec<(u16, Vec<u16>)> = Vec::new();
+        for (register, words) in &writes {
+            match runs.last_mut() {
+                Some((start, data)) if *start + data.len() as u16 == register.address => {
+                    data.extend(words)
+                }
+                _ => runs.push((register.address, words.clone())),
+            }
+        }
+        for (start, data) in runs {
+            self.set_address(start, &data).await?;
+        }
+        for (register, words) in &writes {
+            self.verify(register, words).await?;
+        }
+        Ok(changes)
     }
-    async fn get_batt_max_discharge_current(&mut self) -> f32 {
-        self.get_address(0x1016, 1).await[0] as f32 / 10.0
+
+    pub async fn set_batt_type(&mut self, batt_type: i16) -> Result<()> {
+        self.write_register("batt_type", batt_type as f64).await
     }
-    async fn get_batt_capacity(&mut self) -> f32 {
-        self.get_address(0x1017, 1).await[0] as f32 / 10.0
+    pub async fn set_batt_charge_voltage(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_charge_voltage", value as f64)
+            .await
     }
-    async fn get_nominal_output_voltage(&mut self) -> f32 {
-        self.get_address(0x1021, 1).await[0] as f32 / 10.0
+    pub async fn set_batt_charge_current(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_charge_current", value as f64)
+            .await
     }
-    async fn get_max_input_current(&mut self) -> f32 {
-        self.get_address(0x1022, 1).await[0] as f32 / 10.0
+    pub async fn set_batt_float_voltage(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_float_voltage", value as f64)
+            .await
     }
-    async fn get_max_output_current(&mut self) -> f32 {
-        self.get_address(0x1023, 1).await[0] as f32 / 10.0
+    pub async fn set_batt_low_voltage(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_low_voltage", value as f64).await
     }
-    // Metering
-    async fn get_input_voltage(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2000, 1).await) as f32 / 10.0
+    pub async fn set_batt_deep_discharge_voltage(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_deep_discharge_voltage", value as f64)
+            .await
     }
-    async fn get_input_current(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2001, 1).await) as f32 / 10.0
+    pub async fn set_batt_max_discharge_current(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_max_discharge_current", value as f64)
+            .await
     }
-    async fn get_output_voltage(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2002, 1).await) as f32 / 10.0
+    pub async fn set_batt_capacity(&mut self, value: f32) -> Result<()> {
+        self.write_register("batt_capacity", value as f64).await
     }
-    async fn get_output_current(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2003, 1).await) as f32 / 10.0
+    pub async fn set_nominal_output_voltage(&mut self, value: f32) -> Result<()> {
+        self.write_register("nominal_output_voltage", value as f64)
+            .await
     }
-    async fn get_batt_voltage(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2004, 1).await) as f32 / 10.0
+    pub async fn set_max_input_current(&mut self, value: f32) -> Result<()> {
+        self.write_register("max_input_current", value as f64).await
     }
-    async fn get_batt_current(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2005, 1).await) as f32 / 10.0
+    pub async fn set_max_output_current(&mut self, value: f32) -> Result<()> {
+        self.write_register("max_output_current", value as f64)
+            .await
     }
-    async fn get_batt_soc(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x200A, 1).await) as f32 / 10.0
+}
+
+/// Registers returned by a single block read
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: u16,
+    pub data: Vec<u16>,
+}
+
+impl Block {
+    pub fn contains(&self, register: &Register) -> bool {
+        register.address >= self.start
+            && register.end() <= self.start as u32 + self.data.len() as u32
     }
-    async fn get_batt_int_resistance(&mut self) -> f32 {
-        convert_to_signed(self.get_address(0x2009, 1).await) as f32 / 10.0
+
+    pub fn words(&self, address: u16, count: u16) -> &[u16] {
+        let offset = (address - self.start) as usize;
+        &self.data[offset..offset + count as usize]
     }
 }
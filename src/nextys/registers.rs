@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// Register map shipped with the crate
+const DCW20_MAP: &str = include_str!("dcw20.toml");
+/// Registers further apart than this are read in separate requests
+const MAX_BLOCK_GAP: u16 = 3;
+/// Modbus limit on registers in a single read
+const MAX_BLOCK_LEN: u16 = 125;
+
+/// Addresses `start..start + count`, ending at 0xffff instead of overflowing
+pub fn addresses(start: u16, count: u16) -> impl Iterator<Item = u16> {
+    (start as u32..start as u32 + count as u32).map_while(|address| u16::try_from(address).ok())
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RegisterMap {
+    #[serde(rename = "register")]
+    pub registers: Vec<Register>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Register {
+    pub name: String,
+    pub address: u16,
+    /// Word count, defaults to the size of `data_type`
+    pub count: Option<u16>,
+    pub data_type: DataType,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub unit: String,
+    #[serde(default)]
+    pub access: Access,
+    /// Lowest value accepted for a write
+    pub min: Option<f64>,
+    /// Highest value accepted for a write
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub description: String,
+    pub group: Group,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    U16,
+    I16,
+    /// Two words, high word first
+    U32,
+    /// Two words, high word first
+    I32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    #[default]
+    R,
+    Rw,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Group {
+    Meters,
+    Settings,
+    /// Optional readings, read after the meters and skipped when refused
+    Counters,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl DataType {
+    pub fn words(&self) -> u16 {
+        match self {
+            DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 => 2,
+        }
+    }
+}
+
+impl Register {
+    pub fn count(&self) -> u16 {
+        self.count.unwrap_or(self.data_type.words())
+    }
+
+    /// Address just past the last word, widened as it may be 0x10000
+    pub fn end(&self) -> u32 {
+        self.address as u32 + self.count() as u32
+    }
+
+    /// Scaled value of the register from its raw words
+    pub fn decode(&self, words: &[u16]) -> f64 {
+        let raw = match self.data_type {
+            DataType::U16 => words[0] as f64,
+            DataType::I16 => words[0] as i16 as f64,
+            DataType::U32 => ((words[0] as u32) << 16 | words[1] as u32) as f64,
+            DataType::I32 => ((words[0] as u32) << 16 | words[1] as u32) as i32 as f64,
+        };
+        raw * self.scale
+    }
+
+    /// Raw words for `value`, saturating at the limits of the data type
+    pub fn raw_words(&self, value: f64) -> Vec<u16> {
+        let raw = (value / self.scale).round();
+        match self.data_type {
+            DataType::U16 => vec![raw as u16],
+            DataType::I16 => vec![raw as i16 as u16],
+            DataType::U32 => {
+                let raw = raw as u32;
+                vec![(raw >> 16) as u16, raw as u16]
+            }
+            DataType::I32 => {
+                let raw = raw as i32 as u32;
+                vec![(raw >> 16) as u16, raw as u16]
+            }
+        }
+    }
+
+    /// Raw words to write for `value`, checking access and range limits
+    pub fn encode(&self, value: f64) -> Result<Vec<u16>> {
+        if self.access != Access::Rw {
+            return Err(Error::ReadOnly(self.name.clone()));
+        }
+        let min = self.min.unwrap_or(f64::MIN);
+        let max = self.max.unwrap_or(f64::MAX);
+        let raw = (value / self.scale).round();
+        let (type_min, type_max) = match self.data_type {
+            DataType::U16 => (0.0, u16::MAX as f64),
+            DataType::I16 => (i16::MIN as f64, i16::MAX as f64),
+            DataType::U32 => (0.0, u32::MAX as f64),
+            DataType::I32 => (i32::MIN as f64, i32::MAX as f64),
+        };
+        if !(min..=max).contains(&value) || !(type_min..=type_max).contains(&raw) {
+            return Err(Error::OutOfRange {
+                name: self.name.clone(),
+                value,
+                min: self.min,
+                max: self.max,
+            });
+        }
+        Ok(self.raw_words(value))
+    }
+}
+
+impl RegisterMap {
+    /// The built in DCW20 map
+    pub fn dcw20() -> Self {
+        toml::from_str(DCW20_MAP).expect("built in register map should parse")
+    }
+
+    /// The built in map with the registers in `path` added or replacing those of the same name
+    pub fn load(path: &str) -> Result<Self> {
+        let invalid = |reason: String| Error::RegisterMap {
+            path: path.to_string(),
+            reason,
+        };
+        let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let overrides: RegisterMap =
+            toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+        let mut map = RegisterMap::dcw20();
+        for register in overrides.registers {
+            if register.count() < register.data_type.words() {
+                return Err(invalid(format!(
+                    "register {} needs at least {} words for {:?}",
+                    register.name,
+                    register.data_type.words(),
+                    register.data_type
+                )));
+            }
+            if register.end() > 0x1_0000 {
+                return Err(invalid(format!(
+                    "register {} at {:#06x} runs past the last address 0xffff",
+                    register.name, register.address
+                )));
+            }
+            match map.registers.iter_mut().find(|r| r.name == register.name) {
+                Some(existing) => *existing = register,
+                None => map.registers.push(register),
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Register> {
+        self.registers.iter().find(|r| r.name == name)
+    }
+
+    /// Registers in `group` ordered by address
+    pub fn group(&self, group: Group) -> Vec<&Register> {
+        let mut registers: Vec<&Register> =
+            self.registers.iter().filter(|r| r.group == group).collect();
+        registers.sort_by_key(|r| r.address);
+        registers
+    }
+
+    /// Contiguous `(start, count)` spans covering every register in `group`
+    pub fn blocks(&self, group: Group) -> Vec<(u16, u16)> {
+        // Worked out in u32 so spans ending at 0xffff do not overflow
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+        for register in self.group(group) {
+            let address = register.address as u32;
+            let end = register.end();
+            match blocks.last_mut() {
+                Some((start, count))
+                    if address <= *start + *count + MAX_BLOCK_GAP as u32
+                        && end - *start <= MAX_BLOCK_LEN as u32 =>
+                {
+                    *count = (*count).max(end - *start);
+                }
+                _ => blocks.push((address, register.count() as u32)),
+            }
+        }
+        blocks
+            .into_iter()
+            .map(|(start, count)| (start as u16, count as u16))
+            .collect()
+    }
+}
+
+/// Decoded register values by name
+#[derive(Debug, Clone, Default)]
+pub struct Values(pub BTreeMap<String, f64>);
+
+impl Values {
+    pub fn get(&self, name: &str) -> Result<f64> {
+        self.0
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::UnknownRegister(name.to_string()))
+    }
+}
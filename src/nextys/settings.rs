@@ -1,4 +1,8 @@
-#[derive(Debug)]
+use std::fmt;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Settings {
     pub batt_type: BatteryType,
     pub batt_type_int: i16,
@@ -14,7 +18,8 @@ pub struct Settings {
     pub max_output_current: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum BatteryType {
     Lead,
     Nickel,
@@ -22,3 +27,64 @@ pub enum BatteryType {
     Supercapacitor,
     Unknown,
 }
+
+impl From<i16> for BatteryType {
+    fn from(value: i16) -> Self {
+        match value {
+            1 => BatteryType::Lead,
+            2 => BatteryType::Nickel,
+            3 => BatteryType::Lithium,
+            4 => BatteryType::Supercapacitor,
+            _ => BatteryType::Unknown,
+        }
+    }
+}
+
+/// A setting that differs between two snapshots, named as in the register map
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SettingChange {
+    pub name: &'static str,
+    pub old: f32,
+    pub new: f32,
+}
+
+impl fmt::Display for SettingChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.name, self.old, self.new)
+    }
+}
+
+impl Settings {
+    /// Writable values keyed by their register map name
+    pub fn values(&self) -> [(&'static str, f32); 11] {
+        [
+            ("batt_type", self.batt_type_int as f32),
+            ("batt_charge_voltage", self.batt_charge_voltage),
+            ("batt_charge_current", self.batt_charge_current),
+            ("batt_float_voltage", self.batt_float_voltage),
+            ("batt_low_voltage", self.batt_low_voltage),
+            (
+                "batt_deep_discharge_voltage",
+                self.batt_deep_discharge_voltage,
+            ),
+            (
+                "batt_max_discharge_current",
+                self.batt_max_discharge_current,
+            ),
+            ("batt_capacity", self.batt_capacity),
+            ("nominal_output_voltage", self.nominal_output_voltage),
+            ("max_input_current", self.max_input_current),
+            ("max_output_current", self.max_output_current),
+        ]
+    }
+
+    /// Settings that change going from `self` to `other`
+    pub fn diff(&self, other: &Settings) -> Vec<SettingChange> {
+        self.values()
+            .into_iter()
+            .zip(other.values())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((name, old), (_, new))| SettingChange { name, old, new })
+            .collect()
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_modbus::ExceptionCode;
+use tokio_modbus::client::{Context, rtu, tcp};
+use tokio_modbus::prelude::{Reader, Writer};
+use tokio_modbus::slave::Slave;
+use tokio_serial::{DataBits, SerialStream, StopBits};
+
+use crate::config::{Config, Parity, Transport};
+use crate::error::{Error, Result};
+use crate::nextys::registers::addresses;
+
+/// Raw holding register access underneath [`Nextys`](super::Nextys)
+pub trait RegisterTransport {
+    fn read_holding_registers(
+        &mut self,
+        address: u16,
+        count: u16,
+    ) -> impl Future<Output = Result<Vec<u16>>> + Send;
+
+    fn write_registers(
+        &mut self,
+        address: u16,
+        words: &[u16],
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A real device reached over Modbus RTU or TCP
+pub struct ModbusTransport {
+    ctx: Context,
+    timeout: Duration,
+}
+
+impl ModbusTransport {
+    /// Connect using the transport selected in the config
+    pub async fn connect(config: &Config) -> Result<Self> {
+        match config.transport {
+            Transport::Rtu => {
+                Ok(ModbusTransport::open_serial(config).map_err(std::io::Error::from)?)
+            }
+            Transport::Tcp | Transport::RtuOverTcp => {
+                Ok(ModbusTransport::connect_tcp(config).await?)
+            }
+        }
+    }
+
+    /// Open the serial port described by the `[serial]` section of the config
+    fn open_serial(config: &Config) -> tokio_serial::Result<Self> {
+        let serial = &config.serial;
+        let timeout = Duration::from_millis(serial.timeout_ms);
+        let data_bits = match serial.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            bits => {
+                return Err(invalid_serial_setting(format!(
+                    "unsupported data bits: {bits}"
+                )));
+            }
+        };
+        let stop_bits = match serial.stop_bits {
+            1 => StopBits::One,
+            2 => StopBits::Two,
+            bits => {
+                return Err(invalid_serial_setting(format!(
+                    "unsupported stop bits: {bits}"
+                )));
+            }
+        };
+        let parity = match serial.parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Even => tokio_serial::Parity::Even,
+            Parity::Odd => tokio_serial::Parity::Odd,
+        };
+        let builder = tokio_serial::new(serial.port.as_str(), serial.baud_rate)
+            .data_bits(data_bits)
+            .stop_bits(stop_bits)
+            .parity(parity)
+            .timeout(timeout);
+        let port = SerialStream::open(&builder)?;
+        let ctx = rtu::attach_slave(port, Slave(serial.slave_id));
+        Ok(ModbusTransport { ctx, timeout })
+    }
+
+    /// Connect to the Modbus gateway described by the `[tcp]` section of the config
+    async fn connect_tcp(config: &Config) -> std::io::Result<Self> {
+        let settings = &config.tcp;
+        let timeout = Duration::from_millis(settings.timeout_ms);
+        let stream = match time::timeout(
+            timeout,
+            TcpStream::connect((settings.host.as_str(), settings.port)),
+        )
+        .await
+        {
+            Ok(stream) => stream?,
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "timed out connecting to {}:{}",
+                        settings.host, settings.port
+                    ),
+                ));
+            }
+        };
+        let slave = Slave(settings.unit_id);
+        let ctx = match config.transport {
+            Transport::RtuOverTcp => rtu::attach_slave(stream, slave),
+            _ => tcp::attach_slave(stream, slave),
+        };
+        Ok(ModbusTransport { ctx, timeout })
+    }
+}
+
+impl RegisterTransport for ModbusTransport {
+    async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>> {
+        call(
+            address,
+            self.timeout,
+            self.ctx.read_holding_registers(address, count),
+        )
+        .await
+    }
+
+    /// One word uses write single register, more use write multiple registers
+    async fn write_registers(&mut self, address: u16, words: &[u16]) -> Result<()> {
+        match words {
+            [word] => {
+                call(
+                    address,
+                    self.timeout,
+                    self.ctx.write_single_register(address, *word),
+                )
+                .await
+            }
+            _ => {
+                call(
+                    address,
+                    self.timeout,
+                    self.ctx.write_multiple_registers(address, words),
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Await a Modbus request, mapping timeouts and exceptions onto [`Error`]
+async fn call<T>(
+    address: u16,
+    timeout: Duration,
+    request: impl Future<Output = tokio_modbus::Result<T>>,
+) -> Result<T> {
+    match time::timeout(timeout, request).await {
+        Ok(Ok(Ok(data))) => Ok(data),
+        Ok(Ok(Err(code))) => Err(Error::Exception { address, code }),
+        Ok(Err(source)) => Err(Error::Transport { address, source }),
+        Err(_) => Err(Error::Timeout { address, timeout }),
+    }
+}
+
+fn invalid_serial_setting(description: String) -> tokio_serial::Error {
+    tokio_serial::Error::new(tokio_serial::ErrorKind::InvalidInput, description)
+}
+
+/// Failure injected into a [`MemoryTransport`] register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The device answers with a Modbus exception
+    Exception(ExceptionCode),
+    /// The device never answers
+    Timeout,
+    /// The device answers with the registers before this one only
+    ShortRead,
+    /// The link drops
+    Disconnected,
+}
+
+/// An in memory register table standing in for a device
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    pub registers: BTreeMap<u16, u16>,
+    pub faults: BTreeMap<u16, Fault>,
+    /// Number of read requests served
+    pub reads: u32,
+    /// Number of write requests served
+    pub writes: u32,
+}
+
+impl MemoryTransport {
+    pub fn new(registers: impl IntoIterator<Item = (u16, u16)>) -> Self {
+        MemoryTransport {
+            registers: registers.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    fn fault(&self, address: u16, count: u16) -> Option<(u16, Fault)> {
+        self.faults
+            .range(address..)
+            .next()
+            .filter(|(at, _)| (**at as u32) < address as u32 + count as u32)
+            .map(|(address, fault)| (*address, *fault))
+    }
+}
+
+impl RegisterTransport for MemoryTransport {
+    async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>> {
+        self.reads += 1;
+        let count = match self.fault(address, count) {
+            Some((at, Fault::ShortRead)) => at - address,
+            Some((at, fault)) => return Err(fault_error(at, fault)),
+            None => count,
+        };
+        addresses(address, count)
+            .map(|address| {
+                self.registers
+                    .get(&address)
+                    .copied()
+                    .ok_or(Error::Exception {
+                        address,
+                        code: ExceptionCode::IllegalDataAddress,
+                    })
+            })
+            .collect()
+    }
+
+    async fn write_registers(&mut self, address: u16, words: &[u16]) -> Result<()> {
+        self.writes += 1;
+        if let Some((at, fault)) = self.fault(address, words.len() as u16)
+            && fault != Fault::ShortRead
+        {
+            return Err(fault_error(at, fault));
+        }
+        for (address, word) in addresses(address, words.len() as u16).zip(words) {
+            match self.registers.get_mut(&address) {
+                Some(register) => *register = *word,
+                None => {
+                    return Err(Error::Exception {
+                        address,
+                        code: ExceptionCode::IllegalDataAddress,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fault_error(address: u16, fault: Fault) -> Error {
+    match fault {
+        Fault::Exception(code) => Error::Exception { address, code },
+        Fault::Timeout => Error::Timeout {
+            address,
+            timeout: Duration::ZERO,
+        },
+        Fault::ShortRead => unreachable!("short reads are answered with partial data"),
+        Fault::Disconnected => Error::Transport {
+            address,
+            source: tokio_modbus::Error::Transport(std::io::ErrorKind::BrokenPipe.into()),
+        },
+    }
+}
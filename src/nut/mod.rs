@@ -0,0 +1,483 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::bus::Snapshot;
+use crate::config::Config;
+use crate::nextys::settings::BatteryType;
+
+/// Version of the network protocol spoken, as reported by `NETVER`
+const PROTOCOL_VERSION: &str = "1.3";
+/// Longest command line accepted, a client sending more is disconnected
+const MAX_LINE: usize = 1024;
+
+/// Descriptions returned by `GET DESC`
+const DESCRIPTIONS: [(&str, &str); 22] = [
+    ("battery.capacity", "Battery capacity (Ah)"),
+    ("battery.charge", "Battery charge (percent of full)"),
+    ("battery.current", "Battery current (A)"),
+    ("battery.type", "Battery chemistry"),
+    ("battery.voltage", "Battery voltage (V)"),
+    (
+        "battery.voltage.low",
+        "Battery voltage that signals low battery (V)",
+    ),
+    ("device.location", "Device physical location"),
+    ("device.mfr", "Device manufacturer"),
+    ("device.model", "Device model"),
+    ("device.type", "Device type"),
+    ("driver.name", "Driver name"),
+    ("driver.version", "Driver version"),
+    ("input.current", "Input current (A)"),
+    ("input.transfer.low", "Low voltage transfer point (V)"),
+    ("input.voltage", "Input voltage (V)"),
+    ("output.current", "Output current (A)"),
+    ("output.voltage", "Output voltage (V)"),
+    ("output.voltage.nominal", "Nominal output voltage (V)"),
+    ("ups.id", "UPS system identifier"),
+    ("ups.mfr", "UPS manufacturer"),
+    ("ups.model", "UPS model"),
+    ("ups.status", "UPS status"),
+];
+
+/// State shared by every client connection
+struct Server {
+    config: Config,
+    snapshot: Arc<RwLock<Snapshot>>,
+    /// Addresses of clients logged in to the UPS
+    clients: Mutex<Vec<SocketAddr>>,
+    /// Forced shutdown requested by a primary, kept until the daemon restarts
+    fsd: AtomicBool,
+}
+
+/// The unit's NUT variables, or `None` while the readings are stale.
+///
+/// `ups.status` is `OL` or `OB` from the `ac_down` alarm, with `LB` added
+/// by the `batt_low` alarm and `FSD` once a primary forces a shutdown.
+pub fn variables(
+    config: &Config,
+    snapshot: &Snapshot,
+    fsd: bool,
+) -> Option<Vec<(&'static str, String)>> {
+    let sample = snapshot
+        .sample
+        .as_ref()
+        .filter(|_| snapshot.device_connected)?;
+    let meters = &sample.meters;
+    let flags = snapshot.alarms;
+    let mut status = Vec::new();
+    if fsd {
+        status.push("FSD");
+    }
+    status.push(if flags.ac_down { "OB" } else { "OL" });
+    if flags.batt_low {
+        status.push("LB");
+    }
+
+    let mut variables = vec![
+        ("battery.charge", meters.batt_soc.to_string()),
+        ("battery.current", meters.batt_current.to_string()),
+        ("battery.voltage", meters.batt_voltage.to_string()),
+        ("battery.voltage.low", config.low_batt_threshold.to_string()),
+        ("device.location", config.location.clone()),
+        ("device.mfr", "Nextys".to_string()),
+        ("device.model", "DCW20".to_string()),
+        ("device.type", "ups".to_string()),
+        ("driver.name", "nextys_reader".to_string()),
+        ("driver.version", env!("CARGO_PKG_VERSION").to_string()),
+        ("input.current", meters.input_current.to_string()),
+        ("input.transfer.low", config.ac_down_threshold.to_string()),
+        ("input.voltage", meters.input_voltage.to_string()),
+        ("output.current", meters.output_current.to_string()),
+        ("output.voltage", meters.output_voltage.to_string()),
+        ("ups.id", config.sys_name.clone()),
+        ("ups.mfr", "Nextys".to_string()),
+        ("ups.model", "DCW20".to_string()),
+        ("ups.status", status.join(" ")),
+    ];
+    if let Some(settings) = &snapshot.settings {
+        variables.extend([
+            ("battery.capacity", settings.batt_capacity.to_string()),
+            ("battery.type", battery_type(settings.batt_type).to_string()),
+            (
+                "output.voltage.nominal",
+                settings.nominal_output_voltage.to_string(),
+            ),
+        ]);
+    }
+    variables.sort_by_key(|(name, _)| *name);
+    Some(variables)
+}
+
+/// Chemistry as NUT drivers usually name it
+fn battery_type(batt_type: BatteryType) -> &'static str {
+    match batt_type {
+        BatteryType::Lead => "PbAc",
+        BatteryType::Nickel => "NiMH",
+        BatteryType::Lithium => "Li-ion",
+        BatteryType::Supercapacitor => "Supercap",
+        BatteryType::Unknown => "unknown",
+    }
+}
+
+/// Answer NUT clients on `listener` from the daemon snapshot
+pub async fn serve(
+    listener: TcpListener,
+    snapshot: Arc<RwLock<Snapshot>>,
+    config: &Config,
+) -> io::Result<()> {
+    let server = Arc::new(Server {
+        config: config.clone(),
+        snapshot,
+        clients: Mutex::new(Vec::new()),
+        fsd: AtomicBool::new(false),
+    });
+    loop {
+        let (stream, address) = listener.accept().await?;
+        debug!("NUT client connected from {address}");
+        let server = server.clone();
+        tokio::spawn(async move {
+            let mut session = Session::new(server, address);
+            if let Err(e) = session.run(stream).await {
+                debug!("NUT client {address} failed: {e}");
+            }
+            session.logout();
+        });
+    }
+}
+
+/// One client connection
+struct Session {
+    server: Arc<Server>,
+    address: SocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+    logged_in: bool,
+    primary: bool,
+}
+
+impl Session {
+    fn new(server: Arc<Server>, address: SocketAddr) -> Self {
+        Session {
+            server,
+            address,
+            username: None,
+            password: None,
+            logged_in: false,
+            primary: false,
+        }
+    }
+
+    async fn run(&mut self, stream: TcpStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let limit = MAX_LINE as u64 + 1;
+            if (&mut reader)
+                .take(limit)
+                .read_until(b'\n', &mut buf)
+                .await?
+                == 0
+            {
+                break;
+            }
+            if buf.len() > MAX_LINE && !buf.ends_with(b"\n") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line longer than {MAX_LINE} bytes"),
+                ));
+            }
+            let line = std::str::from_utf8(&buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .trim_end_matches('\n')
+                .trim_end_matches('\r');
+            let words = match split(line) {
+                Some(words) if !words.is_empty() => words,
+                Some(_) => continue,
+                None => vec![],
+            };
+            let (reply, close) = self.handle(&words);
+            writer.write_all(reply.as_bytes()).await?;
+            if close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reply to one command line, and whether to close the connection after
+    fn handle(&mut self, words: &[String]) -> (String, bool) {
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let reply = match words.as_slice() {
+            ["VER"] => format!(
+                "nextys_reader {} - NUT network protocol {PROTOCOL_VERSION}\n",
+                env!("CARGO_PKG_VERSION")
+            ),
+            ["NETVER"] | ["PROTVER"] => format!("{PROTOCOL_VERSION}\n"),
+            ["HELP"] => "Commands: HELP VER GET LIST LOGIN LOGOUT USERNAME PASSWORD PRIMARY FSD\n"
+                .to_string(),
+            ["STARTTLS"] => err("FEATURE-NOT-CONFIGURED"),
+            ["USERNAME", username] => match self.username {
+                Some(_) => err("ALREADY-SET-USERNAME"),
+                None => {
+                    self.username = Some(username.to_string());
+                    "OK\n".to_string()
+                }
+            },
+            ["PASSWORD", password] => match self.password {
+                Some(_) => err("ALREADY-SET-PASSWORD"),
+                None => {
+                    self.password = Some(password.to_string());
+                    "OK\n".to_string()
+                }
+            },
+            ["LOGIN", ups] => self.login(ups),
+            ["LOGOUT"] => {
+                self.logout();
+                return ("OK Goodbye\n".to_string(), true);
+            }
+            ["PRIMARY", ups] => self.primary(ups, "PRIMARY"),
+            ["MASTER", ups] => self.primary(ups, "MASTER"),
+            ["FSD", ups] => self.fsd(ups),
+            ["LIST", "UPS"] => format!(
+                "BEGIN LIST UPS\nUPS {} {}\nEND LIST UPS\n",
+                self.server.config.nut.ups_name,
+                quote(&self.description())
+            ),
+            ["LIST", "VAR", ups] => self.list_var(ups),
+            ["LIST", kind @ ("RW" | "CMD" | "ENUM" | "RANGE"), ups, ..] => {
+                self.list_empty(kind, ups, &words[2..])
+            }
+            ["LIST", "CLIENT", ups] => self.list_client(ups),
+            ["GET", "VAR", ups, name] => self.get_var(ups, name),
+            ["GET", "UPSDESC", ups] => self.checked(ups, || {
+                format!("UPSDESC {ups} {}\n", quote(&self.description()))
+            }),
+            ["GET", "DESC", ups, name] => {
+                self.checked(ups, || match DESCRIPTIONS.iter().find(|(n, _)| n == name) {
+                    Some((_, desc)) => format!("DESC {ups} {name} {}\n", quote(desc)),
+                    None => err("VAR-NOT-SUPPORTED"),
+                })
+            }
+            ["GET", "TYPE", ups, name] => self.checked(ups, || match self.value(name) {
+                Ok(value) if value.parse::<f64>().is_ok() => {
+                    format!("TYPE {ups} {name} NUMBER\n")
+                }
+                Ok(_) => format!("TYPE {ups} {name} STRING:64\n"),
+                Err(e) => e,
+            }),
+            ["GET", "NUMLOGINS", ups] => self.checked(ups, || {
+                let logins = self.server.clients.lock().unwrap().len();
+                format!("NUMLOGINS {ups} {logins}\n")
+            }),
+            ["SET", "VAR", ..] => err("READONLY"),
+            ["INSTCMD", ..] => err("CMD-NOT-SUPPORTED"),
+            [] => err("INVALID-ARGUMENT"),
+            _ => err("UNKNOWN-COMMAND"),
+        };
+        (reply, false)
+    }
+
+    fn login(&mut self, ups: &str) -> String {
+        if self.logged_in {
+            return err("ALREADY-LOGGED-IN");
+        }
+        if let Some(reply) = self.authenticate(ups) {
+            return reply;
+        }
+        self.logged_in = true;
+        self.server.clients.lock().unwrap().push(self.address);
+        info!("NUT client {} logged in", self.address);
+        "OK\n".to_string()
+    }
+
+    /// Forget the login, when the client logs out or disconnects
+    fn logout(&mut self) {
+        if !self.logged_in {
+            return;
+        }
+        self.logged_in = false;
+        let mut clients = self.server.clients.lock().unwrap();
+        if let Some(index) = clients.iter().position(|a| *a == self.address) {
+            clients.remove(index);
+        }
+        info!("NUT client {} logged out", self.address);
+    }
+
+    /// `MASTER` is the name older clients use for `PRIMARY`
+    fn primary(&mut self, ups: &str, command: &str) -> String {
+        if let Some(reply) = self.authenticate(ups) {
+            return reply;
+        }
+        self.primary = true;
+        format!("OK {command}-GRANTED\n")
+    }
+
+    fn fsd(&mut self, ups: &str) -> String {
+        if !self.is_ups(ups) {
+            return err("UNKNOWN-UPS");
+        }
+        if !self.primary {
+            return err("ACCESS-DENIED");
+        }
+        warn!("NUT client {} forced a shutdown", self.address);
+        self.server.fsd.store(true, Ordering::Relaxed);
+        "OK FSD-SET\n".to_string()
+    }
+
+    /// The error to reply with when the credentials do not allow `ups`
+    fn authenticate(&self, ups: &str) -> Option<String> {
+        if !self.is_ups(ups) {
+            return Some(err("UNKNOWN-UPS"));
+        }
+        let Some(username) = &self.username else {
+            return Some(err("USERNAME-REQUIRED"));
+        };
+        let Some(password) = &self.password else {
+            return Some(err("PASSWORD-REQUIRED"));
+        };
+        let nut = &self.server.config.nut;
+        let allowed = nut.username.as_ref().is_none_or(|u| u == username)
+            && nut.password.as_ref().is_none_or(|p| p == password);
+        if !allowed {
+            warn!("NUT client {} sent wrong credentials", self.address);
+            return Some(err("ACCESS-DENIED"));
+        }
+        None
+    }
+
+    fn list_var(&self, ups: &str) -> String {
+        self.checked(ups, || {
+            let Some(variables) = self.variables() else {
+                return err("DATA-STALE");
+            };
+            let mut reply = format!("BEGIN LIST VAR {ups}\n");
+            for (name, value) in variables {
+                reply.push_str(&format!("VAR {ups} {name} {}\n", quote(&value)));
+            }
+            reply.push_str(&format!("END LIST VAR {ups}\n"));
+            reply
+        })
+    }
+
+    /// Writable variables and instant commands, of which there are none
+    fn list_empty(&self, kind: &str, ups: &str, args: &[&str]) -> String {
+        self.checked(ups, || {
+            let args = args.join(" ");
+            format!("BEGIN LIST {kind} {args}\nEND LIST {kind} {args}\n")
+        })
+    }
+
+    fn list_client(&self, ups: &str) -> String {
+        self.checked(ups, || {
+            let mut reply = format!("BEGIN LIST CLIENT {ups}\n");
+            for address in self.server.clients.lock().unwrap().iter() {
+                reply.push_str(&format!("CLIENT {ups} {}\n", address.ip()));
+            }
+            reply.push_str(&format!("END LIST CLIENT {ups}\n"));
+            reply
+        })
+    }
+
+    fn get_var(&self, ups: &str, name: &str) -> String {
+        self.checked(ups, || match self.value(name) {
+            Ok(value) => format!("VAR {ups} {name} {}\n", quote(&value)),
+            Err(e) => e,
+        })
+    }
+
+    /// The value of one variable, or the error to reply with
+    fn value(&self, name: &str) -> Result<String, String> {
+        if !DESCRIPTIONS.iter().any(|(n, _)| *n == name) {
+            return Err(err("VAR-NOT-SUPPORTED"));
+        }
+        let variables = self.variables().ok_or_else(|| err("DATA-STALE"))?;
+        variables
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+            // Settings have not been read yet
+            .ok_or_else(|| err("DATA-STALE"))
+    }
+
+    fn variables(&self) -> Option<Vec<(&'static str, String)>> {
+        let snapshot = self.server.snapshot.read().unwrap();
+        variables(
+            &self.server.config,
+            &snapshot,
+            self.server.fsd.load(Ordering::Relaxed),
+        )
+    }
+
+    fn description(&self) -> String {
+        let config = &self.server.config;
+        format!("{} at {}", config.sys_name, config.location)
+    }
+
+    fn is_ups(&self, ups: &str) -> bool {
+        ups == self.server.config.nut.ups_name
+    }
+
+    /// `reply` when `ups` is the name served, otherwise an error
+    fn checked(&self, ups: &str, reply: impl FnOnce() -> String) -> String {
+        if self.is_ups(ups) {
+            reply()
+        } else {
+            err("UNKNOWN-UPS")
+        }
+    }
+}
+
+fn err(code: &str) -> String {
+    format!("ERR {code}\n")
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Split a command line into words, honouring double quotes and backslash
+/// escapes, or `None` when a quote is left open
+fn split(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                word.push(chars.next()?);
+                in_word = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::future::{Ready, ready};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time;
+use tokio_modbus::server::Service;
+use tokio_modbus::{ExceptionCode, Request, Response, SlaveRequest};
+
+use crate::nextys::registers::{Access, Group, RegisterMap, addresses};
+
+/// Every nth request fails in the exceptions scenario
+const EXCEPTION_EVERY: u32 = 5;
+
+/// Scripted behaviour of the simulated DCW20
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Scenario {
+    /// Mains present, battery floating at full charge
+    Normal,
+    /// Mains drops after 30 seconds and returns 120 seconds later
+    MainsLoss,
+    /// Mains absent from the start, battery runs down to deep discharge
+    Discharge,
+    /// Mains absent with the battery just above the low voltage cutoff
+    LowBattery,
+    /// Normal readings but every fifth request answers with an exception
+    Exceptions,
+}
+
+/// Electrical state of the simulated unit
+#[derive(Debug, Clone)]
+struct State {
+    /// Seconds of simulated time since start
+    elapsed: f64,
+    /// Simulated seconds spent supplying the load from the battery
+    on_battery: f64,
+    batt_soc: f64,
+    load_current: f64,
+}
+
+/// A simulated DCW20 register bank driven by a [`Scenario`]
+#[derive(Clone)]
+pub struct Simulator {
+    map: RegisterMap,
+    registers: Arc<Mutex<BTreeMap<u16, u16>>>,
+    state: Arc<Mutex<State>>,
+    scenario: Scenario,
+    slave_id: u8,
+    requests: Arc<AtomicU32>,
+}
+
+impl Simulator {
+    pub fn new(map: RegisterMap, scenario: Scenario, slave_id: u8) -> Self {
+        // Every address inside a block is readable, gaps read as zero
+        let mut registers = BTreeMap::new();
+        for group in [Group::Meters, Group::Settings, Group::Counters] {
+            for (start, count) in map.blocks(group) {
+                for address in addresses(start, count) {
+                    registers.insert(address, 0);
+                }
+            }
+        }
+        let batt_soc = match scenario {
+            Scenario::LowBattery => 30.0,
+            _ => 100.0,
+        };
+        let simulator = Simulator {
+            map,
+            registers: Arc::new(Mutex::new(registers)),
+            state: Arc::new(Mutex::new(State {
+                elapsed: 0.0,
+                on_battery: 0.0,
+                batt_soc,
+                load_current: 2.0,
+            })),
+            scenario,
+            slave_id,
+            requests: Arc::new(AtomicU32::new(0)),
+        };
+        simulator.set("batt_type", 1.0);
+        simulator.set("batt_charge_voltage", 14.4);
+        simulator.set("batt_charge_current", 2.0);
+        simulator.set("batt_float_voltage", 13.6);
+        simulator.set("batt_low_voltage", 11.5);
+        simulator.set("batt_deep_discharge_voltage", 10.5);
+        simulator.set("batt_max_discharge_current", 10.0);
+        simulator.set("batt_capacity", 7.0);
+        simulator.set("nominal_output_voltage", 24.0);
+        simulator.set("max_input_current", 10.0);
+        simulator.set("max_output_current", 10.0);
+        simulator.set("batt_int_resistance", 25.0);
+        simulator.step(0.0);
+        simulator
+    }
+
+    /// Advance the scenario in real time, `speed` simulated seconds per second
+    pub async fn run(&self, speed: f64) {
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.step(speed);
+        }
+    }
+
+    /// Value of a register by name as the client would decode it
+    pub fn get(&self, name: &str) -> f64 {
+        let register = self.map.get(name).expect("register should be in the map");
+        let registers = self.registers.lock().unwrap();
+        let words: Vec<u16> = addresses(register.address, register.count())
+            .map(|address| registers.get(&address).copied().unwrap_or(0))
+            .collect();
+        register.decode(&words)
+    }
+
+    /// Store a register by name, registers missing from the map are ignored
+    pub fn set(&self, name: &str, value: f64) {
+        let Some(register) = self.map.get(name) else {
+            return;
+        };
+        let mut registers = self.registers.lock().unwrap();
+        for (address, word) in
+            addresses(register.address, register.count()).zip(register.raw_words(value))
+        {
+            registers.insert(address, word);
+        }
+    }
+
+    /// Advance the scenario by `seconds` and refresh the meter registers
+    pub fn step(&self, seconds: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += seconds;
+        let mains = match self.scenario {
+            Scenario::Normal | Scenario::Exceptions => true,
+            Scenario::MainsLoss => !(30.0..150.0).contains(&state.elapsed),
+            Scenario::Discharge | Scenario::LowBattery => false,
+        };
+
+        let float_voltage = self.get("batt_float_voltage");
+        let deep_discharge_voltage = self.get("batt_deep_discharge_voltage");
+        let capacity = self.get("batt_capacity").max(0.1);
+        let charge_current = self.get("batt_charge_current");
+        let output_voltage_nominal = self.get("nominal_output_voltage");
+        let int_resistance = self.get("batt_int_resistance") / 1000.0;
+
+        let batt_current = if mains {
+            if state.batt_soc < 100.0 {
+                charge_current
+            } else {
+                0.0
+            }
+        } else {
+            -state.load_current
+        };
+        state.batt_soc =
+            (state.batt_soc + batt_current * seconds / (capacity * 36.0)).clamp(0.0, 100.0);
+
+        // Open circuit voltage rises with charge, sagging under discharge current
+        let open_circuit = deep_discharge_voltage
+            + (float_voltage - deep_discharge_voltage) * (0.1 + 0.9 * state.batt_soc / 100.0);
+        let batt_voltage = open_circuit + batt_current * int_resistance;
+        let battery_dead =
+            !mains && (state.batt_soc <= 0.0 || batt_voltage <= deep_discharge_voltage);
+        if !mains && !battery_dead {
+            state.on_battery += seconds;
+        }
+
+        let (input_voltage, input_current) = if mains {
+            (
+                output_voltage_nominal,
+                state.load_current + batt_current.max(0.0) * float_voltage / output_voltage_nominal,
+            )
+        } else {
+            (0.0, 0.0)
+        };
+        let (output_voltage, output_current) = if battery_dead {
+            (0.0, 0.0)
+        } else if mains {
+            (output_voltage_nominal - 0.2, state.load_current)
+        } else {
+            (batt_voltage - 0.3, state.load_current)
+        };
+        let batt_current = if battery_dead { 0.0 } else { batt_current };
+        let batt_soc = state.batt_soc;
+        let operating_time = (state.elapsed / 3600.0).floor();
+        let batt_operating_time = (state.on_battery / 3600.0).floor();
+        drop(state);
+
+        self.set("input_voltage", input_voltage);
+        self.set("input_current", input_current);
+        self.set("output_voltage", output_voltage);
+        self.set("output_current", output_current);
+        self.set("batt_voltage", batt_voltage);
+        self.set("batt_current", batt_current);
+        self.set("batt_soc", batt_soc);
+        self.set("batt_charge_capacity", capacity * batt_soc / 100.0);
+        self.set("operating_time", operating_time);
+        self.set("batt_operating_time", batt_operating_time);
+    }
+
+    fn read(&self, address: u16, count: u16) -> Result<Vec<u16>, ExceptionCode> {
+        if address as u32 + count as u32 > 0x10000 {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        let registers = self.registers.lock().unwrap();
+        addresses(address, count)
+            .map(|address| registers.get(&address).copied())
+            .collect::<Option<Vec<u16>>>()
+            .ok_or(ExceptionCode::IllegalDataAddress)
+    }
+
+    fn write(&self, address: u16, words: &[u16]) -> Result<(), ExceptionCode> {
+        let end = address as u32 + words.len() as u32;
+        let writable = end <= 0x10000
+            && (address as u32..end).all(|address| {
+                self.map.registers.iter().any(|r| {
+                    r.access == Access::Rw && (r.address as u32..r.end()).contains(&address)
+                })
+            });
+        if !writable {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        let mut registers = self.registers.lock().unwrap();
+        for (address, word) in addresses(address, words.len() as u16).zip(words) {
+            registers.insert(address, *word);
+        }
+        Ok(())
+    }
+}
+
+impl Service for Simulator {
+    type Request = SlaveRequest<'static>;
+    type Response = Option<Response>;
+    type Exception = ExceptionCode;
+    type Future = Ready<Result<Self::Response, Self::Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if req.slave != self.slave_id {
+            return ready(Ok(None));
+        }
+        let count = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.scenario == Scenario::Exceptions && count.is_multiple_of(EXCEPTION_EVERY) {
+            return ready(Err(ExceptionCode::ServerDeviceBusy));
+        }
+        let response = match req.request {
+            Request::ReadHoldingRegisters(address, count) => self
+                .read(address, count)
+                .map(Response::ReadHoldingRegisters),
+            Request::WriteSingleRegister(address, word) => self
+                .write(address, &[word])
+                .map(|_| Response::WriteSingleRegister(address, word)),
+            Request::WriteMultipleRegisters(address, words) => self
+                .write(address, &words)
+                .map(|_| Response::WriteMultipleRegisters(address, words.len() as u16)),
+            _ => Err(ExceptionCode::IllegalFunction),
+        };
+        ready(response.map(Some))
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt;
+
+use thiserror::Error;
+
+pub const INTEGER: u8 = 0x02;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const OBJECT_IDENTIFIER: u8 = 0x06;
+pub const SEQUENCE: u8 = 0x30;
+pub const IP_ADDRESS: u8 = 0x40;
+pub const COUNTER32: u8 = 0x41;
+pub const GAUGE32: u8 = 0x42;
+pub const TIMETICKS: u8 = 0x43;
+pub const COUNTER64: u8 = 0x46;
+pub const NO_SUCH_OBJECT: u8 = 0x80;
+pub const NO_SUCH_INSTANCE: u8 = 0x81;
+pub const END_OF_MIB_VIEW: u8 = 0x82;
+
+pub const GET_REQUEST: u8 = 0xa0;
+pub const GET_NEXT_REQUEST: u8 = 0xa1;
+pub const RESPONSE: u8 = 0xa2;
+pub const SET_REQUEST: u8 = 0xa3;
+pub const GET_BULK_REQUEST: u8 = 0xa5;
+pub const TRAP: u8 = 0xa7;
+pub const REPORT: u8 = 0xa8;
+
+/// A message that could not be decoded. Only the BER SNMP needs is
+/// understood: definite lengths and the universal and SNMP application types.
+#[derive(Debug, Error)]
+#[error("malformed SNMP message")]
+pub struct Malformed;
+
+pub type Result<T> = std::result::Result<T, Malformed>;
+
+/// Object identifier, ordered the way GETNEXT walks the tree
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Oid(pub Vec<u32>);
+
+impl Oid {
+    pub fn child(&self, arcs: &[u32]) -> Oid {
+        Oid([self.0.as_slice(), arcs].concat())
+    }
+
+    pub fn starts_with(&self, prefix: &Oid) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+}
+
+impl fmt::Display for Oid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arcs: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", arcs.join("."))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i32),
+    OctetString(Vec<u8>),
+    Null,
+    Oid(Oid),
+    IpAddress([u8; 4]),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Counter64(u64),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+impl Value {
+    pub fn string(value: &str) -> Value {
+        Value::OctetString(value.as_bytes().to_vec())
+    }
+
+    /// SNMPv2-TC TruthValue
+    pub fn truth(value: bool) -> Value {
+        Value::Integer(if value { 1 } else { 2 })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Value::Integer(value) => integer(*value as i64),
+            Value::OctetString(value) => tlv(OCTET_STRING, value),
+            Value::Null => tlv(NULL, &[]),
+            Value::Oid(oid) => encode_oid(oid),
+            Value::IpAddress(address) => tlv(IP_ADDRESS, address),
+            Value::Counter32(value) => unsigned(COUNTER32, *value as u64),
+            Value::Gauge32(value) => unsigned(GAUGE32, *value as u64),
+            Value::TimeTicks(value) => unsigned(TIMETICKS, *value as u64),
+            Value::Counter64(value) => unsigned(COUNTER64, *value),
+            Value::NoSuchObject => tlv(NO_SUCH_OBJECT, &[]),
+            Value::NoSuchInstance => tlv(NO_SUCH_INSTANCE, &[]),
+            Value::EndOfMibView => tlv(END_OF_MIB_VIEW, &[]),
+        }
+    }
+
+    fn decode(tag: u8, content: &[u8]) -> Result<Value> {
+        Ok(match tag {
+            INTEGER => Value::Integer(decode_integer(content)?.try_into().map_err(|_| Malformed)?),
+            OCTET_STRING => Value::OctetString(content.to_vec()),
+            NULL => Value::Null,
+            OBJECT_IDENTIFIER => Value::Oid(decode_oid(content)?),
+            IP_ADDRESS => Value::IpAddress(content.try_into().map_err(|_| Malformed)?),
+            COUNTER32 => Value::Counter32(decode_unsigned(content)? as u32),
+            GAUGE32 => Value::Gauge32(decode_unsigned(content)? as u32),
+            TIMETICKS => Value::TimeTicks(decode_unsigned(content)? as u32),
+            COUNTER64 => Value::Counter64(decode_unsigned(content)?),
+            NO_SUCH_OBJECT => Value::NoSuchObject,
+            NO_SUCH_INSTANCE => Value::NoSuchInstance,
+            END_OF_MIB_VIEW => Value::EndOfMibView,
+            _ => return Err(Malformed),
+        })
+    }
+}
+
+/// A request, response or notification PDU
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pdu {
+    pub tag: u8,
+    pub request_id: i32,
+    /// Error status, or non-repeaters for GETBULK
+    pub error_status: i32,
+    /// Error index, or max-repetitions for GETBULK
+    pub error_index: i32,
+    pub varbinds: Vec<(Oid, Value)>,
+}
+
+impl Pdu {
+    pub fn encode(&self) -> Vec<u8> {
+        let varbinds: Vec<u8> = self
+            .varbinds
+            .iter()
+            .flat_map(|(oid, value)| varbind(oid, value))
+            .collect();
+        tlv(
+            self.tag,
+            &[
+                integer(self.request_id as i64),
+                integer(self.error_status as i64),
+                integer(self.error_index as i64),
+                tlv(SEQUENCE, &varbinds),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn decode(reader: &mut Reader) -> Result<Pdu> {
+        let (tag, mut content) = reader.element()?;
+        let request_id = content.integer()?;
+        let error_status = content.integer()?;
+        let error_index = content.integer()?;
+        let mut list = content.expect(SEQUENCE)?;
+        let mut varbinds = Vec::new();
+        while !list.is_empty() {
+            let mut varbind = list.expect(SEQUENCE)?;
+            let oid = varbind.oid()?;
+            let (tag, value) = varbind.element()?;
+            varbinds.push((oid, Value::decode(tag, value.remaining())?));
+        }
+        Ok(Pdu {
+            tag,
+            request_id: request_id.try_into().map_err(|_| Malformed)?,
+            error_status: error_status.try_into().map_err(|_| Malformed)?,
+            error_index: error_index.try_into().map_err(|_| Malformed)?,
+            varbinds,
+        })
+    }
+}
+
+/// Cursor over BER elements that remembers where it is in the message
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    /// Offset of `buf` in the whole message
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader {
+            buf,
+            pos: 0,
+            base: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Unread bytes
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Offset of the next unread byte in the whole message
+    pub fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    /// The next element's tag and a reader over its content
+    pub fn element(&mut self) -> Result<(u8, Reader<'a>)> {
+        let tag = *self.buf.get(self.pos).ok_or(Malformed)?;
+        let first = *self.buf.get(self.pos + 1).ok_or(Malformed)?;
+        let mut start = self.pos + 2;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let octets = (first & 0x7f) as usize;
+            if octets == 0 || octets > 4 {
+                return Err(Malformed);
+            }
+            let bytes = self.buf.get(start..start + octets).ok_or(Malformed)?;
+            start += octets;
+            bytes.iter().fold(0, |len, b| (len << 8) | *b as usize)
+        };
+        let end = start.checked_add(len).ok_or(Malformed)?;
+        let content = self.buf.get(start..end).ok_or(Malformed)?;
+        self.pos = end;
+        Ok((
+            tag,
+            Reader {
+                buf: content,
+                pos: 0,
+                base: self.base + start,
+            },
+        ))
+    }
+
+    pub fn expect(&mut self, tag: u8) -> Result<Reader<'a>> {
+        match self.element()? {
+            (found, content) if found == tag => Ok(content),
+            _ => Err(Malformed),
+        }
+    }
+
+    pub fn integer(&mut self) -> Result<i64> {
+        decode_integer(self.expect(INTEGER)?.remaining())
+    }
+
+    pub fn octets(&mut self) -> Result<&'a [u8]> {
+        Ok(self.expect(OCTET_STRING)?.remaining())
+    }
+
+    pub fn oid(&mut self) -> Result<Oid> {
+        decode_oid(self.expect(OBJECT_IDENTIFIER)?.remaining())
+    }
+}
+
+/// One name and value pair of a PDU's variable bindings
+pub fn varbind(oid: &Oid, value: &Value) -> Vec<u8> {
+    tlv(SEQUENCE, &[encode_oid(oid), value.encode()].concat())
+}
+
+/// Tag, length and content
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+pub fn integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Drop leading bytes that only repeat the sign bit
+    let mut start = 0;
+    while start < 7 {
+        let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    tlv(INTEGER, &bytes[start..])
+}
+
+pub fn octets(value: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, value)
+}
+
+pub fn sequence(parts: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &parts.concat())
+}
+
+/// Unsigned application types, with a leading zero when the top bit is set
+fn unsigned(tag: u8, value: u64) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value
+        .to_be_bytes()
+        .into_iter()
+        .skip_while(|b| *b == 0)
+        .collect();
+    if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    tlv(tag, &bytes)
+}
+
+fn encode_oid(oid: &Oid) -> Vec<u8> {
+    let arcs = &oid.0;
+    let mut content = Vec::new();
+    if arcs.len() >= 2 {
+        push_base128(&mut content, arcs[0] * 40 + arcs[1]);
+        for arc in &arcs[2..] {
+            push_base128(&mut content, *arc);
+        }
+    }
+    tlv(OBJECT_IDENTIFIER, &content)
+}
+
+fn push_base128(out: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(groups.into_iter().rev());
+}
+
+fn decode_integer(content: &[u8]) -> Result<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return Err(Malformed);
+    }
+    let sign = if content[0] & 0x80 != 0 { -1i64 } else { 0 };
+    Ok(content
+        .iter()
+        .fold(sign, |value, b| (value << 8) | *b as i64))
+}
+
+fn decode_unsigned(content: &[u8]) -> Result<u64> {
+    if content.is_empty() || content.len() > 9 {
+        return Err(Malformed);
+    }
+    Ok(content.iter().fold(0, |value, b| (value << 8) | *b as u64))
+}
+
+fn decode_oid(content: &[u8]) -> Result<Oid> {
+    let mut arcs = Vec::new();
+    let mut value: u32 = 0;
+    for b in content {
+        value = value.checked_mul(128).ok_or(Malformed)? | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.push(first);
+                arcs.push(value - first * 40);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    if content.last().is_some_and(|b| b & 0x80 != 0) {
+        return Err(Malformed);
+    }
+    Ok(Oid(arcs))
+}
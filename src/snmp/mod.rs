@@ -0,0 +1,654 @@
+pub mod ber;
+pub mod usm;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::alarm::{AlarmEvent, AlarmKind};
+use crate::bus::{Event, Snapshot};
+use crate::config::Config;
+use crate::nextys::meters::Meters;
+use ber::{Malformed, Oid, Pdu, Reader, Value};
+use usm::{FLAG_AUTH, FLAG_PRIV, FLAG_REPORTABLE, ScopedPdu, SecurityParameters, User};
+
+/// Private enterprise number the MIB is registered under. 99999 is a
+/// placeholder until one is assigned by IANA.
+pub const ENTERPRISE: u32 = 99999;
+
+/// Largest message the agent sends or accepts
+const MAX_MESSAGE: usize = 1472;
+/// Room left for the message header when fitting varbinds in a response
+const HEADER_ROOM: usize = 200;
+/// Most repetitions answered for one GETBULK
+const MAX_REPETITIONS: i32 = 100;
+/// Seconds a v3 request's engine time may differ from the agent's
+const TIME_WINDOW: u32 = 150;
+
+const VERSION_2C: i64 = 1;
+const VERSION_3: i64 = 3;
+
+const TOO_BIG: i32 = 1;
+const NOT_WRITABLE: i32 = 17;
+
+/// Meter rows of the reading table: name, unit and value, absent for
+/// counters the device does not have
+type Reading = (&'static str, &'static str, fn(&Meters) -> Option<f64>);
+
+const METERS: [Reading; 11] = [
+    ("input_voltage", "V", |m| Some(m.input_voltage as f64)),
+    ("input_current", "A", |m| Some(m.input_current as f64)),
+    ("output_voltage", "V", |m| Some(m.output_voltage as f64)),
+    ("output_current", "A", |m| Some(m.output_current as f64)),
+    ("batt_voltage", "V", |m| Some(m.batt_voltage as f64)),
+    ("batt_current", "A", |m| Some(m.batt_current as f64)),
+    ("batt_soc", "%", |m| Some(m.batt_soc as f64)),
+    ("batt_int_resistance", "mOhm", |m| {
+        Some(m.batt_int_resistance as f64)
+    }),
+    ("batt_charge_capacity", "Ah", |m| {
+        m.batt_charge_capacity.map(f64::from)
+    }),
+    ("operating_time", "h", |m| m.operating_time.map(f64::from)),
+    ("batt_operating_time", "h", |m| {
+        m.batt_operating_time.map(f64::from)
+    }),
+];
+
+/// Units of the settings rows, in the order of [`Settings::values`]
+///
+/// [`Settings::values`]: crate::nextys::settings::Settings::values
+const SETTING_UNITS: [&str; 11] = ["", "V", "A", "V", "V", "V", "A", "Ah", "V", "A", "A"];
+
+fn system(arcs: &[u32]) -> Oid {
+    Oid(vec![1, 3, 6, 1, 2, 1, 1]).child(arcs)
+}
+
+/// Objects of NEXTYS-DCW20-MIB, under `enterprises.ENTERPRISE.1`
+pub fn nextys(arcs: &[u32]) -> Oid {
+    Oid(vec![1, 3, 6, 1, 4, 1, ENTERPRISE, 1]).child(arcs)
+}
+
+fn sys_up_time() -> Oid {
+    system(&[3, 0])
+}
+
+fn snmp_trap_oid() -> Oid {
+    Oid(vec![1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0])
+}
+
+fn usm_stats(arcs: &[u32]) -> Oid {
+    Oid(vec![1, 3, 6, 1, 6, 3, 15, 1, 1]).child(arcs)
+}
+
+/// Counters reported to managers whose v3 requests were rejected
+#[derive(Debug, Clone, Copy)]
+enum UsmStat {
+    UnsupportedSecLevels = 1,
+    NotInTimeWindows = 2,
+    UnknownUserNames = 3,
+    UnknownEngineIds = 4,
+    WrongDigests = 5,
+    DecryptionErrors = 6,
+}
+
+/// The MIB as it stands for `snapshot`, sorted by OID
+pub fn view(config: &Config, snapshot: &Snapshot, uptime: u32) -> Vec<(Oid, Value)> {
+    let mut view = vec![
+        (
+            system(&[1, 0]),
+            Value::string(&format!(
+                "Nextys DCW20 DC UPS, nextys_reader {}",
+                env!("CARGO_PKG_VERSION")
+            )),
+        ),
+        (
+            system(&[2, 0]),
+            Value::Oid(Oid(vec![1, 3, 6, 1, 4, 1, ENTERPRISE, 2, 1])),
+        ),
+        (sys_up_time(), Value::TimeTicks(uptime)),
+        (system(&[4, 0]), Value::string(&config.snmp.contact)),
+        (system(&[5, 0]), Value::string(&config.sys_name)),
+        (system(&[6, 0]), Value::string(&config.location)),
+        // Applications and end to end
+        (system(&[7, 0]), Value::Integer(72)),
+        (
+            nextys(&[1, 1, 1, 0]),
+            Value::string(&config.ip_address.to_string()),
+        ),
+        (
+            nextys(&[1, 1, 3, 0]),
+            Value::truth(snapshot.device_connected),
+        ),
+        (
+            nextys(&[1, 1, 4, 0]),
+            Value::truth(snapshot.database_connected),
+        ),
+    ];
+    if let Some(device_id) = config.device_id {
+        view.push((nextys(&[1, 1, 2, 0]), Value::Integer(device_id)));
+    }
+
+    if let Some(sample) = &snapshot.sample {
+        let meters = &sample.meters;
+        view.push((nextys(&[1, 1, 5, 0]), Value::truth(snapshot.alarms.ac_down)));
+        view.push((
+            nextys(&[1, 1, 6, 0]),
+            Value::truth(snapshot.alarms.batt_low),
+        ));
+        for (index, (name, unit, value)) in METERS.iter().enumerate() {
+            if let Some(value) = value(meters) {
+                push_row(&mut view, index as u32 + 1, name, unit, value);
+            }
+        }
+    }
+    if let Some(settings) = &snapshot.settings {
+        // Settings rows follow the meters and keep their index without them
+        let offset = METERS.len() as u32 + 1;
+        for (index, ((name, value), unit)) in
+            settings.values().into_iter().zip(SETTING_UNITS).enumerate()
+        {
+            push_row(&mut view, offset + index as u32, name, unit, value as f64);
+        }
+    }
+    view.sort_by(|(a, _), (b, _)| a.cmp(b));
+    view
+}
+
+/// Columns of `nextysReadingEntry`, the value in thousandths of the unit
+fn push_row(view: &mut Vec<(Oid, Value)>, index: u32, name: &str, unit: &str, value: f64) {
+    let column = |column| nextys(&[1, 2, 1, column, index]);
+    view.push((column(1), Value::Integer(index as i32)));
+    view.push((column(2), Value::string(name)));
+    view.push((column(3), Value::Integer((value * 1000.0).round() as i32)));
+    view.push((column(4), Value::string(unit)));
+}
+
+/// SNMPv2c and SNMPv3 agent for one DCW20
+pub struct Agent {
+    config: Config,
+    snapshot: Arc<RwLock<Snapshot>>,
+    engine_id: Vec<u8>,
+    boots: u32,
+    started: Instant,
+    users: Vec<User>,
+    usm_stats: [u32; 6],
+    salt: u64,
+    request_id: i32,
+}
+
+impl Agent {
+    pub fn new(config: &Config, snapshot: Arc<RwLock<Snapshot>>) -> io::Result<Self> {
+        let engine_id = match &config.snmp.engine_id {
+            Some(hex) => decode_hex(hex).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "snmp.engine_id should be 5 to 32 bytes of hex",
+                )
+            })?,
+            None => default_engine_id(config.ip_address),
+        };
+        let users = config
+            .snmp
+            .users
+            .iter()
+            .map(|user| {
+                User::new(
+                    &user.name,
+                    &user.auth_password,
+                    user.priv_password.as_deref(),
+                    &engine_id,
+                )
+            })
+            .collect();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Agent {
+            config: config.clone(),
+            snapshot,
+            engine_id,
+            // Increases on every restart without keeping any state
+            boots: now.as_secs().min(i32::MAX as u64) as u32,
+            started: Instant::now(),
+            users,
+            usm_stats: [0; 6],
+            salt: now.as_nanos() as u64,
+            request_id: 0,
+        })
+    }
+
+    pub fn engine_id(&self) -> &[u8] {
+        &self.engine_id
+    }
+
+    pub fn engine_boots(&self) -> u32 {
+        self.boots
+    }
+
+    pub fn engine_time(&self) -> u32 {
+        self.started.elapsed().as_secs() as u32
+    }
+
+    /// Hundredths of a second since the agent started
+    fn uptime(&self) -> u32 {
+        (self.started.elapsed().as_millis() / 10) as u32
+    }
+
+    /// The reply to one datagram, if it deserves one
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() > MAX_MESSAGE {
+            debug!("Dropped SNMP message of {} bytes", request.len());
+            return None;
+        }
+        let result = (|| {
+            let mut message = Reader::new(request).expect(ber::SEQUENCE)?;
+            match message.integer()? {
+                VERSION_2C => self.handle_v2c(&mut message),
+                VERSION_3 => self.handle_v3(request, &mut message),
+                _ => Ok(None),
+            }
+        })();
+        result.unwrap_or_else(|Malformed| {
+            debug!("Dropped malformed SNMP message");
+            None
+        })
+    }
+
+    fn handle_v2c(&mut self, message: &mut Reader) -> ber::Result<Option<Vec<u8>>> {
+        let community = message.octets()?;
+        if self.config.snmp.community.as_deref().map(str::as_bytes) != Some(community) {
+            debug!("Dropped SNMPv2c request with an unknown community");
+            return Ok(None);
+        }
+        let request = Pdu::decode(message)?;
+        let Some(response) = self.respond(&request, MAX_MESSAGE) else {
+            return Ok(None);
+        };
+        Ok(Some(ber::sequence(&[
+            ber::integer(VERSION_2C),
+            ber::octets(community),
+            response.encode(),
+        ])))
+    }
+
+    fn handle_v3(&mut self, raw: &[u8], message: &mut Reader) -> ber::Result<Option<Vec<u8>>> {
+        let message = usm::Message::decode(message)?;
+        let reportable = message.flags & FLAG_REPORTABLE != 0;
+        let limit = (message.max_size.max(484) as usize).min(MAX_MESSAGE);
+        // Discovery sends an empty engine id to learn ours
+        if message.security.engine_id != self.engine_id {
+            return Ok(self.report(&message, None, UsmStat::UnknownEngineIds, reportable));
+        }
+        let Some(user) = self
+            .users
+            .iter()
+            .find(|user| user.name.as_bytes() == message.security.user_name)
+            .cloned()
+        else {
+            return Ok(self.report(&message, None, UsmStat::UnknownUserNames, reportable));
+        };
+        let auth = message.flags & FLAG_AUTH != 0;
+        let privacy = message.flags & FLAG_PRIV != 0;
+        if privacy && !auth {
+            return Err(Malformed);
+        }
+        // Users are only served at the level they are configured for
+        if !auth || privacy != user.has_privacy() {
+            return Ok(self.report(&message, None, UsmStat::UnsupportedSecLevels, reportable));
+        }
+        if !user.verify(raw, message.auth_offset) {
+            warn!("SNMPv3 request for {} failed authentication", user.name);
+            return Ok(self.report(&message, None, UsmStat::WrongDigests, reportable));
+        }
+        let time = self.engine_time();
+        if message.security.boots != self.boots
+            || message.security.time.abs_diff(time) > TIME_WINDOW
+        {
+            return Ok(self.report(&message, Some(&user), UsmStat::NotInTimeWindows, reportable));
+        }
+        let Ok(scoped) = message.scoped_pdu(Some(&user)) else {
+            return Ok(self.report(&message, None, UsmStat::DecryptionErrors, reportable));
+        };
+        let Some(response) = self.respond(&scoped.pdu, limit) else {
+            return Ok(None);
+        };
+        let scoped = ScopedPdu {
+            pdu: response,
+            ..scoped
+        };
+        let security = self.security(&user.name, privacy);
+        let flags = message.flags & (FLAG_AUTH | FLAG_PRIV);
+        Ok(Some(usm::encode(
+            message.id,
+            MAX_MESSAGE as i32,
+            flags,
+            security,
+            Some(&user),
+            &scoped,
+        )))
+    }
+
+    /// Tell the manager why its request was rejected, signed by `user` when
+    /// the request was authentic but out of time
+    fn report(
+        &mut self,
+        message: &usm::Message,
+        user: Option<&User>,
+        stat: UsmStat,
+        reportable: bool,
+    ) -> Option<Vec<u8>> {
+        let counter = &mut self.usm_stats[stat as usize - 1];
+        *counter = counter.wrapping_add(1);
+        debug!("SNMPv3 request rejected: {stat:?}");
+        if !reportable {
+            return None;
+        }
+        let request_id = message
+            .scoped_pdu(None)
+            .map(|scoped| scoped.pdu.request_id)
+            .unwrap_or(0);
+        let scoped = ScopedPdu {
+            context_engine_id: self.engine_id.clone(),
+            context_name: Vec::new(),
+            pdu: Pdu {
+                tag: ber::REPORT,
+                request_id,
+                error_status: 0,
+                error_index: 0,
+                varbinds: vec![(
+                    usm_stats(&[stat as u32, 0]),
+                    Value::Counter32(self.usm_stats[stat as usize - 1]),
+                )],
+            },
+        };
+        let user_name = String::from_utf8_lossy(&message.security.user_name).into_owned();
+        let security = self.security(&user_name, false);
+        let flags = if user.is_some() { FLAG_AUTH } else { 0 };
+        Some(usm::encode(
+            message.id,
+            MAX_MESSAGE as i32,
+            flags,
+            security,
+            user,
+            &scoped,
+        ))
+    }
+
+    /// Security parameters for a message from this engine, with a fresh
+    /// salt when it is to be encrypted
+    fn security(&mut self, user_name: &str, privacy: bool) -> SecurityParameters {
+        let privacy = if privacy {
+            self.salt = self.salt.wrapping_add(1);
+            self.salt.to_be_bytes().to_vec()
+        } else {
+            Vec::new()
+        };
+        SecurityParameters {
+            engine_id: self.engine_id.clone(),
+            boots: self.boots,
+            time: self.engine_time(),
+            user_name: user_name.as_bytes().to_vec(),
+            auth: Vec::new(),
+            privacy,
+        }
+    }
+
+    /// The MIB plus the engine and USM statistics groups
+    fn full_view(&self) -> Vec<(Oid, Value)> {
+        let snapshot = self.snapshot.read().unwrap().clone();
+        let mut view = view(&self.config, &snapshot, self.uptime());
+        let engine = Oid(vec![1, 3, 6, 1, 6, 3, 10, 2, 1]);
+        view.extend([
+            (
+                engine.child(&[1, 0]),
+                Value::OctetString(self.engine_id.clone()),
+            ),
+            (engine.child(&[2, 0]), Value::Integer(self.boots as i32)),
+            (
+                engine.child(&[3, 0]),
+                Value::Integer(self.engine_time() as i32),
+            ),
+            (engine.child(&[4, 0]), Value::Integer(MAX_MESSAGE as i32)),
+        ]);
+        for (index, count) in self.usm_stats.iter().enumerate() {
+            view.push((usm_stats(&[index as u32 + 1, 0]), Value::Counter32(*count)));
+        }
+        view.sort_by(|(a, _), (b, _)| a.cmp(b));
+        view
+    }
+
+    /// Response to a request PDU, fitted in `limit` bytes
+    fn respond(&self, request: &Pdu, limit: usize) -> Option<Pdu> {
+        let view = self.full_view();
+        let mut response = Pdu {
+            tag: ber::RESPONSE,
+            request_id: request.request_id,
+            error_status: 0,
+            error_index: 0,
+            varbinds: Vec::new(),
+        };
+        match request.tag {
+            ber::GET_REQUEST => {
+                response.varbinds = request
+                    .varbinds
+                    .iter()
+                    .map(|(oid, _)| (oid.clone(), get(&view, oid)))
+                    .collect();
+            }
+            ber::GET_NEXT_REQUEST => {
+                response.varbinds = request
+                    .varbinds
+                    .iter()
+                    .map(|(oid, _)| next(&view, oid))
+                    .collect();
+            }
+            ber::GET_BULK_REQUEST => {
+                let non_repeaters =
+                    (request.error_status.max(0) as usize).min(request.varbinds.len());
+                let repetitions = request.error_index.clamp(0, MAX_REPETITIONS);
+                let (single, repeated) = request.varbinds.split_at(non_repeaters);
+                response.varbinds = single.iter().map(|(oid, _)| next(&view, oid)).collect();
+                let mut last: Vec<Oid> = repeated.iter().map(|(oid, _)| oid.clone()).collect();
+                // The two enclosing sequences may each need two more length
+                // bytes as varbinds are added
+                let mut size = response.encode().len() + 4;
+                'rows: for _ in 0..repetitions {
+                    let row: Vec<(Oid, Value)> = last.iter().map(|oid| next(&view, oid)).collect();
+                    let done = row.iter().all(|(_, value)| *value == Value::EndOfMibView);
+                    last = row.iter().map(|(oid, _)| oid.clone()).collect();
+                    for (oid, value) in row {
+                        let len = ber::varbind(&oid, &value).len();
+                        // A shorter walk is fine, the manager asks again from there
+                        if size + len + HEADER_ROOM > limit && !response.varbinds.is_empty() {
+                            break 'rows;
+                        }
+                        size += len;
+                        response.varbinds.push((oid, value));
+                    }
+                    if done {
+                        break;
+                    }
+                }
+            }
+            ber::SET_REQUEST => {
+                response.error_status = NOT_WRITABLE;
+                response.error_index = 1;
+                response.varbinds = request.varbinds.clone();
+            }
+            _ => return None,
+        }
+        if response.encode().len() + HEADER_ROOM > limit {
+            response.error_status = TOO_BIG;
+            response.error_index = 0;
+            response.varbinds.clear();
+        }
+        Some(response)
+    }
+
+    /// Notifications of an alarm being raised or cleared, with where to
+    /// send each
+    pub fn notifications(&mut self, event: &AlarmEvent) -> Vec<(SocketAddr, Vec<u8>)> {
+        let (notification, object) = match event.alarm {
+            AlarmKind::AcDown => (1, nextys(&[1, 1, 5, 0])),
+            AlarmKind::BattLow => (2, nextys(&[1, 1, 6, 0])),
+        };
+        info!("Sending SNMP notification {} = {}", object, event.raised);
+        let varbinds = vec![
+            (sys_up_time(), Value::TimeTicks(self.uptime())),
+            (snmp_trap_oid(), Value::Oid(nextys(&[0, notification]))),
+            (object, Value::truth(event.raised)),
+            (system(&[5, 0]), Value::string(&self.config.sys_name)),
+        ];
+        let mut messages = Vec::new();
+        for target in self.config.snmp.traps.clone() {
+            if let Some(message) = self.trap(&target, varbinds.clone()) {
+                messages.push((target.target, message));
+            }
+        }
+        messages
+    }
+
+    fn trap(
+        &mut self,
+        target: &crate::config::TrapTarget,
+        varbinds: Vec<(Oid, Value)>,
+    ) -> Option<Vec<u8>> {
+        self.request_id = self.request_id.wrapping_add(1);
+        let pdu = Pdu {
+            tag: ber::TRAP,
+            request_id: self.request_id,
+            error_status: 0,
+            error_index: 0,
+            varbinds,
+        };
+        let Some(name) = &target.user else {
+            let community = target
+                .community
+                .as_deref()
+                .or(self.config.snmp.community.as_deref())
+                .unwrap_or("public");
+            return Some(ber::sequence(&[
+                ber::integer(VERSION_2C),
+                ber::octets(community.as_bytes()),
+                pdu.encode(),
+            ]));
+        };
+        let Some(user) = self.users.iter().find(|user| &user.name == name).cloned() else {
+            warn!("SNMP trap user {name} is not configured");
+            return None;
+        };
+        let flags = FLAG_AUTH | if user.has_privacy() { FLAG_PRIV } else { 0 };
+        let security = self.security(&user.name, user.has_privacy());
+        let scoped = ScopedPdu {
+            context_engine_id: self.engine_id.clone(),
+            context_name: Vec::new(),
+            pdu,
+        };
+        Some(usm::encode(
+            self.request_id,
+            MAX_MESSAGE as i32,
+            flags,
+            security,
+            Some(&user),
+            &scoped,
+        ))
+    }
+}
+
+/// Exact match for GET
+fn get(view: &[(Oid, Value)], oid: &Oid) -> Value {
+    match view.binary_search_by(|(candidate, _)| candidate.cmp(oid)) {
+        Ok(index) => view[index].1.clone(),
+        Err(_) => {
+            // A known object asked for with the wrong instance
+            let object = Oid(oid.0[..oid.0.len().saturating_sub(1)].to_vec());
+            if !object.0.is_empty()
+                && view
+                    .iter()
+                    .any(|(candidate, _)| candidate.starts_with(&object))
+            {
+                Value::NoSuchInstance
+            } else {
+                Value::NoSuchObject
+            }
+        }
+    }
+}
+
+/// First object after `oid` for GETNEXT and GETBULK
+fn next(view: &[(Oid, Value)], oid: &Oid) -> (Oid, Value) {
+    let index = view.partition_point(|(candidate, _)| candidate <= oid);
+    match view.get(index) {
+        Some(varbind) => varbind.clone(),
+        None => (oid.clone(), Value::EndOfMibView),
+    }
+}
+
+/// RFC 3411 engine id: the enterprise number then the device's address
+fn default_engine_id(address: IpAddr) -> Vec<u8> {
+    let mut id = (0x8000_0000 | ENTERPRISE).to_be_bytes().to_vec();
+    match address {
+        IpAddr::V4(address) => {
+            id.push(1);
+            id.extend(address.octets());
+        }
+        IpAddr::V6(address) => {
+            id.push(2);
+            id.extend(address.octets());
+        }
+    }
+    id
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x");
+    if !hex.len().is_multiple_of(2) || !(10..=64).contains(&hex.len()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Answer requests on `socket` and send notifications when alarms change,
+/// until the bus shuts down
+pub async fn serve(
+    socket: UdpSocket,
+    mut agent: Agent,
+    mut events: broadcast::Receiver<Event>,
+) -> io::Result<()> {
+    // One byte more than accepted, so longer datagrams are seen and dropped
+    let mut buf = vec![0; MAX_MESSAGE + 1];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = received?;
+                if let Some(reply) = agent.handle(&buf[..len])
+                    && let Err(e) = socket.send_to(&reply, from).await
+                {
+                    warn!("Failed to answer SNMP request from {from}: {e}");
+                }
+            }
+            event = events.recv() => match event {
+                Ok(Event::Alarm(alarm)) => {
+                    for (target, message) in agent.notifications(&alarm) {
+                        if let Err(e) = socket.send_to(&message, target).await {
+                            warn!("Failed to send SNMP notification to {target}: {e}");
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => warn!("SNMP agent missed {missed} events"),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use aes::Aes128;
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+use hmac::{Hmac, Mac};
+use sha1::{Digest, Sha1};
+
+use super::ber::{self, Malformed, Pdu, Reader, Result};
+
+/// Length of the truncated HMAC-SHA-96 digest
+pub const AUTH_LEN: usize = 12;
+/// Bytes of password expanded into the key, RFC 3414 A.2
+const EXPANDED_PASSWORD_LEN: usize = 1_048_576;
+
+/// Localized SHA key for `password`, RFC 3414 A.2.2
+pub fn password_to_key(password: &[u8], engine_id: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    if !password.is_empty() {
+        let mut index = 0;
+        let mut chunk = [0; 64];
+        for _ in 0..EXPANDED_PASSWORD_LEN / chunk.len() {
+            for byte in chunk.iter_mut() {
+                *byte = password[index % password.len()];
+                index += 1;
+            }
+            hasher.update(chunk);
+        }
+    }
+    let key = hasher.finalize();
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(engine_id);
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+/// A user with HMAC-SHA-96 authentication and optional AES-128 privacy
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    auth_key: [u8; 20],
+    priv_key: Option<[u8; 16]>,
+}
+
+impl User {
+    pub fn new(
+        name: &str,
+        auth_password: &str,
+        priv_password: Option<&str>,
+        engine_id: &[u8],
+    ) -> Self {
+        User {
+            name: name.to_string(),
+            auth_key: password_to_key(auth_password.as_bytes(), engine_id),
+            priv_key: priv_password.map(|password| {
+                let key = password_to_key(password.as_bytes(), engine_id);
+                key[..16].try_into().expect("key is longer than 16 bytes")
+            }),
+        }
+    }
+
+    pub fn has_privacy(&self) -> bool {
+        self.priv_key.is_some()
+    }
+
+    /// Digest of `message`, which must hold zeros where the digest goes
+    pub fn sign(&self, message: &[u8]) -> [u8; AUTH_LEN] {
+        let mut mac = self.mac();
+        mac.update(message);
+        mac.finalize().into_bytes()[..AUTH_LEN]
+            .try_into()
+            .expect("digest is longer than 12 bytes")
+    }
+
+    /// Check the digest at `offset` in `message`
+    pub fn verify(&self, message: &[u8], offset: usize) -> bool {
+        let Some(digest) = message.get(offset..offset + AUTH_LEN) else {
+            return false;
+        };
+        let mut zeroed = message.to_vec();
+        zeroed[offset..offset + AUTH_LEN].fill(0);
+        let mut mac = self.mac();
+        mac.update(&zeroed);
+        mac.verify_truncated_left(digest).is_ok()
+    }
+
+    /// AES-128-CFB with the IV from RFC 3826 section 3.1.2.1
+    pub fn encrypt(&self, boots: u32, time: u32, salt: &[u8; 8], data: &mut [u8]) {
+        if let Some(key) = &self.priv_key {
+            cfb_mode::Encryptor::<Aes128>::new(key.into(), &iv(boots, time, salt).into())
+                .encrypt(data);
+        }
+    }
+
+    pub fn decrypt(&self, boots: u32, time: u32, salt: &[u8], data: &mut [u8]) -> Result<()> {
+        let key = self.priv_key.as_ref().ok_or(Malformed)?;
+        let salt: &[u8; 8] = salt.try_into().map_err(|_| Malformed)?;
+        cfb_mode::Decryptor::<Aes128>::new(key.into(), &iv(boots, time, salt).into()).decrypt(data);
+        Ok(())
+    }
+
+    fn mac(&self) -> Hmac<Sha1> {
+        Hmac::<Sha1>::new_from_slice(&self.auth_key).expect("HMAC takes any key length")
+    }
+}
+
+fn iv(boots: u32, time: u32, salt: &[u8; 8]) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv[..4].copy_from_slice(&boots.to_be_bytes());
+    iv[4..8].copy_from_slice(&time.to_be_bytes());
+    iv[8..].copy_from_slice(salt);
+    iv
+}
+
+/// `msgSecurityParameters` of a USM message
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SecurityParameters {
+    pub engine_id: Vec<u8>,
+    pub boots: u32,
+    pub time: u32,
+    pub user_name: Vec<u8>,
+    pub auth: Vec<u8>,
+    pub privacy: Vec<u8>,
+}
+
+impl SecurityParameters {
+    /// The parameters and the offset of the digest in the whole message
+    pub fn decode(reader: &mut Reader) -> Result<(Self, usize)> {
+        let mut octets = reader.expect(ber::OCTET_STRING)?;
+        let mut fields = octets.expect(ber::SEQUENCE)?;
+        let engine_id = fields.octets()?.to_vec();
+        let boots = fields.integer()?.try_into().map_err(|_| Malformed)?;
+        let time = fields.integer()?.try_into().map_err(|_| Malformed)?;
+        let user_name = fields.octets()?.to_vec();
+        let auth = fields.expect(ber::OCTET_STRING)?;
+        let offset = auth.offset();
+        let auth = auth.remaining().to_vec();
+        let privacy = fields.octets()?.to_vec();
+        let parameters = SecurityParameters {
+            engine_id,
+            boots,
+            time,
+            user_name,
+            auth,
+            privacy,
+        };
+        Ok((parameters, offset))
+    }
+
+    /// The encoded parameters and the offset of the digest within them
+    pub fn encode(&self) -> (Vec<u8>, usize) {
+        let before = [
+            ber::octets(&self.engine_id),
+            ber::integer(self.boots as i64),
+            ber::integer(self.time as i64),
+            ber::octets(&self.user_name),
+        ]
+        .concat();
+        let inner = ber::sequence(&[
+            before.clone(),
+            ber::octets(&self.auth),
+            ber::octets(&self.privacy),
+        ]);
+        let outer = ber::octets(&inner);
+        // Tag and length of each wrapper, then of the digest itself
+        let header = (outer.len() - inner.len())
+            + (inner.len() - before.len() - 2 - self.auth.len() - 2 - self.privacy.len());
+        (outer, header + before.len() + 2)
+    }
+}
+
+pub const FLAG_AUTH: u8 = 0x01;
+pub const FLAG_PRIV: u8 = 0x02;
+pub const FLAG_REPORTABLE: u8 = 0x04;
+/// msgSecurityModel for the user based security model
+const USM: i64 = 3;
+
+/// A decoded SNMPv3 message, not yet authenticated
+pub struct Message {
+    pub id: i32,
+    pub max_size: i32,
+    pub flags: u8,
+    pub security: SecurityParameters,
+    /// Where the digest sits in the raw message
+    pub auth_offset: usize,
+    data: Data,
+}
+
+enum Data {
+    Plain(Vec<u8>),
+    Encrypted(Vec<u8>),
+}
+
+impl Message {
+    /// Decode the fields after the version in an SNMPv3 message
+    pub fn decode(message: &mut Reader) -> Result<Self> {
+        let mut global = message.expect(ber::SEQUENCE)?;
+        let id = global.integer()?.try_into().map_err(|_| Malformed)?;
+        let max_size = global.integer()?.try_into().map_err(|_| Malformed)?;
+        let flags = *global.octets()?.first().ok_or(Malformed)?;
+        if global.integer()? != USM {
+            return Err(Malformed);
+        }
+        let (security, auth_offset) = SecurityParameters::decode(message)?;
+        let data = if flags & FLAG_PRIV != 0 {
+            Data::Encrypted(message.octets()?.to_vec())
+        } else {
+            let (tag, content) = message.element()?;
+            if tag != ber::SEQUENCE {
+                return Err(Malformed);
+            }
+            Data::Plain(ber::tlv(tag, content.remaining()))
+        };
+        Ok(Message {
+            id,
+            max_size,
+            flags,
+            security,
+            auth_offset,
+            data,
+        })
+    }
+
+    /// The scoped PDU, decrypted with `user` when the message is encrypted
+    pub fn scoped_pdu(&self, user: Option<&User>) -> Result<ScopedPdu> {
+        match &self.data {
+            Data::Plain(data) => ScopedPdu::decode(data),
+            Data::Encrypted(data) => {
+                let user = user.ok_or(Malformed)?;
+                let mut data = data.clone();
+                user.decrypt(
+                    self.security.boots,
+                    self.security.time,
+                    &self.security.privacy,
+                    &mut data,
+                )?;
+                ScopedPdu::decode(&data)
+            }
+        }
+    }
+}
+
+/// Encode a message, encrypting it with the salt in `security.privacy` and
+/// signing it when `flags` ask for it
+pub fn encode(
+    id: i32,
+    max_size: i32,
+    flags: u8,
+    mut security: SecurityParameters,
+    user: Option<&User>,
+    scoped_pdu: &ScopedPdu,
+) -> Vec<u8> {
+    let mut data = scoped_pdu.encode();
+    if flags & FLAG_PRIV != 0
+        && let Some(user) = user
+    {
+        let salt: [u8; 8] = security
+            .privacy
+            .as_slice()
+            .try_into()
+            .expect("salt should be 8 bytes");
+        user.encrypt(security.boots, security.time, &salt, &mut data);
+        data = ber::octets(&data);
+    }
+    let signed = flags & FLAG_AUTH != 0 && user.is_some();
+    security.auth = if signed {
+        vec![0; AUTH_LEN]
+    } else {
+        Vec::new()
+    };
+    let (security, auth_offset) = security.encode();
+    let before = [
+        ber::integer(3),
+        ber::sequence(&[
+            ber::integer(id as i64),
+            ber::integer(max_size as i64),
+            ber::octets(&[flags]),
+            ber::integer(USM),
+        ]),
+    ]
+    .concat();
+    let content_len = before.len() + security.len() + data.len();
+    let mut message = ber::tlv(ber::SEQUENCE, &[before.clone(), security, data].concat());
+    if signed && let Some(user) = user {
+        let offset = message.len() - content_len + before.len() + auth_offset;
+        let digest = user.sign(&message);
+        message[offset..offset + AUTH_LEN].copy_from_slice(&digest);
+    }
+    message
+}
+
+/// A PDU with the context it applies to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopedPdu {
+    pub context_engine_id: Vec<u8>,
+    pub context_name: Vec<u8>,
+    pub pdu: Pdu,
+}
+
+impl ScopedPdu {
+    pub fn encode(&self) -> Vec<u8> {
+        ber::sequence(&[
+            ber::octets(&self.context_engine_id),
+            ber::octets(&self.context_name),
+            self.pdu.encode(),
+        ])
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut scoped = Reader::new(data).expect(ber::SEQUENCE)?;
+        Ok(ScopedPdu {
+            context_engine_id: scoped.octets()?.to_vec(),
+            context_name: scoped.octets()?.to_vec(),
+            pdu: Pdu::decode(&mut scoped)?,
+        })
+    }
+}
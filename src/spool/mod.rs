@@ -0,0 +1,181 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use sqlx::Postgres;
+
+use crate::alarm::Flags;
+use crate::config::{self, Config};
+use crate::database;
+use crate::nextys::meters::MetersAggregate;
+
+/// A window of metrics waiting to be uploaded with its original timestamp
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpooledMetrics {
+    pub time: DateTime<Utc>,
+    pub meters: MetersAggregate,
+    /// Alarms raised when the window closed, older records and one shot
+    /// uploads have none
+    #[serde(default)]
+    pub alarms: Option<Flags>,
+}
+
+/// Append only JSON lines file holding metrics that failed to upload
+pub struct MetricsSpool {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: TimeDelta,
+}
+
+impl MetricsSpool {
+    pub fn new(config: &config::Spool) -> Self {
+        MetricsSpool {
+            path: PathBuf::from(&config.path),
+            max_bytes: config.max_bytes,
+            max_age: TimeDelta::hours(config.max_age_hours as i64),
+        }
+    }
+
+    /// Upload `record`, replaying anything already spooled first so rows
+    /// arrive in order. The record is spooled if the database is unreachable.
+    /// Returns whether the database took the record.
+    pub async fn upload(
+        &self,
+        pool: &sqlx::Pool<Postgres>,
+        config: &Config,
+        record: SpooledMetrics,
+    ) -> io::Result<bool> {
+        if let Err(e) = self.replay(pool, config).await {
+            warn!("Database unavailable, spooling metrics: {e}");
+            return self.push(&record).map(|_| false);
+        }
+        match database::upload_metrics(pool, config, record.time, &record.meters, record.alarms)
+            .await
+        {
+            Ok(_) => {
+                info!("Uploaded Metrics");
+                Ok(true)
+            }
+            Err(e) => {
+                warn!("Failed to upload metrics, spooling: {e}");
+                self.push(&record).map(|_| false)
+            }
+        }
+    }
+
+    /// Append a record, dropping the oldest ones if the spool outgrows its cap
+    pub fn push(&self, record: &SpooledMetrics) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        // Finish a partial last line left by a torn write, or this record
+        // would be glued onto it and skipped as well
+        if file.metadata()?.len() > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last != *b"\n" {
+                line.insert(0, '\n');
+            }
+        }
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        if file.metadata()?.len() > self.max_bytes {
+            let mut records = self.load()?;
+            let mut size: u64 = records.iter().map(record_size).sum();
+            let mut dropped = 0;
+            while size > self.max_bytes && !records.is_empty() {
+                size -= record_size(&records.remove(0));
+                dropped += 1;
+            }
+            warn!(
+                "Spool is over {} bytes, dropped {dropped} oldest samples",
+                self.max_bytes
+            );
+            self.rewrite(&records)?;
+        }
+        Ok(())
+    }
+
+    /// Spooled records oldest first, skipping any past the age cap
+    pub fn load(&self) -> io::Result<Vec<SpooledMetrics>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let oldest = Utc::now() - self.max_age;
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<SpooledMetrics>(&line) {
+                Ok(record) if record.time >= oldest => records.push(record),
+                Ok(_) => {}
+                // A write cut short by a power loss leaves a partial last line
+                Err(e) => warn!("Skipping unreadable spool entry: {e}"),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Upload spooled records in order, keeping whatever could not be sent.
+    /// Returns how many records were uploaded.
+    pub async fn replay(
+        &self,
+        pool: &sqlx::Pool<Postgres>,
+        config: &Config,
+    ) -> Result<usize, sqlx::Error> {
+        if !self.path.exists() {
+            return Ok(0);
+        }
+        let records = self.load()?;
+        let mut uploaded = 0;
+        let mut result = Ok(());
+        for record in &records {
+            if let Err(e) =
+                database::upload_metrics(pool, config, record.time, &record.meters, record.alarms)
+                    .await
+            {
+                result = Err(e);
+                break;
+            }
+            uploaded += 1;
+        }
+        if uploaded > 0 {
+            info!("Replayed {uploaded} spooled samples");
+            self.rewrite(&records[uploaded..])?;
+        }
+        result.map(|_| uploaded)
+    }
+
+    /// Atomically replace the spool with `records`, removing it when empty
+    fn rewrite(&self, records: &[SpooledMetrics]) -> io::Result<()> {
+        if records.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        for record in records {
+            serde_json::to_writer(&mut file, record)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn record_size(record: &SpooledMetrics) -> u64 {
+    serde_json::to_string(record).map_or(0, |line| line.len() as u64 + 1)
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_nextys_monitoring::alarm::{Alarm, AlarmKind};
+use rust_nextys_monitoring::config::AlarmOptions;
+
+fn at(secs: i64) -> DateTime<Utc> {
+    DateTime::UNIX_EPOCH + TimeDelta::seconds(secs)
+}
+
+/// Feed `readings` a second apart, returning the state after each
+fn run(alarm: &mut Alarm, readings: &[f32]) -> Vec<bool> {
+    readings
+        .iter()
+        .enumerate()
+        .map(|(secs, &value)| {
+            alarm.update(value, at(secs as i64));
+            alarm.is_raised()
+        })
+        .collect()
+}
+
+#[test]
+fn hysteresis_keeps_the_alarm_until_the_clear_point() {
+    let options = AlarmOptions {
+        clear: Some(12.0),
+        ..Default::default()
+    };
+    let mut alarm = Alarm::new(AlarmKind::BattLow, &options, 11.0);
+    assert_eq!(
+        run(&mut alarm, &[12.5, 11.0, 11.5, 12.0, 11.5, 12.1, 11.5]),
+        [false, true, true, true, true, false, false]
+    );
+}
+
+#[test]
+fn clear_point_below_set_point_is_ignored() {
+    let options = AlarmOptions {
+        clear: Some(10.0),
+        ..Default::default()
+    };
+    let mut alarm = Alarm::new(AlarmKind::BattLow, &options, 11.0);
+    assert_eq!(run(&mut alarm, &[11.0, 11.1]), [true, false]);
+}
+
+#[test]
+fn short_dips_are_debounced() {
+    let options = AlarmOptions {
+        set: Some(5.0),
+        raise_after_secs: 2,
+        clear_after_secs: 1,
+        ..Default::default()
+    };
+    let mut alarm = Alarm::new(AlarmKind::AcDown, &options, 20.0);
+    assert_eq!(
+        run(
+            &mut alarm,
+            &[24.0, 0.0, 24.0, 0.0, 0.0, 0.0, 24.0, 0.0, 24.0, 24.0]
+        ),
+        [
+            false, false, false, false, false, true, true, true, true, false
+        ]
+    );
+}
+
+#[test]
+fn events_carry_the_reading_and_time() {
+    let mut alarm = Alarm::new(AlarmKind::AcDown, &AlarmOptions::default(), 5.0);
+    assert_eq!(alarm.update(24.0, at(0)), None);
+    let raised = alarm.update(4.5, at(1)).unwrap();
+    assert_eq!(raised.alarm, AlarmKind::AcDown);
+    assert!(raised.raised);
+    assert_eq!(raised.time, at(1));
+    assert_eq!(raised.value, Some(4.5));
+    assert_eq!(alarm.update(4.0, at(2)), None);
+    let cleared = alarm.update(24.0, at(3)).unwrap();
+    assert!(!cleared.raised);
+    assert_eq!(cleared.value, Some(24.0));
+}
+
+#[test]
+fn latched_alarms_wait_for_acknowledgement() {
+    let options = AlarmOptions {
+        latched: true,
+        ..Default::default()
+    };
+    let mut alarm = Alarm::new(AlarmKind::AcDown, &options, 5.0);
+    assert_eq!(alarm.acknowledge(at(0)), None);
+    assert_eq!(run(&mut alarm, &[0.0, 24.0, 24.0]), [true, true, true]);
+    let cleared = alarm.acknowledge(at(3)).unwrap();
+    assert!(!cleared.raised);
+    assert_eq!(cleared.value, None);
+
+    // Acknowledged while still down, it clears once the condition ends
+    alarm.update(0.0, at(4));
+    assert_eq!(alarm.acknowledge(at(5)), None);
+    assert!(alarm.is_raised());
+    assert!(alarm.update(24.0, at(6)).is_some());
+    assert!(!alarm.is_raised());
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use rust_nextys_monitoring::alarm::{AlarmEngine, AlarmKind};
+use rust_nextys_monitoring::bus::{Bus, BusHandle, Event};
+use rust_nextys_monitoring::config;
+use rust_nextys_monitoring::error::Error;
+use rust_nextys_monitoring::nextys::Nextys;
+use rust_nextys_monitoring::nextys::registers::RegisterMap;
+use rust_nextys_monitoring::nextys::transport::MemoryTransport;
+use tokio::time::timeout;
+
+/// A DCW20 on mains with a 12 V lead battery
+fn transport() -> MemoryTransport {
+    let mut registers: Vec<(u16, u16)> = (0x1010..=0x1023).map(|a| (a, 0)).collect();
+    registers.extend((0x2000..=0x200E).map(|address| (address, 0)));
+    let mut transport = MemoryTransport::new(registers);
+    transport.registers.extend([
+        (0x1010, 1),
+        (0x1011, 144),
+        (0x1013, 136),
+        (0x1014, 115),
+        (0x1015, 105),
+        (0x1017, 70),
+        (0x1021, 240),
+        (0x2000, 240),
+        (0x2004, 135),
+    ]);
+    transport
+}
+
+fn options() -> config::Daemon {
+    config::Daemon {
+        sample_interval_ms: 10,
+        backoff_initial_ms: 10,
+        ..Default::default()
+    }
+}
+
+/// Alarms raised at `ac_down` volts, latched until acknowledged
+fn alarms(ac_down: f32) -> AlarmEngine {
+    let config: config::Config = toml::from_str(&format!(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "ups"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = {ac_down}
+
+        [timescaledb]
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5432
+        timescaledb_user = "nextys"
+        timescaledb_pass = "hunter2"
+        timescaledb_db = "metrics"
+
+        [alarms.ac_down]
+        latched = true
+        "#
+    ))
+    .unwrap();
+    AlarmEngine::new(&config)
+}
+
+fn spawn_alarmed(ac_down: f32) -> BusHandle {
+    Bus::spawn_with(&options(), alarms(ac_down), || async {
+        Ok(Nextys::new(transport(), RegisterMap::dcw20()))
+    })
+}
+
+fn spawn() -> BusHandle {
+    spawn_alarmed(5.0)
+}
+
+#[tokio::test]
+async fn commands_are_served_in_turn() {
+    let bus = spawn();
+    let (meters, settings) = tokio::join!(bus.read_meters(), bus.read_settings());
+    assert_eq!(meters.unwrap().batt_voltage, 13.5);
+    assert_eq!(settings.unwrap().batt_charge_voltage, 14.4);
+    assert_eq!(bus.read_register(0x2000, 1).await.unwrap(), [240]);
+}
+
+#[tokio::test]
+async fn settings_writes_are_broadcast() {
+    let bus = spawn();
+    let mut events = bus.subscribe();
+    let mut settings = bus.read_settings().await.unwrap();
+    settings.batt_float_voltage = 13.8;
+    let changes = bus.write_settings(settings).await.unwrap();
+    assert_eq!(changes.len(), 1);
+
+    let changed = timeout(Duration::from_secs(1), async {
+        loop {
+            if let Event::SettingsChanged(changes) = events.recv().await.unwrap() {
+                return changes;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(changed[0].name, "batt_float_voltage");
+    assert_eq!(bus.read_settings().await.unwrap().batt_float_voltage, 13.8);
+}
+
+#[tokio::test]
+async fn samples_are_broadcast_and_flushed_on_shutdown() {
+    let bus = spawn();
+    let mut events = bus.subscribe();
+    let mut samples = 0;
+    while samples < 3 {
+        let event = timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        match event.unwrap() {
+            Event::Sample(sample) => {
+                assert_eq!(sample.meters.input_voltage, 24.0);
+                samples += 1;
+            }
+            Event::Request { error, .. } => assert_eq!(error, None),
+            Event::Connected => {}
+            other => panic!("unexpected event {other:?}"),
+        }
+    }
+    assert!(bus.snapshot().read().unwrap().device_connected);
+
+    let window = bus.shutdown().await.expect("window should hold samples");
+    assert!(window.meters.samples >= 2);
+    assert!(matches!(bus.read_meters().await, Err(Error::BusClosed)));
+}
+
+#[tokio::test]
+async fn failed_connections_are_retried() {
+    let attempts = Arc::new(AtomicU32::new(0));
+    let counter = attempts.clone();
+    let bus = Bus::spawn_with(&options(), alarms(5.0), move || {
+        let attempt = counter.fetch_add(1, Ordering::Relaxed);
+        async move {
+            if attempt < 2 {
+                return Err(Error::Connect(std::io::ErrorKind::NotFound.into()));
+            }
+            Ok(Nextys::new(transport(), RegisterMap::dcw20()))
+        }
+    });
+    let mut events = bus.subscribe();
+    timeout(Duration::from_secs(2), async {
+        while !matches!(events.recv().await.unwrap(), Event::Connected) {}
+    })
+    .await
+    .unwrap();
+    assert_eq!(attempts.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn alarms_are_raised_from_samples() {
+    let bus = spawn_alarmed(30.0);
+    let mut events = bus.subscribe();
+    let event = timeout(Duration::from_secs(1), async {
+        loop {
+            if let Event::Alarm(event) = events.recv().await.unwrap() {
+                return event;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(event.alarm, AlarmKind::AcDown);
+    assert!(event.raised);
+    assert_eq!(event.value, Some(24.0));
+    let alarms = bus.snapshot().read().unwrap().alarms;
+    assert!(alarms.ac_down && !alarms.batt_low);
+
+    // Still on battery, so acknowledging only lets it clear later
+    assert_eq!(
+        bus.acknowledge_alarm(AlarmKind::AcDown).await.unwrap(),
+        None
+    );
+    assert!(bus.snapshot().read().unwrap().alarms.ac_down);
+
+    let window = bus.shutdown().await.unwrap();
+    assert!(window.alarms.unwrap().ac_down);
+}
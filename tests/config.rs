@@ -0,0 +1,267 @@
+use std::fs;
+
+use rust_nextys_monitoring::config::{Config, ConfigError, Level};
+use rust_nextys_monitoring::nextys::settings::{BatteryType, Settings};
+
+const CONFIG: &str = r#"
+ip_address = "10.0.0.2"
+sys_name = "ups"
+location = "rack 1"
+low_batt_threshold = 11.0
+ac_down_threshold = 5.0
+
+[timescaledb]
+timescaledb_host = "10.0.0.1"
+timescaledb_user = "nextys"
+timescaledb_pass = "from-file"
+timescaledb_db = "metrics"
+"#;
+
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn temp(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("nextys_config_{}_{name}", std::process::id()));
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn environment_overrides_any_field() {
+    let config = Config::parse(
+        CONFIG,
+        env(&[
+            ("NEXTYS_DEVICE_ID", "7"),
+            ("NEXTYS_SYS_NAME", "1234"),
+            ("NEXTYS_TIMESCALEDB__TIMESCALEDB_PORT", "5433"),
+            ("NEXTYS_MQTT__HOST", "broker.local"),
+            ("NEXTYS_ALARMS__AC_DOWN__LATCHED", "true"),
+            ("PATH", "/usr/bin"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(config.device_id, Some(7));
+    // Strings in the file stay strings even when they look like numbers
+    assert_eq!(config.sys_name, "1234");
+    assert_eq!(config.timescaledb.timescaledb_port, 5433);
+    assert_eq!(config.mqtt.host.as_deref(), Some("broker.local"));
+    assert!(config.alarms.ac_down.latched);
+}
+
+#[test]
+fn secrets_missing_from_the_file_stay_strings() {
+    let content = CONFIG.replace("timescaledb_pass = \"from-file\"\n", "");
+    let config = Config::parse(
+        &content,
+        env(&[
+            ("NEXTYS_TIMESCALEDB__TIMESCALEDB_PASS", "true"),
+            ("NEXTYS_MQTT__PASSWORD", "123456"),
+            ("NEXTYS_MQTT__PORT", "1884"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(config.timescaledb.timescaledb_pass, "true");
+    assert_eq!(config.mqtt.password.as_deref(), Some("123456"));
+    assert_eq!(config.mqtt.port, 1884);
+}
+
+#[test]
+fn bad_overrides_are_reported() {
+    let error = Config::parse(CONFIG, env(&[("NEXTYS_SYS_NAME__X", "1")])).unwrap_err();
+    assert!(matches!(error, ConfigError::Override(name) if name == "NEXTYS_SYS_NAME__X"));
+    let error = Config::parse(CONFIG, env(&[("NEXTYS_DEVICE_ID", "seven")])).unwrap_err();
+    assert!(error.to_string().contains("device_id"));
+}
+
+#[test]
+fn password_file_replaces_the_password() {
+    let secret = temp("secret");
+    fs::write(&secret, "from-secret\n").unwrap();
+    let content = CONFIG.replace(
+        r#"timescaledb_pass = "from-file""#,
+        &format!(r#"timescaledb_pass_file = "{secret}""#),
+    );
+    let config = Config::parse(&content, env(&[])).unwrap();
+    assert_eq!(config.timescaledb.timescaledb_pass, "from-secret");
+
+    let config = Config::parse(
+        &content,
+        env(&[("NEXTYS_TIMESCALEDB__TIMESCALEDB_PASS", "from-env")]),
+    )
+    .unwrap();
+    assert_eq!(config.timescaledb.timescaledb_pass, "from-env");
+    fs::remove_file(secret).unwrap();
+
+    assert!(matches!(
+        Config::parse(&content, env(&[])),
+        Err(ConfigError::PasswordFile { .. })
+    ));
+}
+
+#[test]
+fn save_keeps_secrets_out_of_the_file() {
+    let secret = temp("saved_secret");
+    fs::write(&secret, "from-secret").unwrap();
+    let content = CONFIG.replace(
+        r#"timescaledb_pass = "from-file""#,
+        &format!(r#"timescaledb_pass_file = "{secret}""#),
+    );
+    let mut config = Config::parse(
+        &content,
+        env(&[
+            ("NEXTYS_MQTT__PASSWORD", "mqtt-secret"),
+            ("NEXTYS_LOCATION", "rack 9"),
+        ]),
+    )
+    .unwrap();
+    config.device_id = Some(3);
+
+    let path = temp("saved.toml");
+    config.save(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(!saved.contains("from-secret"));
+    assert!(!saved.contains("mqtt-secret"));
+    assert!(saved.contains(r#"location = "rack 1""#));
+
+    let reloaded = Config::parse(&saved, env(&[])).unwrap();
+    fs::remove_file(secret).unwrap();
+    assert_eq!(reloaded.device_id, Some(3));
+    assert_eq!(reloaded.timescaledb.timescaledb_pass, "from-secret");
+    assert_eq!(reloaded.mqtt.password, None);
+}
+
+#[test]
+fn validation_collects_every_problem() {
+    let config = Config::parse(
+        CONFIG,
+        env(&[
+            ("NEXTYS_DEVICE_ID", "1"),
+            ("NEXTYS_AC_DOWN_THRESHOLD", "0"),
+            ("NEXTYS_ALARMS__BATT_LOW__CLEAR", "10.5"),
+            ("NEXTYS_SERIAL__STOP_BITS", "3"),
+            (
+                "NEXTYS_TIMESCALEDB__TIMESCALEDB_SSL_CLIENT_CERT",
+                "/nonexistent.pem",
+            ),
+        ]),
+    )
+    .unwrap();
+    let report = config.validate();
+    let levels: Vec<_> = report
+        .problems
+        .iter()
+        .map(|p| (p.field.as_str(), p.level))
+        .collect();
+    assert_eq!(
+        levels,
+        [
+            ("ac_down_threshold", Level::Error),
+            ("alarms.batt_low.clear", Level::Warning),
+            ("timescaledb.timescaledb_ssl_client_key", Level::Error),
+            ("timescaledb.timescaledb_ssl_client_cert", Level::Error),
+            ("serial.stop_bits", Level::Error),
+        ]
+    );
+    assert!(report.has_errors());
+    assert!(report.get("serial.stop_bits").unwrap().hint.is_some());
+    assert!(report.to_string().ends_with("4 error(s), 1 warning(s)"));
+
+    let config = Config::parse(CONFIG, env(&[("NEXTYS_DEVICE_ID", "1")])).unwrap();
+    assert!(config.validate().is_empty());
+}
+
+#[test]
+fn batt_low_is_checked_against_the_device() {
+    let mut settings = Settings {
+        batt_type: BatteryType::Lead,
+        batt_type_int: 1,
+        batt_charge_voltage: 14.4,
+        batt_charge_current: 2.0,
+        batt_float_voltage: 13.6,
+        batt_low_voltage: 11.5,
+        batt_deep_discharge_voltage: 10.5,
+        batt_max_discharge_current: 10.0,
+        batt_capacity: 7.0,
+        nominal_output_voltage: 12.0,
+        max_input_current: 5.0,
+        max_output_current: 5.0,
+    };
+    let config = Config::parse(CONFIG, env(&[])).unwrap();
+    let report = config.validate_settings(&settings);
+    assert_eq!(
+        report.get("low_batt_threshold").map(|p| p.level),
+        Some(Level::Warning)
+    );
+
+    settings.batt_low_voltage = 10.8;
+    assert!(config.validate_settings(&settings).is_empty());
+
+    let config = Config::parse(CONFIG, env(&[("NEXTYS_ALARMS__BATT_LOW__SET", "13.8")])).unwrap();
+    let report = config.validate_settings(&settings);
+    assert_eq!(
+        report.get("alarms.batt_low.set").map(|p| p.level),
+        Some(Level::Error)
+    );
+}
+
+#[test]
+fn misspelled_overrides_are_reported() {
+    let config = Config::parse(
+        CONFIG,
+        env(&[
+            ("NEXTYS_DEVICE_ID", "1"),
+            ("NEXTYS_TIMESCALEDB__TIMESCALEDB_PASWORD", "secret"),
+            ("NEXTYS_TIMESCALEDB_PASS", "secret"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(config.timescaledb.timescaledb_pass, "from-file");
+    let report = config.validate();
+    assert_eq!(report.problems.len(), 2);
+    let misspelled = report
+        .get("NEXTYS_TIMESCALEDB__TIMESCALEDB_PASWORD")
+        .unwrap();
+    assert_eq!(misspelled.level, Level::Warning);
+    assert_eq!(
+        misspelled.hint.as_deref(),
+        Some("did you mean NEXTYS_TIMESCALEDB__TIMESCALEDB_PASS?")
+    );
+    assert!(report.get("NEXTYS_TIMESCALEDB_PASS").is_some());
+}
+
+#[test]
+fn parse_errors_are_reported_by_field() {
+    let content = CONFIG
+        .replace("ac_down_threshold = 5.0", r#"ac_down_threshold = "five""#)
+        .replace(r#"timescaledb_user = "nextys""#, "")
+        + "[tcp]\nport = 70000\n";
+    let report = Config::parse(&content, env(&[])).unwrap_err().report();
+    let fields: Vec<_> = report
+        .problems
+        .iter()
+        .map(|p| (p.field.as_str(), p.level))
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            ("ac_down_threshold", Level::Error),
+            ("tcp.port", Level::Error),
+            ("timescaledb.timescaledb_user", Level::Error),
+        ]
+    );
+    assert_eq!(
+        report
+            .get("timescaledb.timescaledb_user")
+            .unwrap()
+            .hint
+            .as_deref(),
+        Some("add timescaledb_user to the [timescaledb] section")
+    );
+
+    let error = Config::parse("sys_name = \"ups\"\nlocation = [\n", env(&[])).unwrap_err();
+    assert!(matches!(error, ConfigError::Syntax { line: 3, .. }));
+    assert_eq!(error.report().problems[0].field, "line 3, column 1");
+}
@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+use rust_nextys_monitoring::daemon::Backoff;
+
+#[test]
+fn backoff_doubles_up_to_the_cap() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+    assert!(backoff.ready());
+    let delays: Vec<u64> = (0..5).map(|_| backoff.failed().as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 5, 5]);
+    assert!(!backoff.ready());
+
+    backoff.reset();
+    assert!(backoff.ready());
+    assert_eq!(backoff.failed(), Duration::from_secs(1));
+}
@@ -0,0 +1,63 @@
+use rust_nextys_monitoring::config::{Config, TimescaleDB};
+use rust_nextys_monitoring::database;
+use sqlx::postgres::PgSslMode;
+
+fn timescaledb(section: &str) -> TimescaleDB {
+    let config: Config = toml::from_str(&format!(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "ups"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = 5.0
+
+        [timescaledb]
+        {section}
+        "#
+    ))
+    .unwrap();
+    config.timescaledb
+}
+
+#[test]
+fn existing_configs_keep_working() {
+    let database = timescaledb(
+        r#"
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5433
+        timescaledb_user = "nextys"
+        timescaledb_pass = "p@ss/word"
+        timescaledb_db = "metrics"
+        "#,
+    );
+    let options = database::connect_options(&database);
+    assert_eq!(options.get_host(), "10.0.0.1");
+    assert_eq!(options.get_port(), 5433);
+    assert_eq!(options.get_username(), "nextys");
+    assert_eq!(options.get_database(), Some("metrics"));
+    assert_eq!(options.get_application_name(), Some("nextys_reader"));
+    assert!(matches!(options.get_ssl_mode(), PgSslMode::Prefer));
+    assert_eq!(database.timescaledb_max_connections, 5);
+}
+
+#[test]
+fn tls_and_session_options_are_applied() {
+    let database = timescaledb(
+        r#"
+        timescaledb_host = "db.example.com"
+        timescaledb_user = "nextys"
+        timescaledb_pass = "secret"
+        timescaledb_db = "metrics"
+        timescaledb_sslmode = "verify-full"
+        timescaledb_ssl_root_cert = "/etc/nextys/ca.pem"
+        timescaledb_application_name = "site-4"
+        timescaledb_statement_timeout_ms = 5000
+        "#,
+    );
+    let options = database::connect_options(&database);
+    assert_eq!(options.get_host(), "db.example.com");
+    assert_eq!(options.get_port(), 5432);
+    assert!(matches!(options.get_ssl_mode(), PgSslMode::VerifyFull));
+    assert_eq!(options.get_application_name(), Some("site-4"));
+    assert_eq!(options.get_options(), Some("-c statement_timeout=5000"));
+}
@@ -0,0 +1,55 @@
+use chrono::Utc;
+use rust_nextys_monitoring::alarm::{AlarmEvent, AlarmKind};
+use rust_nextys_monitoring::events::{EventKind, SensorEvent, Severity};
+use rust_nextys_monitoring::nextys::settings::SettingChange;
+use serde_json::json;
+
+fn alarm(alarm: AlarmKind, raised: bool) -> SensorEvent {
+    SensorEvent::alarm(&AlarmEvent {
+        alarm,
+        raised,
+        time: Utc::now(),
+        value: Some(10.5),
+    })
+}
+
+#[test]
+fn alarms_are_graded_by_what_they_mean_for_the_load() {
+    let mains = alarm(AlarmKind::AcDown, true);
+    assert_eq!(mains.kind, EventKind::AlarmRaised);
+    assert_eq!(mains.severity, Severity::Warning);
+    assert_eq!(mains.payload, json!({ "alarm": "ac_down", "value": 10.5 }));
+
+    assert_eq!(alarm(AlarmKind::BattLow, true).severity, Severity::Critical);
+    let cleared = alarm(AlarmKind::BattLow, false);
+    assert_eq!(cleared.kind, EventKind::AlarmCleared);
+    assert_eq!(cleared.severity, Severity::Info);
+}
+
+#[test]
+fn settings_changes_list_old_and_new_values() {
+    let changes = [SettingChange {
+        name: "batt_float_voltage",
+        old: 13.5,
+        new: 13.75,
+    }];
+    let event = SensorEvent::settings_changed(Utc::now(), &changes);
+    assert_eq!(
+        event.payload,
+        json!({ "changes": [{ "name": "batt_float_voltage", "old": 13.5, "new": 13.75 }] })
+    );
+}
+
+#[test]
+fn names_round_trip_and_severities_filter_upwards() {
+    for kind in EventKind::ALL {
+        assert_eq!(EventKind::from_name(kind.name()), Some(kind));
+    }
+    for severity in Severity::ALL {
+        assert_eq!(Severity::from_name(severity.name()), Some(severity));
+    }
+    assert_eq!(
+        Severity::Warning.and_above(),
+        [Severity::Warning, Severity::Critical]
+    );
+}
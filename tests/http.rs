@@ -0,0 +1,181 @@
+use std::sync::{Arc, RwLock};
+
+use axum::body::{Body, to_bytes};
+use axum::http::{Request, StatusCode};
+use chrono::Utc;
+use rust_nextys_monitoring::alarm::{AlarmEngine, Flags};
+use rust_nextys_monitoring::bus::{Bus, Sample, Snapshot};
+use rust_nextys_monitoring::config::{self, Config};
+use rust_nextys_monitoring::http;
+use rust_nextys_monitoring::metrics::Metrics;
+use rust_nextys_monitoring::nextys::Nextys;
+use rust_nextys_monitoring::nextys::meters::Meters;
+use rust_nextys_monitoring::nextys::registers::RegisterMap;
+use rust_nextys_monitoring::nextys::transport::MemoryTransport;
+use serde_json::Value;
+use tower::ServiceExt;
+
+fn config() -> Config {
+    toml::from_str(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "ups"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = 5.0
+
+        [timescaledb]
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5432
+        timescaledb_user = "nextys"
+        timescaledb_pass = "hunter2"
+        timescaledb_db = "metrics"
+        "#,
+    )
+    .unwrap()
+}
+
+async fn get(snapshot: &Arc<RwLock<Snapshot>>, uri: &str) -> (StatusCode, Value) {
+    let (status, body) = get_text(snapshot, uri).await;
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+async fn get_text(snapshot: &Arc<RwLock<Snapshot>>, uri: &str) -> (StatusCode, String) {
+    let config = config();
+    let metrics = Arc::new(Metrics::new(&config));
+    let response = http::router(snapshot.clone(), metrics, &config)
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn meters_are_unavailable_until_sampled() {
+    let snapshot = Arc::new(RwLock::new(Snapshot::default()));
+    let (status, _) = get(&snapshot, "/meters").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let (status, health) = get(&snapshot, "/health").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(health["device_connected"], false);
+
+    {
+        let mut snapshot = snapshot.write().unwrap();
+        snapshot.device_connected = true;
+        snapshot.sample = Some(Sample {
+            time: Utc::now(),
+            meters: Meters {
+                batt_voltage: 13.5,
+                ..Default::default()
+            },
+        });
+    }
+    let (status, meters) = get(&snapshot, "/meters").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(meters["meters"]["batt_voltage"], 13.5);
+    let (status, _) = get(&snapshot, "/health").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn config_hides_the_database_password() {
+    let snapshot = Arc::new(RwLock::new(Snapshot::default()));
+    let (status, config) = get(&snapshot, "/config").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(config["sys_name"], "ups");
+    assert_ne!(config["timescaledb"]["timescaledb_pass"], "hunter2");
+}
+
+#[tokio::test]
+async fn metrics_are_labelled_and_flag_alarms() {
+    let snapshot = Arc::new(RwLock::new(Snapshot {
+        device_connected: true,
+        sample: Some(Sample {
+            time: Utc::now(),
+            meters: Meters {
+                input_voltage: 0.0,
+                batt_voltage: 12.5,
+                ..Default::default()
+            },
+        }),
+        alarms: Flags {
+            ac_down: true,
+            batt_low: false,
+        },
+        ..Default::default()
+    }));
+    let (status, body) = get_text(&snapshot, "/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    let labels = gauge_labels(&body, "nextys_battery_voltage_volts");
+    assert!(labels.contains(r#"sys_name="ups""#));
+    assert!(labels.contains(r#"location="rack 1""#));
+    assert_eq!(gauge(&body, "nextys_battery_voltage_volts"), 12.5);
+    assert_eq!(gauge(&body, "nextys_ac_down"), 1.0);
+    assert_eq!(gauge(&body, "nextys_batt_low"), 0.0);
+    assert_eq!(gauge(&body, "nextys_up"), 1.0);
+}
+
+fn gauge_line<'a>(body: &'a str, name: &str) -> &'a str {
+    body.lines()
+        .find(|line| line.starts_with(&format!("{name}{{")))
+        .unwrap_or_else(|| panic!("{name} should be exported"))
+}
+
+fn gauge_labels<'a>(body: &'a str, name: &str) -> &'a str {
+    gauge_line(body, name).rsplit_once(' ').unwrap().0
+}
+
+fn gauge(body: &str, name: &str) -> f64 {
+    gauge_line(body, name)
+        .rsplit_once(' ')
+        .unwrap()
+        .1
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn control_requires_the_token() {
+    let config = config();
+    let transport = || {
+        MemoryTransport::new(
+            (0x1010..=0x1023)
+                .chain(0x2000..=0x200A)
+                .map(|address| (address, 0)),
+        )
+    };
+    let bus = Bus::spawn_with(
+        &config::Daemon::default(),
+        AlarmEngine::new(&config),
+        move || async move { Ok(Nextys::new(transport(), RegisterMap::dcw20())) },
+    );
+    let metrics = Arc::new(Metrics::new(&config));
+    let router = http::router(bus.snapshot(), metrics, &config);
+    let post = |token: Option<&str>| {
+        let request = Request::post("/alarms/ac_down/acknowledge");
+        match token {
+            Some(token) => request.header("Authorization", format!("Bearer {token}")),
+            None => request,
+        }
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    // Off unless a token is configured
+    let response = router.clone().oneshot(post(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let router = router.merge(http::control(bus.clone(), "0123456789abcdef"));
+    for token in [None, Some("wrong"), Some("0123456789abcdeg")] {
+        let response = router.clone().oneshot(post(token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = router
+        .oneshot(post(Some("0123456789abcdef")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    bus.shutdown().await;
+}
@@ -0,0 +1,59 @@
+use rust_nextys_monitoring::nextys::meters::{Meters, MetersAggregate, Stat};
+
+fn sample(input_voltage: f32, batt_voltage: f32) -> Meters {
+    Meters {
+        input_voltage,
+        input_current: 1.0,
+        output_voltage: 24.0,
+        output_current: 1.0,
+        batt_voltage,
+        batt_current: 0.0,
+        batt_soc: 100.0,
+        batt_int_resistance: 25.0,
+        batt_charge_capacity: Some(7.0),
+        operating_time: Some(100),
+        batt_operating_time: Some(2),
+    }
+}
+
+#[test]
+fn aggregate_keeps_min_mean_and_max() {
+    let mut samples = [sample(24.0, 13.6), sample(3.0, 13.2), sample(24.0, 13.4)];
+    samples[2].operating_time = Some(101);
+    let aggregate = MetersAggregate::from_samples(&samples).unwrap();
+    assert_eq!(
+        aggregate.input_voltage,
+        Stat {
+            min: 3.0,
+            avg: 17.0,
+            max: 24.0
+        }
+    );
+    assert_eq!(aggregate.batt_voltage.min, 13.2);
+    assert_eq!(aggregate.batt_voltage.max, 13.6);
+    assert_eq!(aggregate.average().input_voltage, 17.0);
+    assert_eq!(aggregate.operating_time, Some(101));
+    assert_eq!(aggregate.samples, 3);
+}
+
+#[test]
+fn empty_window_has_no_aggregate() {
+    assert!(MetersAggregate::from_samples(&[]).is_none());
+}
+
+#[test]
+fn missing_counters_are_left_out_of_the_aggregate() {
+    let mut samples = [sample(24.0, 13.6), sample(24.0, 13.6)];
+    samples[1].batt_charge_capacity = None;
+    samples[1].operating_time = None;
+    let aggregate = MetersAggregate::from_samples(&samples).unwrap();
+    assert_eq!(aggregate.batt_charge_capacity.map(|s| s.avg), Some(7.0));
+    assert_eq!(aggregate.operating_time, Some(100));
+
+    for sample in &mut samples {
+        sample.batt_charge_capacity = None;
+    }
+    let aggregate = MetersAggregate::from_samples(&samples).unwrap();
+    assert_eq!(aggregate.batt_charge_capacity, None);
+    assert_eq!(aggregate.average().batt_charge_capacity, None);
+}
@@ -0,0 +1,19 @@
+use rust_nextys_monitoring::database::migrations::MIGRATIONS;
+
+#[test]
+fn versions_are_ordered_from_one_without_gaps() {
+    for (index, migration) in MIGRATIONS.iter().enumerate() {
+        assert_eq!(migration.version, index as i64 + 1, "{}", migration.name);
+        assert!(!migration.sql.trim().is_empty(), "{}", migration.name);
+    }
+}
+
+#[test]
+fn baseline_leaves_existing_tables_alone() {
+    let baseline = MIGRATIONS[0].sql;
+    assert_eq!(
+        baseline.matches("CREATE TABLE ").count(),
+        baseline.matches("CREATE TABLE IF NOT EXISTS").count()
+    );
+    assert!(baseline.contains("if_not_exists => TRUE"));
+}
@@ -0,0 +1,94 @@
+use rust_nextys_monitoring::alarm::Flags;
+use rust_nextys_monitoring::config::Config;
+use rust_nextys_monitoring::mqtt::Topics;
+use rust_nextys_monitoring::nextys::meters::Meters;
+use serde_json::Value;
+
+fn config() -> Config {
+    toml::from_str(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "UPS 1"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = 5.0
+
+        [timescaledb]
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5432
+        timescaledb_user = "nextys"
+        timescaledb_pass = "hunter2"
+        timescaledb_db = "metrics"
+
+        [mqtt]
+        host = "broker"
+        "#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn meters_are_published_per_field_and_as_json() {
+    let topics = Topics::new(&config());
+    assert_eq!(topics.availability(), "nextys/ups_1/availability");
+
+    let meters = Meters {
+        batt_voltage: 12.5,
+        ..Default::default()
+    };
+    let messages = topics.meters(&meters);
+    let voltage = messages
+        .iter()
+        .find(|m| m.topic == "nextys/ups_1/meters/batt_voltage")
+        .unwrap();
+    assert_eq!(voltage.payload, "12.5");
+    assert!(!voltage.retain);
+
+    let all = messages.last().unwrap();
+    assert_eq!(all.topic, "nextys/ups_1/meters");
+    let json: Value = serde_json::from_str(&all.payload).unwrap();
+    assert_eq!(json["batt_voltage"], 12.5);
+}
+
+#[test]
+fn alarms_are_retained_as_on_off() {
+    let topics = Topics::new(&config());
+    let messages = topics.alarms(Flags {
+        ac_down: true,
+        batt_low: false,
+    });
+    let state = |topic: &str| {
+        let message = messages.iter().find(|m| m.topic == topic).unwrap();
+        assert!(message.retain);
+        message.payload.clone()
+    };
+    assert_eq!(state("nextys/ups_1/alarms/ac_down"), "ON");
+    assert_eq!(state("nextys/ups_1/alarms/batt_low"), "OFF");
+}
+
+#[test]
+fn discovery_describes_every_sensor() {
+    let topics = Topics::new(&config());
+    let messages = topics.discovery();
+    assert_eq!(messages.len(), 13);
+    assert!(messages.iter().all(|m| m.retain));
+
+    let soc = messages
+        .iter()
+        .find(|m| m.topic == "homeassistant/sensor/ups_1/batt_soc/config")
+        .unwrap();
+    let json: Value = serde_json::from_str(&soc.payload).unwrap();
+    assert_eq!(json["unique_id"], "nextys_ups_1_batt_soc");
+    assert_eq!(json["device_class"], "battery");
+    assert_eq!(json["state_topic"], "nextys/ups_1/meters/batt_soc");
+    assert_eq!(json["availability_topic"], "nextys/ups_1/availability");
+    assert_eq!(json["device"]["name"], "UPS 1");
+
+    let mains = messages
+        .iter()
+        .find(|m| m.topic == "homeassistant/binary_sensor/ups_1/ac_down/config")
+        .unwrap();
+    let json: Value = serde_json::from_str(&mains.payload).unwrap();
+    assert_eq!(json["device_class"], "problem");
+    assert_eq!(json["state_topic"], "nextys/ups_1/alarms/ac_down");
+}
@@ -0,0 +1,320 @@
+use rust_nextys_monitoring::convert_to_signed;
+use rust_nextys_monitoring::error::Error;
+use rust_nextys_monitoring::nextys::Nextys;
+use rust_nextys_monitoring::nextys::registers::{Group, RegisterMap};
+use rust_nextys_monitoring::nextys::settings::BatteryType;
+use rust_nextys_monitoring::nextys::transport::{Fault, MemoryTransport};
+use tokio_modbus::ExceptionCode;
+
+/// Counters at placeholder addresses, as a user would add them
+const COUNTERS: &str = r#"
+[[register]]
+name = "batt_charge_capacity"
+address = 0x2100
+data_type = "u16"
+scale = 0.1
+group = "counters"
+
+[[register]]
+name = "operating_time"
+address = 0x2101
+data_type = "u32"
+group = "counters"
+
+[[register]]
+name = "batt_operating_time"
+address = 0x2103
+data_type = "u32"
+group = "counters"
+"#;
+
+/// A DCW20 on mains with a 12 V lead battery
+fn device() -> Nextys<MemoryTransport> {
+    let mut registers = vec![
+        (0x1010, 1),
+        (0x1011, 144),
+        (0x1012, 20),
+        (0x1013, 136),
+        (0x1014, 115),
+        (0x1015, 105),
+        (0x1016, 100),
+        (0x1017, 70),
+        (0x1021, 240),
+        (0x1022, 100),
+        (0x1023, 100),
+    ];
+    registers.extend((0x2000..=0x200A).map(|address| (address, 0)));
+    let mut transport = MemoryTransport::new(registers);
+    transport.registers.extend([
+        (0x2000, 240),
+        (0x2001, 21),
+        (0x2002, 238),
+        (0x2003, 20),
+        (0x2004, 135),
+        (0x2005, 0xFFEC),
+        (0x2009, 250),
+        (0x200A, 995),
+    ]);
+    Nextys::new(transport, RegisterMap::dcw20())
+}
+
+/// The same device with the counters added to its register map
+fn with_counters() -> Nextys<MemoryTransport> {
+    let mut map = RegisterMap::dcw20();
+    map.registers
+        .extend(toml::from_str::<RegisterMap>(COUNTERS).unwrap().registers);
+    Nextys::new(device().transport().clone(), map)
+}
+
+#[test]
+fn convert_to_signed_reads_twos_complement() {
+    assert_eq!(convert_to_signed(vec![0x0001]), 1);
+    assert_eq!(convert_to_signed(vec![0xFFFF]), -1);
+    assert_eq!(convert_to_signed(vec![0x8000]), i16::MIN);
+}
+
+#[tokio::test]
+async fn meters_are_scaled_and_signed() {
+    let meters = device().get_meters().await.unwrap();
+    assert_eq!(meters.input_voltage, 24.0);
+    assert_eq!(meters.input_current, 2.1);
+    assert_eq!(meters.output_voltage, 23.8);
+    assert_eq!(meters.output_current, 2.0);
+    assert_eq!(meters.batt_voltage, 13.5);
+    assert_eq!(meters.batt_current, -2.0);
+    assert_eq!(meters.batt_int_resistance, 25.0);
+    assert_eq!(meters.batt_soc, 99.5);
+}
+
+#[tokio::test]
+async fn meters_are_read_in_one_request() {
+    let mut nextys = device();
+    let meters = nextys.get_meters().await.unwrap();
+    assert_eq!(nextys.transport().reads, 1);
+    assert_eq!(meters.batt_charge_capacity, None);
+    assert_eq!(meters.operating_time, None);
+    assert_eq!(meters.batt_operating_time, None);
+}
+
+#[tokio::test]
+async fn counters_are_read_when_the_device_has_them() {
+    let mut nextys = with_counters();
+    nextys.transport_mut().registers.extend([
+        (0x2100, 69),
+        (0x2101, 0x0001),
+        (0x2102, 0x0002),
+        (0x2103, 0),
+        (0x2104, 12),
+    ]);
+    let meters = nextys.get_meters().await.unwrap();
+    assert_eq!(meters.batt_charge_capacity, Some(6.9));
+    assert_eq!(meters.operating_time, Some(0x0001_0002));
+    assert_eq!(meters.batt_operating_time, Some(12));
+}
+
+#[tokio::test]
+async fn refused_counters_do_not_fail_the_meters() {
+    let mut nextys = with_counters();
+    let meters = nextys.get_meters().await.unwrap();
+    assert_eq!(meters.input_voltage, 24.0);
+    assert_eq!(meters.batt_charge_capacity, None);
+    assert_eq!(meters.operating_time, None);
+    assert_eq!(meters.batt_operating_time, None);
+
+    let reads = nextys.transport().reads;
+    nextys.get_meters().await.unwrap();
+    assert_eq!(nextys.transport().reads - reads, 1);
+}
+
+#[tokio::test]
+async fn busy_counters_are_read_again_next_time() {
+    let mut nextys = with_counters();
+    let transport = nextys.transport_mut();
+    transport
+        .registers
+        .extend((0x2100..=0x2104).map(|address| (address, 0)));
+    transport.registers.insert(0x2100, 69);
+    transport
+        .faults
+        .insert(0x2100, Fault::Exception(ExceptionCode::ServerDeviceBusy));
+    let meters = nextys.get_meters().await.unwrap();
+    assert_eq!(meters.batt_charge_capacity, None);
+
+    nextys.transport_mut().faults.clear();
+    let meters = nextys.get_meters().await.unwrap();
+    assert_eq!(meters.batt_charge_capacity, Some(6.9));
+}
+
+#[tokio::test]
+async fn settings_are_scaled() {
+    let mut nextys = device();
+    let settings = nextys.get_settings().await.unwrap();
+    assert_eq!(settings.batt_charge_voltage, 14.4);
+    assert_eq!(settings.batt_charge_current, 2.0);
+    assert_eq!(settings.batt_float_voltage, 13.6);
+    assert_eq!(settings.batt_low_voltage, 11.5);
+    assert_eq!(settings.batt_deep_discharge_voltage, 10.5);
+    assert_eq!(settings.batt_max_discharge_current, 10.0);
+    assert_eq!(settings.batt_capacity, 7.0);
+    assert_eq!(settings.nominal_output_voltage, 24.0);
+    assert_eq!(settings.max_input_current, 10.0);
+    assert_eq!(settings.max_output_current, 10.0);
+    assert_eq!(nextys.transport().reads, 2);
+}
+
+#[tokio::test]
+async fn battery_type_is_mapped() {
+    let expected = [
+        (0, BatteryType::Unknown),
+        (1, BatteryType::Lead),
+        (2, BatteryType::Nickel),
+        (3, BatteryType::Lithium),
+        (4, BatteryType::Supercapacitor),
+        (5, BatteryType::Unknown),
+    ];
+    for (raw, batt_type) in expected {
+        let mut nextys = device();
+        nextys.transport_mut().registers.insert(0x1010, raw);
+        let settings = nextys.get_settings().await.unwrap();
+        assert_eq!(settings.batt_type, batt_type);
+        assert_eq!(settings.batt_type_int, raw as i16);
+    }
+}
+
+#[tokio::test]
+async fn exceptions_are_propagated() {
+    let mut nextys = device();
+    nextys
+        .transport_mut()
+        .faults
+        .insert(0x2004, Fault::Exception(ExceptionCode::ServerDeviceBusy));
+    match nextys.get_meters().await {
+        Err(Error::Exception { address, code }) => {
+            assert_eq!(address, 0x2004);
+            assert_eq!(code, ExceptionCode::ServerDeviceBusy);
+        }
+        other => panic!("expected an exception, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn timeouts_and_disconnects_are_propagated() {
+    let mut nextys = device();
+    nextys.transport_mut().faults.insert(0x1022, Fault::Timeout);
+    assert!(matches!(
+        nextys.get_settings().await,
+        Err(Error::Timeout {
+            address: 0x1022,
+            ..
+        })
+    ));
+
+    let mut nextys = device();
+    nextys
+        .transport_mut()
+        .faults
+        .insert(0x2000, Fault::Disconnected);
+    assert!(matches!(
+        nextys.get_meters().await,
+        Err(Error::Transport {
+            address: 0x2000,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn short_reads_are_errors() {
+    let mut nextys = device();
+    nextys
+        .transport_mut()
+        .faults
+        .insert(0x2006, Fault::ShortRead);
+    match nextys.get_meters().await {
+        Err(Error::ShortRead {
+            address,
+            expected,
+            received,
+        }) => {
+            assert_eq!(address, 0x2000);
+            assert_eq!(expected, 11);
+            assert_eq!(received, 6);
+        }
+        other => panic!("expected a short read, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn apply_settings_writes_only_changes() {
+    let mut nextys = device();
+    let mut settings = nextys.get_settings().await.unwrap();
+    settings.batt_float_voltage = 13.8;
+    settings.batt_low_voltage = 11.8;
+    settings.batt_capacity = 12.0;
+
+    let changes = nextys.apply_settings(&settings).await.unwrap();
+    let names: Vec<&str> = changes.iter().map(|c| c.name).collect();
+    assert_eq!(
+        names,
+        ["batt_float_voltage", "batt_low_voltage", "batt_capacity"]
+    );
+    // 0x1013-0x1014 go out together, 0x1017 on its own
+    assert_eq!(nextys.transport().writes, 2);
+    assert_eq!(nextys.transport().registers[&0x1013], 138);
+    assert_eq!(nextys.transport().registers[&0x1014], 118);
+    assert_eq!(nextys.transport().registers[&0x1017], 120);
+}
+
+#[tokio::test]
+async fn out_of_range_settings_are_not_written() {
+    let mut nextys = device();
+    let mut settings = nextys.get_settings().await.unwrap();
+    settings.batt_float_voltage = 13.8;
+    settings.batt_charge_voltage = 600.0;
+
+    assert!(matches!(
+        nextys.apply_settings(&settings).await,
+        Err(Error::OutOfRange { .. })
+    ));
+    assert_eq!(nextys.transport().writes, 0);
+    assert_eq!(nextys.transport().registers[&0x1013], 136);
+}
+
+#[tokio::test]
+async fn meters_are_read_only() {
+    let mut nextys = device();
+    assert!(matches!(
+        nextys.write_register("input_voltage", 12.0).await,
+        Err(Error::ReadOnly(_))
+    ));
+    assert!(matches!(
+        nextys.write_register("no_such_register", 1.0).await,
+        Err(Error::UnknownRegister(_))
+    ));
+}
+
+#[tokio::test]
+async fn registers_past_the_last_address_are_rejected() {
+    let path = std::env::temp_dir().join(format!("nextys_map_{}.toml", std::process::id()));
+    let register = |address: &str| {
+        format!(
+            "[[register]]\nname = \"top\"\naddress = {address}\ndata_type = \"u32\"\ngroup = \"meters\"\n"
+        )
+    };
+
+    std::fs::write(&path, register("0xFFFF")).unwrap();
+    let error = RegisterMap::load(path.to_str().unwrap()).unwrap_err();
+    assert!(matches!(error, Error::RegisterMap { .. }));
+
+    std::fs::write(&path, register("0xFFFE")).unwrap();
+    let map = RegisterMap::load(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(map.blocks(Group::Meters).last(), Some(&(0xFFFE, 2)));
+
+    let meters = (0x2000..=0x200A).map(|address| (address, 0));
+    let transport = MemoryTransport::new(meters.chain([(0xFFFE, 0x0001), (0xFFFF, 0x0002)]));
+    let mut nextys = Nextys::new(transport, map);
+    let values = nextys.read_group(Group::Meters).await.unwrap();
+    assert_eq!(values.get("top").unwrap(), 65538.0);
+    assert_eq!(nextys.read_register("top").await.unwrap(), 65538.0);
+}
@@ -0,0 +1,197 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use rust_nextys_monitoring::alarm::Flags;
+use rust_nextys_monitoring::bus::{Sample, Snapshot};
+use rust_nextys_monitoring::config::Config;
+use rust_nextys_monitoring::nextys::meters::Meters;
+use rust_nextys_monitoring::nut;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+fn config() -> Config {
+    toml::from_str(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "ups"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = 5.0
+
+        [timescaledb]
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5432
+        timescaledb_user = "nextys"
+        timescaledb_pass = "hunter2"
+        timescaledb_db = "metrics"
+
+        [nut]
+        username = "monuser"
+        password = "secret"
+        "#,
+    )
+    .unwrap()
+}
+
+fn snapshot(input_voltage: f32, batt_voltage: f32) -> Snapshot {
+    Snapshot {
+        sample: Some(Sample {
+            time: Utc::now(),
+            meters: Meters {
+                input_voltage,
+                batt_voltage,
+                batt_soc: 80.0,
+                ..Default::default()
+            },
+        }),
+        alarms: Flags::new(&config(), input_voltage, batt_voltage),
+        device_connected: true,
+        ..Default::default()
+    }
+}
+
+fn status(input_voltage: f32, batt_voltage: f32, fsd: bool) -> String {
+    let variables = nut::variables(&config(), &snapshot(input_voltage, batt_voltage), fsd).unwrap();
+    let (_, status) = variables
+        .into_iter()
+        .find(|(name, _)| *name == "ups.status")
+        .unwrap();
+    status
+}
+
+struct Client {
+    lines: tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(snapshot: &Arc<RwLock<Snapshot>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let snapshot = snapshot.clone();
+        tokio::spawn(async move { nut::serve(listener, snapshot, &config()).await });
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        Client {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        }
+    }
+
+    /// Send a command and read `count` reply lines
+    async fn send(&mut self, command: &str, count: usize) -> Vec<String> {
+        self.writer
+            .write_all(format!("{command}\n").as_bytes())
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        for _ in 0..count {
+            reply.push(self.lines.next_line().await.unwrap().unwrap());
+        }
+        reply
+    }
+}
+
+#[test]
+fn status_follows_the_thresholds() {
+    assert_eq!(status(24.0, 12.5, false), "OL");
+    assert_eq!(status(0.0, 12.5, false), "OB");
+    assert_eq!(status(0.0, 10.5, false), "OB LB");
+    assert_eq!(status(0.0, 10.5, true), "FSD OB LB");
+}
+
+#[test]
+fn variables_are_stale_without_the_device() {
+    let mut snapshot = snapshot(24.0, 12.5);
+    snapshot.device_connected = false;
+    assert!(nut::variables(&config(), &snapshot, false).is_none());
+    assert!(nut::variables(&config(), &Snapshot::default(), false).is_none());
+}
+
+#[tokio::test]
+async fn clients_read_variables() {
+    let snapshot = Arc::new(RwLock::new(snapshot(0.0, 12.5)));
+    let mut client = Client::connect(&snapshot).await;
+
+    assert_eq!(
+        client.send("GET VAR nextys ups.status", 1).await,
+        ["VAR nextys ups.status \"OB\""]
+    );
+    assert_eq!(
+        client.send("GET VAR nextys battery.charge", 1).await,
+        ["VAR nextys battery.charge \"80\""]
+    );
+    assert_eq!(
+        client.send("GET VAR nextys battery.type", 1).await,
+        ["ERR DATA-STALE"]
+    );
+    assert_eq!(
+        client.send("GET VAR nextys ups.delay.shutdown", 1).await,
+        ["ERR VAR-NOT-SUPPORTED"]
+    );
+    assert_eq!(
+        client.send("GET VAR other ups.status", 1).await,
+        ["ERR UNKNOWN-UPS"]
+    );
+    assert_eq!(
+        client.send("LIST UPS", 3).await,
+        [
+            "BEGIN LIST UPS",
+            "UPS nextys \"ups at rack 1\"",
+            "END LIST UPS"
+        ]
+    );
+    let list = client.send("LIST VAR nextys", 21).await;
+    assert_eq!(list[0], "BEGIN LIST VAR nextys");
+    assert!(list.contains(&"VAR nextys device.location \"rack 1\"".to_string()));
+    assert_eq!(list[20], "END LIST VAR nextys");
+
+    snapshot.write().unwrap().device_connected = false;
+    assert_eq!(
+        client.send("GET VAR nextys ups.status", 1).await,
+        ["ERR DATA-STALE"]
+    );
+}
+
+#[tokio::test]
+async fn primary_needs_the_configured_credentials() {
+    let snapshot = Arc::new(RwLock::new(snapshot(0.0, 10.5)));
+    let mut client = Client::connect(&snapshot).await;
+
+    assert_eq!(
+        client.send("LOGIN nextys", 1).await,
+        ["ERR USERNAME-REQUIRED"]
+    );
+    assert_eq!(client.send("USERNAME monuser", 1).await, ["OK"]);
+    assert_eq!(client.send("PASSWORD wrong", 1).await, ["OK"]);
+    assert_eq!(
+        client.send("PRIMARY nextys", 1).await,
+        ["ERR ACCESS-DENIED"]
+    );
+    assert_eq!(client.send("FSD nextys", 1).await, ["ERR ACCESS-DENIED"]);
+
+    let mut client = Client::connect(&snapshot).await;
+    client.send("USERNAME monuser", 1).await;
+    client.send("PASSWORD \"secret\"", 1).await;
+    assert_eq!(client.send("LOGIN nextys", 1).await, ["OK"]);
+    assert_eq!(
+        client.send("GET NUMLOGINS nextys", 1).await,
+        ["NUMLOGINS nextys 1"]
+    );
+    assert_eq!(client.send("MASTER nextys", 1).await, ["OK MASTER-GRANTED"]);
+    assert_eq!(client.send("FSD nextys", 1).await, ["OK FSD-SET"]);
+    assert_eq!(
+        client.send("GET VAR nextys ups.status", 1).await,
+        ["VAR nextys ups.status \"FSD OB LB\""]
+    );
+    assert_eq!(client.send("LOGOUT", 1).await, ["OK Goodbye"]);
+}
+
+#[tokio::test]
+async fn clients_sending_overlong_lines_are_dropped() {
+    let snapshot = Arc::new(RwLock::new(snapshot(24.0, 12.5)));
+    let mut client = Client::connect(&snapshot).await;
+    assert_eq!(client.send("NETVER", 1).await, ["1.3"]);
+    client.writer.write_all(&[b'A'; 4096]).await.unwrap();
+    // Closed with input unread, so the client may see a reset instead of EOF
+    assert!(!matches!(client.lines.next_line().await, Ok(Some(_))));
+}
@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use rust_nextys_monitoring::config::Config;
+use rust_nextys_monitoring::error::Error;
+use rust_nextys_monitoring::nextys::Nextys;
+use rust_nextys_monitoring::nextys::meters::Meters;
+use rust_nextys_monitoring::nextys::registers::RegisterMap;
+use rust_nextys_monitoring::nextys::transport::ModbusTransport;
+use rust_nextys_monitoring::sim::{Scenario, Simulator};
+use tokio::net::TcpListener;
+use tokio_modbus::ExceptionCode;
+use tokio_modbus::server::tcp;
+
+/// Counters at placeholder addresses, as a user would add them
+const COUNTERS: &str = r#"
+[[register]]
+name = "batt_charge_capacity"
+address = 0x2100
+data_type = "u16"
+scale = 0.1
+group = "counters"
+
+[[register]]
+name = "operating_time"
+address = 0x2101
+data_type = "u32"
+group = "counters"
+
+[[register]]
+name = "batt_operating_time"
+address = 0x2103
+data_type = "u32"
+group = "counters"
+"#;
+
+fn config(port: u16) -> Config {
+    toml::from_str(&format!(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "ups"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = 5.0
+        transport = "tcp"
+
+        [timescaledb]
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5432
+        timescaledb_user = "nextys"
+        timescaledb_pass = "hunter2"
+        timescaledb_db = "metrics"
+
+        [tcp]
+        host = "127.0.0.1"
+        port = {port}
+        unit_id = 1
+        timeout_ms = 1000
+        "#
+    ))
+    .unwrap()
+}
+
+/// Serve `scenario` with the counters on a loopback Modbus TCP port and
+/// connect a client to it
+async fn serve(scenario: Scenario) -> (Simulator, Nextys<ModbusTransport>) {
+    let mut map = RegisterMap::dcw20();
+    map.registers
+        .extend(toml::from_str::<RegisterMap>(COUNTERS).unwrap().registers);
+    let simulator = Simulator::new(map.clone(), scenario, 1);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let served = Arc::new(simulator.clone());
+    tokio::spawn(async move {
+        let on_connected = |stream, _| {
+            let served = served.clone();
+            async move { Ok(Some((served, stream))) }
+        };
+        tcp::Server::new(listener)
+            .serve(&on_connected, |_| {})
+            .await
+            .unwrap();
+    });
+    let transport = ModbusTransport::connect(&config(port)).await.unwrap();
+    (simulator, Nextys::new(transport, map))
+}
+
+fn assert_near(actual: f32, expected: f32) {
+    assert!(
+        (actual - expected).abs() < 0.05,
+        "expected {expected}, got {actual}"
+    );
+}
+
+fn assert_on_mains(meters: &Meters) {
+    assert_near(meters.input_voltage, 24.0);
+    assert_near(meters.output_voltage, 23.8);
+    assert_near(meters.output_current, 2.0);
+}
+
+fn assert_on_battery(meters: &Meters) {
+    assert_near(meters.input_voltage, 0.0);
+    assert_near(meters.input_current, 0.0);
+    assert_near(meters.output_current, 2.0);
+    assert_near(meters.batt_current, -2.0);
+    assert_near(meters.output_voltage, meters.batt_voltage - 0.3);
+}
+
+#[tokio::test]
+async fn normal_floats_on_mains() {
+    let (simulator, mut nextys) = serve(Scenario::Normal).await;
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_mains(&meters);
+    assert_near(meters.input_current, 2.0);
+    assert_near(meters.batt_voltage, 13.6);
+    assert_near(meters.batt_current, 0.0);
+    assert_near(meters.batt_soc, 100.0);
+    assert_near(meters.batt_int_resistance, 25.0);
+
+    simulator.step(7200.0);
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_mains(&meters);
+    assert_near(meters.batt_soc, 100.0);
+    assert_eq!(meters.operating_time, Some(2));
+    assert_eq!(meters.batt_operating_time, Some(0));
+}
+
+#[tokio::test]
+async fn mains_loss_drops_and_restores_the_input() {
+    let (simulator, mut nextys) = serve(Scenario::MainsLoss).await;
+    simulator.step(29.0);
+    assert_on_mains(&nextys.get_meters().await.unwrap());
+
+    simulator.step(1.0);
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_battery(&meters);
+
+    simulator.step(120.0);
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_mains(&meters);
+    assert_near(meters.batt_current, 2.0);
+}
+
+#[tokio::test]
+async fn discharge_runs_the_battery_flat() {
+    let (simulator, mut nextys) = serve(Scenario::Discharge).await;
+    let full = nextys.get_meters().await.unwrap();
+    assert_on_battery(&full);
+
+    simulator.step(3600.0);
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_battery(&meters);
+    assert!(meters.batt_soc < full.batt_soc);
+    assert!(meters.batt_voltage < full.batt_voltage);
+
+    for _ in 0..3 {
+        simulator.step(3600.0);
+    }
+    let meters = nextys.get_meters().await.unwrap();
+    assert_near(meters.batt_soc, 0.0);
+    assert_near(meters.output_voltage, 0.0);
+    assert_near(meters.output_current, 0.0);
+    assert_near(meters.batt_current, 0.0);
+    assert_eq!(meters.batt_operating_time, Some(3));
+}
+
+#[tokio::test]
+async fn low_battery_crosses_the_cutoff() {
+    let (simulator, mut nextys) = serve(Scenario::LowBattery).await;
+    let low_voltage = nextys.get_settings().await.unwrap().batt_low_voltage;
+    assert_near(low_voltage, 11.5);
+
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_battery(&meters);
+    assert_near(meters.batt_soc, 30.0);
+    assert!(meters.batt_voltage > low_voltage);
+
+    simulator.step(1200.0);
+    let meters = nextys.get_meters().await.unwrap();
+    assert_on_battery(&meters);
+    assert!(meters.batt_voltage < low_voltage);
+}
+
+#[tokio::test]
+async fn exceptions_answer_every_fifth_request_busy() {
+    let (_, mut nextys) = serve(Scenario::Exceptions).await;
+    for round in 0..2 {
+        for _ in 0..4 {
+            nextys.get_address(0x2000, 1).await.unwrap();
+        }
+        match nextys.get_address(0x2000, 1).await {
+            Err(Error::Exception { address, code }) => {
+                assert_eq!(address, 0x2000);
+                assert_eq!(code, ExceptionCode::ServerDeviceBusy);
+            }
+            other => panic!("request {} should fail, got {other:?}", 5 * (round + 1)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unmapped_addresses_are_illegal() {
+    let (_, mut nextys) = serve(Scenario::Normal).await;
+    match nextys.get_address(0x3000, 1).await {
+        Err(Error::Exception { code, .. }) => {
+            assert_eq!(code, ExceptionCode::IllegalDataAddress)
+        }
+        other => panic!("read should fail, got {other:?}"),
+    }
+    match nextys.set_address(0x2000, &[0]).await {
+        Err(Error::Exception { code, .. }) => {
+            assert_eq!(code, ExceptionCode::IllegalDataAddress)
+        }
+        other => panic!("write to a meter should fail, got {other:?}"),
+    }
+}
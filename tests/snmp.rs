@@ -0,0 +1,347 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use rust_nextys_monitoring::alarm::{AlarmEvent, AlarmKind, Flags};
+use rust_nextys_monitoring::bus::{Sample, Snapshot};
+use rust_nextys_monitoring::config::Config;
+use rust_nextys_monitoring::nextys::meters::Meters;
+use rust_nextys_monitoring::snmp::ber::{self, Oid, Pdu, Reader, Value};
+use rust_nextys_monitoring::snmp::usm::{self, ScopedPdu, SecurityParameters, User};
+use rust_nextys_monitoring::snmp::{self, Agent};
+
+fn config() -> Config {
+    toml::from_str(
+        r#"
+        ip_address = "10.0.0.2"
+        sys_name = "ups"
+        location = "rack 1"
+        low_batt_threshold = 11.0
+        ac_down_threshold = 5.0
+
+        [timescaledb]
+        timescaledb_host = "10.0.0.1"
+        timescaledb_port = 5432
+        timescaledb_user = "nextys"
+        timescaledb_pass = "hunter2"
+        timescaledb_db = "metrics"
+
+        [snmp]
+        community = "public"
+
+        [[snmp.users]]
+        name = "nms"
+        auth_password = "authpass1"
+        priv_password = "privpass1"
+
+        [[snmp.traps]]
+        target = "10.0.0.5:162"
+        "#,
+    )
+    .unwrap()
+}
+
+fn snapshot(input_voltage: f32) -> Arc<RwLock<Snapshot>> {
+    Arc::new(RwLock::new(Snapshot {
+        sample: Some(Sample {
+            time: Utc::now(),
+            meters: Meters {
+                input_voltage,
+                batt_voltage: 12.5,
+                ..Default::default()
+            },
+        }),
+        alarms: Flags::new(&config(), input_voltage, 12.5),
+        device_connected: true,
+        ..Default::default()
+    }))
+}
+
+fn sys_name() -> Oid {
+    Oid(vec![1, 3, 6, 1, 2, 1, 1, 5, 0])
+}
+
+fn request(tag: u8, oids: &[Oid]) -> Pdu {
+    Pdu {
+        tag,
+        request_id: 7,
+        error_status: 0,
+        error_index: 0,
+        varbinds: oids.iter().map(|oid| (oid.clone(), Value::Null)).collect(),
+    }
+}
+
+fn v2c(agent: &mut Agent, community: &str, pdu: Pdu) -> Option<Pdu> {
+    let message = ber::sequence(&[
+        ber::integer(1),
+        ber::octets(community.as_bytes()),
+        pdu.encode(),
+    ]);
+    let reply = agent.handle(&message)?;
+    let mut reader = Reader::new(&reply).expect(ber::SEQUENCE).unwrap();
+    assert_eq!(reader.integer().unwrap(), 1);
+    assert_eq!(reader.octets().unwrap(), community.as_bytes());
+    Some(Pdu::decode(&mut reader).unwrap())
+}
+
+fn v3(
+    agent: &mut Agent,
+    flags: u8,
+    security: SecurityParameters,
+    user: Option<&User>,
+    pdu: Pdu,
+) -> (usm::Message, ScopedPdu) {
+    let scoped = ScopedPdu {
+        context_engine_id: agent.engine_id().to_vec(),
+        context_name: Vec::new(),
+        pdu,
+    };
+    let message = usm::encode(
+        1,
+        65507,
+        flags | usm::FLAG_REPORTABLE,
+        security,
+        user,
+        &scoped,
+    );
+    let reply = agent.handle(&message).unwrap();
+    let mut reader = Reader::new(&reply).expect(ber::SEQUENCE).unwrap();
+    assert_eq!(reader.integer().unwrap(), 3);
+    let reply = usm::Message::decode(&mut reader).unwrap();
+    let scoped = reply.scoped_pdu(user).unwrap();
+    (reply, scoped)
+}
+
+#[test]
+fn keys_are_localized_as_in_rfc_3414() {
+    let engine_id = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    let key = usm::password_to_key(b"maplesyrup", &engine_id);
+    assert_eq!(
+        key,
+        [
+            0x66, 0x95, 0xfe, 0xbc, 0x92, 0x88, 0xe3, 0x62, 0x82, 0x23, 0x5f, 0xc7, 0x15, 0x1f,
+            0x12, 0x84, 0x97, 0xb3, 0x8f, 0x3f
+        ]
+    );
+}
+
+#[test]
+fn ber_round_trips() {
+    let pdu = Pdu {
+        tag: ber::RESPONSE,
+        request_id: -300,
+        error_status: 0,
+        error_index: 0,
+        varbinds: vec![
+            (
+                Oid(vec![1, 3, 6, 1, 4, 1, 99999, 1]),
+                Value::Integer(-12_500),
+            ),
+            (sys_name(), Value::string(&"x".repeat(300))),
+            (
+                Oid(vec![1, 3, 6, 1, 2, 1, 1, 3, 0]),
+                Value::TimeTicks(u32::MAX),
+            ),
+            (Oid(vec![1, 3]), Value::Counter64(1 << 63)),
+        ],
+    };
+    let encoded = pdu.encode();
+    assert_eq!(Pdu::decode(&mut Reader::new(&encoded)).unwrap(), pdu);
+    assert!(Pdu::decode(&mut Reader::new(&encoded[..encoded.len() - 1])).is_err());
+}
+
+#[test]
+fn view_holds_the_system_group_and_readings() {
+    let view = snmp::view(&config(), &snapshot(0.0).read().unwrap(), 100);
+    let value = |oid: Oid| view.iter().find(|(o, _)| *o == oid).unwrap().1.clone();
+    assert_eq!(value(sys_name()), Value::string("ups"));
+    assert_eq!(
+        value(Oid(vec![1, 3, 6, 1, 2, 1, 1, 6, 0])),
+        Value::string("rack 1")
+    );
+    // nextysAcDown.0
+    assert_eq!(value(snmp::nextys(&[1, 1, 5, 0])), Value::truth(true));
+    // Fifth reading is the battery voltage, in millivolts
+    assert_eq!(
+        value(snmp::nextys(&[1, 2, 1, 2, 5])),
+        Value::string("batt_voltage")
+    );
+    assert_eq!(
+        value(snmp::nextys(&[1, 2, 1, 3, 5])),
+        Value::Integer(12_500)
+    );
+    assert!(view.windows(2).all(|pair| pair[0].0 < pair[1].0));
+}
+
+#[test]
+fn v2c_requests_need_the_community() {
+    let mut agent = Agent::new(&config(), snapshot(24.0)).unwrap();
+    assert!(
+        v2c(
+            &mut agent,
+            "private",
+            request(ber::GET_REQUEST, &[sys_name()])
+        )
+        .is_none()
+    );
+
+    let response = v2c(
+        &mut agent,
+        "public",
+        request(ber::GET_REQUEST, &[sys_name()]),
+    )
+    .unwrap();
+    assert_eq!(response.tag, ber::RESPONSE);
+    assert_eq!(response.request_id, 7);
+    assert_eq!(response.varbinds, [(sys_name(), Value::string("ups"))]);
+
+    let missing = Oid(vec![1, 3, 6, 1, 2, 1, 1, 5, 1]);
+    let response = v2c(&mut agent, "public", request(ber::GET_REQUEST, &[missing])).unwrap();
+    assert_eq!(response.varbinds[0].1, Value::NoSuchInstance);
+
+    let response = v2c(
+        &mut agent,
+        "public",
+        request(ber::SET_REQUEST, &[sys_name()]),
+    )
+    .unwrap();
+    assert_eq!(response.error_status, 17);
+}
+
+#[test]
+fn v2c_walks_the_tree() {
+    let mut agent = Agent::new(&config(), snapshot(24.0)).unwrap();
+    let system = Oid(vec![1, 3, 6, 1, 2, 1, 1]);
+    let response = v2c(
+        &mut agent,
+        "public",
+        request(ber::GET_NEXT_REQUEST, &[system]),
+    )
+    .unwrap();
+    assert_eq!(response.varbinds[0].0, Oid(vec![1, 3, 6, 1, 2, 1, 1, 1, 0]));
+
+    let mut bulk = request(ber::GET_BULK_REQUEST, &[snmp::nextys(&[1, 2])]);
+    bulk.error_index = 3;
+    let response = v2c(&mut agent, "public", bulk).unwrap();
+    let oids: Vec<Oid> = response.varbinds.into_iter().map(|(oid, _)| oid).collect();
+    assert_eq!(
+        oids,
+        [
+            snmp::nextys(&[1, 2, 1, 1, 1]),
+            snmp::nextys(&[1, 2, 1, 1, 2]),
+            snmp::nextys(&[1, 2, 1, 1, 3]),
+        ]
+    );
+
+    let end = Oid(vec![2, 0]);
+    let response = v2c(&mut agent, "public", request(ber::GET_NEXT_REQUEST, &[end])).unwrap();
+    assert_eq!(response.varbinds[0].1, Value::EndOfMibView);
+}
+
+#[test]
+fn bulk_responses_and_requests_are_size_capped() {
+    let mut agent = Agent::new(&config(), snapshot(24.0)).unwrap();
+    let mut bulk = request(ber::GET_BULK_REQUEST, &vec![sys_name(); 40]);
+    bulk.error_index = 100;
+    let response = v2c(&mut agent, "public", bulk).unwrap();
+    assert_eq!(response.error_status, 0);
+    assert!(!response.varbinds.is_empty());
+    assert!(response.encode().len() <= 1472);
+
+    // Too long to have come from a manager respecting the agent's limit
+    let get = request(ber::GET_REQUEST, &vec![sys_name(); 200]);
+    assert!(get.encode().len() > 1472);
+    assert_eq!(v2c(&mut agent, "public", get), None);
+}
+
+#[test]
+fn v3_discovers_then_answers_encrypted_requests() {
+    let mut agent = Agent::new(&config(), snapshot(24.0)).unwrap();
+
+    // Discovery with an empty engine id reports the agent's
+    let (report, scoped) = v3(
+        &mut agent,
+        0,
+        SecurityParameters::default(),
+        None,
+        request(ber::GET_REQUEST, &[]),
+    );
+    assert_eq!(scoped.pdu.tag, ber::REPORT);
+    assert_eq!(
+        scoped.pdu.varbinds[0].0,
+        Oid(vec![1, 3, 6, 1, 6, 3, 15, 1, 1, 4, 0])
+    );
+    let engine_id = report.security.engine_id.clone();
+    assert_eq!(engine_id, agent.engine_id());
+
+    let user = User::new("nms", "authpass1", Some("privpass1"), &engine_id);
+    let security = SecurityParameters {
+        engine_id: engine_id.clone(),
+        boots: report.security.boots,
+        time: report.security.time,
+        user_name: b"nms".to_vec(),
+        auth: Vec::new(),
+        privacy: 42u64.to_be_bytes().to_vec(),
+    };
+    let (response, scoped) = v3(
+        &mut agent,
+        usm::FLAG_AUTH | usm::FLAG_PRIV,
+        security.clone(),
+        Some(&user),
+        request(ber::GET_REQUEST, &[sys_name()]),
+    );
+    assert_eq!(response.flags, usm::FLAG_AUTH | usm::FLAG_PRIV);
+    assert_eq!(scoped.pdu.tag, ber::RESPONSE);
+    assert_eq!(scoped.pdu.varbinds, [(sys_name(), Value::string("ups"))]);
+
+    // A wrong password fails the digest check
+    let wrong = User::new("nms", "authpass2", Some("privpass1"), &engine_id);
+    let (_, scoped) = v3(
+        &mut agent,
+        usm::FLAG_AUTH | usm::FLAG_PRIV,
+        security.clone(),
+        Some(&wrong),
+        request(ber::GET_REQUEST, &[sys_name()]),
+    );
+    assert_eq!(
+        scoped.pdu.varbinds[0].0,
+        Oid(vec![1, 3, 6, 1, 6, 3, 15, 1, 1, 5, 0])
+    );
+
+    // Requests below the user's level are refused
+    let (_, scoped) = v3(
+        &mut agent,
+        usm::FLAG_AUTH,
+        security,
+        Some(&user),
+        request(ber::GET_REQUEST, &[sys_name()]),
+    );
+    assert_eq!(
+        scoped.pdu.varbinds[0].0,
+        Oid(vec![1, 3, 6, 1, 6, 3, 15, 1, 1, 1, 0])
+    );
+}
+
+#[test]
+fn alarm_events_send_notifications() {
+    let mut agent = Agent::new(&config(), snapshot(24.0)).unwrap();
+    let notifications = agent.notifications(&AlarmEvent {
+        alarm: AlarmKind::AcDown,
+        raised: true,
+        time: Utc::now(),
+        value: Some(0.0),
+    });
+    assert_eq!(notifications.len(), 1);
+    let (target, message) = &notifications[0];
+    assert_eq!(target.to_string(), "10.0.0.5:162");
+
+    let mut reader = Reader::new(message).expect(ber::SEQUENCE).unwrap();
+    assert_eq!(reader.integer().unwrap(), 1);
+    assert_eq!(reader.octets().unwrap(), b"public");
+    let trap = Pdu::decode(&mut reader).unwrap();
+    assert_eq!(trap.tag, ber::TRAP);
+    assert_eq!(trap.varbinds[1].1, Value::Oid(snmp::nextys(&[0, 1])));
+    assert_eq!(
+        trap.varbinds[2],
+        (snmp::nextys(&[1, 1, 5, 0]), Value::truth(true))
+    );
+}
@@ -0,0 +1,76 @@
+use chrono::{TimeDelta, Utc};
+use rust_nextys_monitoring::config;
+use rust_nextys_monitoring::nextys::meters::{Meters, MetersAggregate};
+use rust_nextys_monitoring::spool::{MetricsSpool, SpooledMetrics};
+
+fn spool(name: &str, max_bytes: u64) -> (MetricsSpool, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("nextys_{name}_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = config::Spool {
+        path: path.to_string_lossy().into_owned(),
+        max_bytes,
+        max_age_hours: 24,
+    };
+    (MetricsSpool::new(&config), path)
+}
+
+fn record(age_hours: i64, batt_voltage: f32) -> SpooledMetrics {
+    let sample = Meters {
+        batt_voltage,
+        ..Default::default()
+    };
+    SpooledMetrics {
+        time: Utc::now() - TimeDelta::hours(age_hours),
+        meters: MetersAggregate::from_samples(&[sample]).unwrap(),
+        alarms: None,
+    }
+}
+
+#[test]
+fn records_are_kept_in_order() {
+    let (spool, path) = spool("order", 1 << 20);
+    for voltage in [12.0, 12.5, 13.0] {
+        spool.push(&record(0, voltage)).unwrap();
+    }
+    // A torn final write is skipped rather than failing the whole spool
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, b"{\"time\":"))
+        .unwrap();
+    // and the next record still starts on a line of its own
+    spool.push(&record(0, 13.5)).unwrap();
+    let voltages: Vec<f32> = spool
+        .load()
+        .unwrap()
+        .iter()
+        .map(|r| r.meters.batt_voltage.avg)
+        .collect();
+    assert_eq!(voltages, [12.0, 12.5, 13.0, 13.5]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn oldest_records_are_dropped_over_the_size_cap() {
+    let line = serde_json::to_string(&record(0, 12.0)).unwrap().len() as u64 + 1;
+    let (spool, path) = spool("size", line * 2);
+    for voltage in [12.0, 12.5, 13.0] {
+        spool.push(&record(0, voltage)).unwrap();
+    }
+    let records = spool.load().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].meters.batt_voltage.avg, 12.5);
+    assert!(std::fs::metadata(&path).unwrap().len() <= line * 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn expired_records_are_skipped() {
+    let (spool, path) = spool("age", 1 << 20);
+    spool.push(&record(48, 12.0)).unwrap();
+    spool.push(&record(1, 13.0)).unwrap();
+    let records = spool.load().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].meters.batt_voltage.avg, 13.0);
+    std::fs::remove_file(path).unwrap();
+}